@@ -0,0 +1,229 @@
+use std::{collections::BTreeMap, mem, time::Instant};
+
+use resol_vbus::chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+
+use crate::Measurements;
+
+/// How the values of a field within one write interval are combined.
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregation {
+    #[default]
+    Last,
+    Mean,
+    Min,
+    Max,
+    /// E.g. of pulses or energy increments counted per packet.
+    Sum,
+}
+
+struct Accumulator {
+    last: f64,
+    sum: f64,
+    count: u32,
+    min: f64,
+    max: f64,
+}
+
+impl Accumulator {
+    fn new(value: f64) -> Self {
+        Accumulator {
+            last: value,
+            sum: value,
+            count: 1,
+            min: value,
+            max: value,
+        }
+    }
+
+    fn add(&mut self, value: f64) {
+        self.last = value;
+        self.sum += value;
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    fn value(&self, aggregation: Aggregation) -> f64 {
+        match aggregation {
+            Aggregation::Last => self.last,
+            Aggregation::Mean => self.sum / f64::from(self.count),
+            Aggregation::Min => self.min,
+            Aggregation::Max => self.max,
+            Aggregation::Sum => self.sum,
+        }
+    }
+}
+
+struct Window {
+    start: DateTime<Utc>,
+    last_time: DateTime<Utc>,
+    fields: BTreeMap<String, Accumulator>,
+    /// Tags of the latest measurements.
+    tags: BTreeMap<String, String>,
+    /// When measurements were last added, to tell devices that stopped sending.
+    updated: Instant,
+}
+
+/// Combines the measurements of each device into one per write interval.
+pub struct Aggregator {
+    interval: Duration,
+    aggregations: BTreeMap<String, Aggregation>,
+    windows: BTreeMap<Option<String>, Window>,
+}
+
+impl Aggregator {
+    pub fn new(interval: Duration, aggregations: BTreeMap<String, Aggregation>) -> Self {
+        Aggregator {
+            interval,
+            aggregations,
+            windows: BTreeMap::new(),
+        }
+    }
+
+    /// Takes over the open intervals of the aggregator this one replaces, e.g. on a reload.
+    pub fn continue_from(&mut self, old: Aggregator) {
+        self.windows = old.windows;
+    }
+
+    /// Adds measurements to the current interval of their device. Returns the combined
+    /// measurements once an interval is complete, stamped with the time of its last ones.
+    pub fn push(&mut self, measurements: Measurements) -> Option<Measurements> {
+        // Events are written as they happen
+        if measurements.measurement.is_some() {
+            return Some(measurements);
+        }
+        let complete = self
+            .windows
+            .get(&measurements.device)
+            .is_some_and(|window| measurements.time - window.start >= self.interval);
+        let finished = if complete {
+            self.windows
+                .remove(&measurements.device)
+                .map(|window| self.finish(measurements.device.clone(), window))
+        } else {
+            None
+        };
+
+        let window = self
+            .windows
+            .entry(measurements.device)
+            .or_insert_with(|| Window {
+                start: measurements.time,
+                last_time: measurements.time,
+                fields: BTreeMap::new(),
+                tags: BTreeMap::new(),
+                updated: Instant::now(),
+            });
+        window.last_time = measurements.time;
+        window.updated = Instant::now();
+        window.tags = measurements.tags;
+        for (name, value) in measurements.fields {
+            window
+                .fields
+                .entry(name)
+                .and_modify(|accumulator| accumulator.add(value))
+                .or_insert_with(|| Accumulator::new(value));
+        }
+        finished
+    }
+
+    /// Finishes every open interval, e.g. before shutting down or at the end of an import.
+    pub fn flush(&mut self) -> Vec<Measurements> {
+        let windows = mem::take(&mut self.windows);
+        self.finish_all(windows)
+    }
+
+    /// Finishes the intervals of devices that sent nothing for a whole interval, which would
+    /// otherwise stay open until they send again.
+    pub fn flush_stale(&mut self) -> Vec<Measurements> {
+        let interval = self.interval.to_std().unwrap_or_default();
+        let (stale, open) = mem::take(&mut self.windows)
+            .into_iter()
+            .partition(|(_, window)| window.updated.elapsed() >= interval);
+        self.windows = open;
+        self.finish_all(stale)
+    }
+
+    fn finish_all(&self, windows: BTreeMap<Option<String>, Window>) -> Vec<Measurements> {
+        windows
+            .into_iter()
+            .map(|(device, window)| self.finish(device, window))
+            .collect()
+    }
+
+    fn finish(&self, device: Option<String>, window: Window) -> Measurements {
+        let fields = window
+            .fields
+            .into_iter()
+            .map(|(name, accumulator)| {
+                let aggregation = self.aggregations.get(&name).copied().unwrap_or_default();
+                (name, accumulator.value(aggregation))
+            })
+            .collect();
+        Measurements {
+            time: window.last_time,
+            device,
+            measurement: None,
+            fields,
+            text: None,
+            tags: window.tags,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measurements(time: DateTime<Utc>, device: &str, value: f64) -> Measurements {
+        let mut measurements = Measurements::empty();
+        measurements.time = time;
+        measurements.device = Some(device.to_owned());
+        measurements.fields.insert("temperature".to_owned(), value);
+        measurements
+    }
+
+    fn aggregator() -> Aggregator {
+        let aggregations = BTreeMap::from([("temperature".to_owned(), Aggregation::Mean)]);
+        Aggregator::new(Duration::seconds(60), aggregations)
+    }
+
+    #[test]
+    fn combines_an_interval_once_it_is_complete() {
+        let mut aggregator = aggregator();
+        let start = Utc::now();
+        assert!(aggregator.push(measurements(start, "a", 10.0)).is_none());
+        let second = start + Duration::seconds(30);
+        assert!(aggregator.push(measurements(second, "a", 20.0)).is_none());
+        let next = start + Duration::seconds(60);
+        let finished = aggregator.push(measurements(next, "a", 50.0)).unwrap();
+        assert_eq!(finished.time, second);
+        assert_eq!(finished.fields["temperature"], 15.0);
+    }
+
+    #[test]
+    fn flushes_open_intervals() {
+        let mut aggregator = aggregator();
+        let start = Utc::now();
+        aggregator.push(measurements(start, "a", 10.0));
+        aggregator.push(measurements(start, "b", 30.0));
+        aggregator.push(measurements(start + Duration::seconds(10), "a", 20.0));
+        let flushed = aggregator.flush();
+        let means: Vec<_> = flushed
+            .iter()
+            .map(|measurements| measurements.fields["temperature"])
+            .collect();
+        assert_eq!(means, [15.0, 30.0]);
+        assert!(aggregator.flush().is_empty());
+    }
+
+    #[test]
+    fn keeps_intervals_of_devices_still_sending() {
+        let mut aggregator = aggregator();
+        aggregator.push(measurements(Utc::now(), "a", 10.0));
+        assert!(aggregator.flush_stale().is_empty());
+        assert_eq!(aggregator.flush().len(), 1);
+    }
+}
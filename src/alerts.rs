@@ -0,0 +1,505 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+use color_eyre::{eyre::eyre, Result};
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::{
+    sink::mqtt::{self, MqttConfig},
+    telegram::{self, TelegramConfig},
+    Measurements,
+};
+
+const PUSHOVER_URL: &str = "https://api.pushover.net/1/messages.json";
+
+#[derive(Deserialize, Clone)]
+pub struct AlertConfig {
+    /// Topic URL notifications are published to, e.g. `https://ntfy.sh/my-solar`.
+    pub ntfy_url: Option<String>,
+    /// Access token for protected ntfy topics.
+    pub ntfy_token: Option<String>,
+    /// Application token and user key to notify through Pushover.
+    pub pushover_token: Option<String>,
+    pub pushover_user: Option<String>,
+    /// Topic alerts are published to as JSON, through the broker of `[mqtt]`.
+    pub mqtt_topic: Option<String>,
+    /// URL alerts are POSTed to as JSON.
+    pub webhook_url: Option<String>,
+    /// Measurement alerts firing and resolving are written to as events.
+    #[serde(default = "default_event_measurement")]
+    pub event_measurement: String,
+    #[serde(default)]
+    pub rules: Vec<AlertRule>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct AlertRule {
+    /// Used in messages and as `rule` tag of the events, e.g. `temperature_01 > 120` if not set.
+    pub name: Option<String>,
+    pub field: String,
+    pub comparison: Comparison,
+    pub threshold: f64,
+    /// How far the value has to get back from the threshold before the alert resolves, so a
+    /// value hovering around it doesn't alert over and over.
+    #[serde(default)]
+    pub hysteresis: f64,
+    /// Seconds the threshold has to stay breached before the alert fires.
+    #[serde(default)]
+    pub duration: u64,
+    /// Seconds before the alert is sent again while the threshold is still breached.
+    #[serde(default = "default_cooldown")]
+    pub cooldown: u64,
+    /// Notifiers of this rule, all configured ones if empty.
+    #[serde(default)]
+    pub channels: Vec<Channel>,
+}
+
+fn default_cooldown() -> u64 {
+    3600
+}
+
+fn default_event_measurement() -> String {
+    "alert_events".to_owned()
+}
+
+impl AlertRule {
+    fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| {
+            format!(
+                "{} {} {}",
+                self.field,
+                self.comparison.symbol(),
+                self.threshold
+            )
+        })
+    }
+
+    fn notifies(&self, channel: Channel) -> bool {
+        self.channels.is_empty() || self.channels.contains(&channel)
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    Ntfy,
+    Pushover,
+    Telegram,
+    Mqtt,
+    Webhook,
+}
+
+const CHANNELS: [Channel; 5] = [
+    Channel::Ntfy,
+    Channel::Pushover,
+    Channel::Telegram,
+    Channel::Mqtt,
+    Channel::Webhook,
+];
+
+impl Channel {
+    fn name(self) -> &'static str {
+        match self {
+            Channel::Ntfy => "ntfy",
+            Channel::Pushover => "Pushover",
+            Channel::Telegram => "Telegram",
+            Channel::Mqtt => "MQTT",
+            Channel::Webhook => "the webhook",
+        }
+    }
+}
+
+/// When a value breaches the threshold.
+#[derive(Deserialize, Clone, Copy)]
+pub enum Comparison {
+    #[serde(rename = ">")]
+    Above,
+    #[serde(rename = ">=")]
+    AtLeast,
+    #[serde(rename = "<")]
+    Below,
+    #[serde(rename = "<=")]
+    AtMost,
+}
+
+impl Comparison {
+    fn breached(self, value: f64, threshold: f64) -> bool {
+        match self {
+            Comparison::Above => value > threshold,
+            Comparison::AtLeast => value >= threshold,
+            Comparison::Below => value < threshold,
+            Comparison::AtMost => value <= threshold,
+        }
+    }
+
+    /// Whether a value is back on the right side of the threshold by `hysteresis`.
+    fn cleared(self, value: f64, threshold: f64, hysteresis: f64) -> bool {
+        let threshold = match self {
+            Comparison::Above | Comparison::AtLeast => threshold - hysteresis,
+            Comparison::Below | Comparison::AtMost => threshold + hysteresis,
+        };
+        !self.breached(value, threshold)
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            Comparison::Above => ">",
+            Comparison::AtLeast => ">=",
+            Comparison::Below => "<",
+            Comparison::AtMost => "<=",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum RuleState {
+    /// Breached since then, but not for the rule's `duration` yet.
+    Pending(Instant),
+    Firing {
+        last_sent: Instant,
+    },
+}
+
+enum Outcome {
+    Fired,
+    /// Still firing after the cool-down.
+    Repeated,
+    Resolved,
+}
+
+/// Notifies when a rule fires, again after every cool-down while it keeps firing, and when it
+/// resolves, per device.
+pub struct Alerter {
+    config: AlertConfig,
+    telegram: Option<TelegramConfig>,
+    mqtt: Option<MqttConfig>,
+    client: Client,
+    /// Rules breached or firing, per device. The others are fine.
+    states: HashMap<(usize, Option<String>), RuleState>,
+}
+
+impl Alerter {
+    pub fn new(
+        config: AlertConfig,
+        telegram: Option<TelegramConfig>,
+        mqtt: Option<MqttConfig>,
+    ) -> Self {
+        Alerter {
+            config,
+            telegram,
+            mqtt,
+            client: Client::new(),
+            states: HashMap::new(),
+        }
+    }
+
+    /// Takes over the state of the rules kept from the alerter this one replaces, matched by
+    /// name, so a reload doesn't fire or resolve them again.
+    pub fn continue_from(&mut self, old: Alerter) {
+        for ((index, device), state) in old.states {
+            let name = old.config.rules[index].name();
+            if let Some(index) = self
+                .config
+                .rules
+                .iter()
+                .position(|rule| rule.name() == name)
+            {
+                self.states.insert((index, device), state);
+            }
+        }
+    }
+
+    /// Checks the rules against the measurements, notifying in the background. Returns an
+    /// event for every rule that fired or resolved.
+    pub fn check(&mut self, measurements: &Measurements) -> Vec<Measurements> {
+        let mut events = Vec::new();
+        for (index, rule) in self.config.rules.iter().enumerate() {
+            let Some(&value) = measurements.fields.get(&rule.field) else {
+                continue;
+            };
+            let key = (index, measurements.device.clone());
+            let breached = rule.comparison.breached(value, rule.threshold);
+            let duration = Duration::from_secs(rule.duration);
+            let cooldown = Duration::from_secs(rule.cooldown);
+            let outcome = match self.states.get(&key).copied() {
+                None if !breached => continue,
+                None if duration.is_zero() => Outcome::Fired,
+                None => {
+                    self.states.insert(key, RuleState::Pending(Instant::now()));
+                    continue;
+                }
+                Some(RuleState::Pending(_)) if !breached => {
+                    self.states.remove(&key);
+                    continue;
+                }
+                Some(RuleState::Pending(since)) if since.elapsed() >= duration => Outcome::Fired,
+                Some(RuleState::Pending(_)) => continue,
+                Some(RuleState::Firing { .. })
+                    if rule
+                        .comparison
+                        .cleared(value, rule.threshold, rule.hysteresis) =>
+                {
+                    Outcome::Resolved
+                }
+                Some(RuleState::Firing { last_sent }) if last_sent.elapsed() >= cooldown => {
+                    Outcome::Repeated
+                }
+                Some(RuleState::Firing { .. }) => continue,
+            };
+            let firing = !matches!(outcome, Outcome::Resolved);
+            if firing {
+                let last_sent = Instant::now();
+                self.states.insert(key, RuleState::Firing { last_sent });
+            } else {
+                self.states.remove(&key);
+            }
+
+            let mut message = format!(
+                "{} is {value} ({} {})",
+                rule.field,
+                rule.comparison.symbol(),
+                rule.threshold
+            );
+            if let Some(name) = &rule.name {
+                message = format!("{name}: {message}");
+            }
+            if !firing {
+                message = format!("Resolved: {message}");
+            }
+            if let Some(device) = &measurements.device {
+                message = format!("{device}: {message}");
+            }
+            if firing {
+                warn!("Alert: {message}");
+            } else {
+                info!("Alert: {message}");
+            }
+            // Repeated notifications aren't a change of state
+            if !matches!(outcome, Outcome::Repeated) {
+                events.push(Measurements {
+                    time: measurements.time,
+                    device: measurements.device.clone(),
+                    measurement: Some(self.config.event_measurement.clone()),
+                    fields: BTreeMap::from([
+                        ("firing".to_owned(), f64::from(u8::from(firing))),
+                        ("value".to_owned(), value),
+                    ]),
+                    text: Some(message.clone()),
+                    tags: BTreeMap::from([
+                        ("rule".to_owned(), rule.name()),
+                        ("field".to_owned(), rule.field.clone()),
+                    ]),
+                });
+            }
+
+            let alert = json!({
+                "rule": rule.name(),
+                "field": rule.field,
+                "device": measurements.device,
+                "value": value,
+                "firing": firing,
+                "message": message,
+            });
+            let client = self.client.clone();
+            let config = self.config.clone();
+            let rule = rule.clone();
+            let telegram = self.telegram.clone();
+            let mqtt = self.mqtt.clone();
+            tokio::spawn(async move {
+                let channels = Channels {
+                    client: &client,
+                    config: &config,
+                    telegram: telegram.as_ref(),
+                    broker: mqtt.as_ref(),
+                };
+                if let Err(err) = channels.notify(&rule, &message, &alert).await {
+                    warn!("{err}");
+                }
+            });
+        }
+        events
+    }
+}
+
+/// Where alerts are sent to.
+struct Channels<'a> {
+    client: &'a Client,
+    config: &'a AlertConfig,
+    telegram: Option<&'a TelegramConfig>,
+    broker: Option<&'a MqttConfig>,
+}
+
+impl Channels<'_> {
+    /// Sends the alert through every channel configured for the rule. A failing channel is
+    /// logged and doesn't keep the alert from the others, the error names all that failed.
+    async fn notify(&self, rule: &AlertRule, message: &str, alert: &Value) -> Result<()> {
+        let mut failed = Vec::new();
+        for channel in CHANNELS {
+            if !rule.notifies(channel) {
+                continue;
+            }
+            if let Err(err) = self.send(channel, message, alert).await {
+                warn!(
+                    "Error while sending alert through {}: {err}",
+                    channel.name()
+                );
+                failed.push(channel.name());
+            }
+        }
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(eyre!("Alert not sent through {}", failed.join(", ")))
+        }
+    }
+
+    /// Sends the alert through one channel, nothing if it isn't configured.
+    async fn send(&self, channel: Channel, message: &str, alert: &Value) -> Result<()> {
+        let config = self.config;
+        match channel {
+            Channel::Ntfy => {
+                let Some(url) = &config.ntfy_url else {
+                    return Ok(());
+                };
+                let mut request = self
+                    .client
+                    .post(url)
+                    .header("Title", "vbus2influx")
+                    .body(message.to_owned());
+                if let Some(token) = &config.ntfy_token {
+                    request = request.bearer_auth(token);
+                }
+                let response = request.send().await?;
+                if !response.status().is_success() {
+                    return Err(eyre!("ntfy answered {}", response.status()));
+                }
+            }
+            Channel::Pushover => {
+                let (Some(token), Some(user)) = (&config.pushover_token, &config.pushover_user)
+                else {
+                    return Ok(());
+                };
+                let response = self
+                    .client
+                    .post(PUSHOVER_URL)
+                    .form(&[
+                        ("token", token.as_str()),
+                        ("user", user.as_str()),
+                        ("title", "vbus2influx"),
+                        ("message", message),
+                    ])
+                    .send()
+                    .await?;
+                if !response.status().is_success() {
+                    return Err(eyre!("Pushover answered {}", response.status()));
+                }
+            }
+            Channel::Telegram => {
+                if let Some(telegram) = self.telegram {
+                    telegram::send_message(self.client, telegram, message).await?;
+                }
+            }
+            Channel::Mqtt => {
+                if let (Some(topic), Some(broker)) = (&config.mqtt_topic, self.broker) {
+                    mqtt::publish_once(broker, topic, alert.to_string()).await?;
+                }
+            }
+            Channel::Webhook => {
+                let Some(url) = &config.webhook_url else {
+                    return Ok(());
+                };
+                let response = self.client.post(url).json(alert).send().await?;
+                if !response.status().is_success() {
+                    return Err(eyre!("The alert webhook answered {}", response.status()));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alerter(hysteresis: f64) -> Alerter {
+        let config = AlertConfig {
+            ntfy_url: None,
+            ntfy_token: None,
+            pushover_token: None,
+            pushover_user: None,
+            mqtt_topic: None,
+            webhook_url: None,
+            event_measurement: default_event_measurement(),
+            rules: vec![AlertRule {
+                name: None,
+                field: "temperature_01".to_owned(),
+                comparison: Comparison::Above,
+                threshold: 80.0,
+                hysteresis,
+                duration: 0,
+                cooldown: default_cooldown(),
+                channels: Vec::new(),
+            }],
+        };
+        Alerter::new(config, None, None)
+    }
+
+    fn temperature(value: f64) -> Measurements {
+        let mut measurements = Measurements::empty();
+        measurements
+            .fields
+            .insert("temperature_01".to_owned(), value);
+        measurements
+    }
+
+    /// Whether the events say the rule fired (`Some(true)`) or resolved (`Some(false)`).
+    fn change(events: &[Measurements]) -> Option<bool> {
+        match events {
+            [] => None,
+            [event] => Some(event.fields["firing"] == 1.0),
+            _ => panic!("more than one event for one rule"),
+        }
+    }
+
+    #[test]
+    fn comparisons_clear_past_the_hysteresis() {
+        assert!(!Comparison::Above.cleared(78.0, 80.0, 5.0));
+        assert!(Comparison::Above.cleared(75.0, 80.0, 5.0));
+        assert!(!Comparison::AtMost.cleared(4.0, 0.0, 5.0));
+        assert!(Comparison::AtMost.cleared(5.5, 0.0, 5.0));
+    }
+
+    #[tokio::test]
+    async fn resolves_only_past_the_hysteresis() {
+        let mut alerter = alerter(5.0);
+        assert_eq!(change(&alerter.check(&temperature(70.0))), None);
+        assert_eq!(change(&alerter.check(&temperature(85.0))), Some(true));
+        assert_eq!(change(&alerter.check(&temperature(78.0))), None);
+        assert_eq!(change(&alerter.check(&temperature(81.0))), None);
+        assert_eq!(change(&alerter.check(&temperature(74.0))), Some(false));
+        assert_eq!(change(&alerter.check(&temperature(78.0))), None);
+    }
+
+    #[tokio::test]
+    async fn resolves_below_the_threshold_without_hysteresis() {
+        let mut alerter = alerter(0.0);
+        assert_eq!(change(&alerter.check(&temperature(85.0))), Some(true));
+        assert_eq!(change(&alerter.check(&temperature(80.0))), Some(false));
+    }
+
+    #[tokio::test]
+    async fn keeps_firing_across_a_reload() {
+        let mut old = alerter(5.0);
+        old.check(&temperature(85.0));
+        let mut new = alerter(5.0);
+        new.continue_from(old);
+        assert_eq!(change(&new.check(&temperature(85.0))), None);
+        assert_eq!(change(&new.check(&temperature(70.0))), Some(false));
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use resol_vbus::chrono::Utc;
+use serde::Deserialize;
+
+use crate::Measurements;
+
+/// Writes notable events as points with a `text` field and a `kind` tag, which Grafana can
+/// show as annotations.
+#[derive(Deserialize, Clone)]
+pub struct AnnotationConfig {
+    #[serde(default = "default_measurement")]
+    pub measurement: String,
+}
+
+fn default_measurement() -> String {
+    "annotations".to_owned()
+}
+
+impl AnnotationConfig {
+    pub fn annotation(&self, device: Option<String>, kind: &str, text: String) -> Measurements {
+        Measurements {
+            time: Utc::now(),
+            device,
+            measurement: Some(self.measurement.clone()),
+            fields: BTreeMap::new(),
+            text: Some(text),
+            tags: BTreeMap::from([("kind".to_owned(), kind.to_owned())]),
+        }
+    }
+
+    /// Annotation for a relay event of the `RelayTracker`.
+    pub fn relay_switched(&self, event: &Measurements) -> Option<Measurements> {
+        let (relay, &value) = event
+            .fields
+            .iter()
+            .find(|(name, _)| !name.ends_with("_previous_duration_s"))?;
+        let state = if value > 0.0 { "on" } else { "off" };
+        let mut annotation = self.annotation(
+            event.device.clone(),
+            "relay",
+            format!("{relay} switched {state}"),
+        );
+        annotation.time = event.time;
+        Some(annotation)
+    }
+}
+
+/// Remembers which fields are implausible, so faults are only annotated when they start and
+/// end instead of with every packet.
+#[derive(Default)]
+pub struct FaultTracker {
+    faulty: BTreeSet<String>,
+}
+
+impl FaultTracker {
+    /// Texts of the faults that started or ended with these measurements.
+    pub fn update(
+        &mut self,
+        dropped: &[(String, f64)],
+        measurements: &Measurements,
+    ) -> Vec<String> {
+        let mut texts = Vec::new();
+        for (name, value) in dropped {
+            if self.faulty.insert(name.clone()) {
+                texts.push(format!(
+                    "{name} implausible ({value}), dropped until it recovers"
+                ));
+            }
+        }
+        self.faulty.retain(|name| {
+            let recovered = measurements.fields.contains_key(name);
+            if recovered {
+                texts.push(format!("{name} recovered"));
+            }
+            !recovered
+        });
+        texts
+    }
+}
@@ -0,0 +1,179 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, ErrorKind, Write},
+    path::PathBuf,
+};
+
+use color_eyre::Result;
+use resol_vbus::chrono::Duration;
+use tracing::warn;
+
+use crate::{state_file, Measurements};
+
+/// Queue of measurements not yet written to InfluxDB, optionally mirrored to a file
+/// (one JSON object per line) so they survive a restart.
+pub struct Buffer {
+    points: VecDeque<Measurements>,
+    path: Option<PathBuf>,
+    /// Number of points at the front of the queue that are already in the file.
+    persisted: usize,
+    /// Points were removed from the front, so the file has to be rewritten.
+    stale: bool,
+    /// Number of points kept at most, the oldest are dropped beyond.
+    max_len: Option<usize>,
+    /// Points older than this compared to the newest one are dropped.
+    max_age: Option<Duration>,
+}
+
+impl Buffer {
+    /// Creates a buffer that only lives in memory.
+    pub fn in_memory() -> Self {
+        Buffer {
+            points: VecDeque::new(),
+            path: None,
+            persisted: 0,
+            stale: false,
+            max_len: None,
+            max_age: None,
+        }
+    }
+
+    /// Creates the buffer, loading points left over from a previous run.
+    pub fn open(path: Option<PathBuf>) -> Result<Self> {
+        let mut points = VecDeque::new();
+        if let Some(path) = &path {
+            match File::open(path) {
+                Ok(file) => {
+                    for line in BufReader::new(file).lines() {
+                        match serde_json::from_str(&line?) {
+                            Ok(point) => points.push_back(point),
+                            Err(err) => warn!("Skipping broken line in buffer file: {err}"),
+                        }
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(Buffer {
+            persisted: points.len(),
+            points,
+            path,
+            stale: false,
+            max_len: None,
+            max_age: None,
+        })
+    }
+
+    /// Limits the number and age of the points kept.
+    pub fn set_limits(&mut self, max_len: Option<usize>, max_age: Option<Duration>) {
+        self.max_len = max_len;
+        self.max_age = max_age;
+    }
+
+    /// Adds a point, returns how many of the oldest points were dropped to stay within the
+    /// limits.
+    pub fn push_back(&mut self, mut point: Measurements) -> usize {
+        // NaN and infinity would be written as `null`, which doesn't load again
+        point.fields.retain(|_, value| value.is_finite());
+        let newest = point.time;
+        self.points.push_back(point);
+        let mut dropped = 0;
+        while let Some(oldest) = self.points.front() {
+            let too_many = self
+                .max_len
+                .is_some_and(|max_len| self.points.len() > max_len);
+            let too_old = self
+                .max_age
+                .is_some_and(|max_age| newest - oldest.time > max_age);
+            if !too_many && !too_old {
+                break;
+            }
+            self.pop_front();
+            dropped += 1;
+        }
+        dropped
+    }
+
+    /// Iterates over the points, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &Measurements> {
+        self.points.iter()
+    }
+
+    pub fn pop_front(&mut self) -> Option<Measurements> {
+        let point = self.points.pop_front()?;
+        if self.persisted > 0 {
+            self.persisted -= 1;
+            self.stale = true;
+        }
+        Some(point)
+    }
+
+    /// Whether the points survive a restart.
+    pub fn is_persistent(&self) -> bool {
+        self.path.is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// Brings the file in sync with the queue, appending where possible.
+    pub fn persist(&mut self) -> Result<()> {
+        let result = self.write_file();
+        if result.is_err() {
+            // The file may end in a partial line, so it is written anew next time
+            self.stale = true;
+        }
+        result
+    }
+
+    fn write_file(&mut self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if self.stale {
+            let tmp_path = state_file::tmp_path(path);
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            for point in &self.points {
+                serde_json::to_writer(&mut writer, point)?;
+                writer.write_all(b"\n")?;
+            }
+            writer.flush()?;
+            fs::rename(tmp_path, path)?;
+        } else if self.persisted < self.points.len() {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            let mut writer = BufWriter::new(file);
+            for point in self.points.range(self.persisted..) {
+                serde_json::to_writer(&mut writer, point)?;
+                writer.write_all(b"\n")?;
+            }
+            writer.flush()?;
+        }
+        self.persisted = self.points.len();
+        self.stale = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_non_finite_values() {
+        let mut buffer = Buffer::in_memory();
+        let mut point = Measurements::empty();
+        point.fields.insert("collector".to_owned(), 21.5);
+        point.fields.insert("flow".to_owned(), f64::NAN);
+        point.fields.insert("power".to_owned(), f64::INFINITY);
+        buffer.push_back(point);
+        let point = buffer.iter().next().unwrap();
+        assert_eq!(point.fields.keys().collect::<Vec<_>>(), ["collector"]);
+    }
+}
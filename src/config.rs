@@ -0,0 +1,1087 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env,
+    ffi::OsStr,
+    fmt::Write,
+    fs, iter,
+    net::SocketAddr,
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+    sync::{Arc, PoisonError, RwLock},
+    time::Duration,
+};
+
+use chrono_tz::Tz;
+use color_eyre::{eyre::eyre, Result};
+use figment::{
+    providers::{Env, Format, Json, Toml, Yaml},
+    Figment,
+};
+use influxdb::Client;
+use resol_vbus::{chrono, Specification};
+use serde::Deserialize;
+use tracing::info;
+
+use crate::{
+    aggregate::{Aggregation, Aggregator},
+    alerts::{AlertConfig, Alerter},
+    annotations::AnnotationConfig,
+    buffer::Buffer,
+    decode::{load_specification, spec_knows_field, LEGACY_FIELD_NAMES},
+    dedup::DedupConfig,
+    delta::Deltas,
+    expr::ComputedField,
+    filter::PacketFilter,
+    heartbeat::HeartbeatConfig,
+    heat::{HeatConfig, HeatMeter},
+    led::StatusLedConfig,
+    modbus::ModbusConfig,
+    parameters::{ParameterConfig, ParameterPoller},
+    recorder::{RecordConfig, Recorder},
+    relays::{runtime_relay, RelayConfig},
+    routes::{FieldTags, RouteConfig},
+    sink::{
+        clickhouse::{ClickhouseConfig, ClickhouseSink},
+        csv::{CsvConfig, CsvSink},
+        emoncms::{EmoncmsConfig, EmoncmsSink},
+        graphite::{GraphiteConfig, GraphiteSink},
+        influx::InfluxSink,
+        line_protocol::{LineProtocolSink, Precision},
+        mqtt::{MqttConfig, MqttSink},
+        postgres::{PostgresConfig, PostgresSink},
+        pvoutput::{PvoutputConfig, PvoutputSink},
+        redis::{RedisConfig, RedisSink},
+        remote_write::{RemoteWriteConfig, RemoteWriteSink},
+        sqlite::{SqliteConfig, SqliteSink},
+        stdout::{StdoutConfig, StdoutSink},
+        webhook::{WebhookConfig, WebhookSink},
+        SinkRunner,
+    },
+    source::{self, DeviceSource, SourceConfig, UartParity, UartSource},
+    spec_update::SpecUpdateConfig,
+    telegram::TelegramConfig,
+    totals::TotalsConfig,
+    units::Unit,
+    vbus_server::{RawBytes, VbusServerConfig},
+};
+
+#[derive(Deserialize)]
+pub struct Config {
+    /// Without a URL nothing is written to InfluxDB.
+    pub(crate) db_url: Option<String>,
+    /// Major version of the InfluxDB server, `1` or `2`.
+    #[serde(default = "default_db_version")]
+    pub(crate) db_version: u8,
+    pub(crate) db_token: Option<String>,
+    /// File holding the token instead of `db_token`, e.g. a Docker secret. Without either, the
+    /// systemd credential `db_token` (`LoadCredential=db_token:...`) is used if there is one.
+    pub(crate) db_token_file: Option<PathBuf>,
+    pub(crate) db_org: Option<String>,
+    pub(crate) db_bucket: Option<String>,
+    pub(crate) db_username: Option<String>,
+    pub(crate) db_password: Option<String>,
+    pub(crate) db_database: Option<String>,
+    pub(crate) db_retention_policy: Option<String>,
+    #[serde(default = "default_db_measurement")]
+    pub(crate) db_measurement: String,
+    /// Fields written to other measurements or buckets than the ones above.
+    #[serde(default)]
+    pub(crate) routes: Vec<RouteConfig>,
+    /// Number of points sent to InfluxDB in one request.
+    #[serde(default = "default_db_batch_size")]
+    pub(crate) db_batch_size: usize,
+    /// Seconds after which an incomplete batch is sent anyway.
+    #[serde(default = "default_db_batch_interval")]
+    pub(crate) db_batch_interval: u64,
+    /// Post line protocol to `/api/v2/write` directly instead of going through the client
+    /// library, which also works with InfluxDB 3 and compatible databases.
+    #[serde(default)]
+    pub(crate) db_line_protocol: bool,
+    /// Timestamp precision of the points written to InfluxDB: `s`, `ms`, `us` or `ns`. Times
+    /// are truncated to it, so points of one interval line up with other collectors'.
+    #[serde(default = "default_db_precision")]
+    pub(crate) db_precision: String,
+    /// Compress request bodies with gzip, also accepted as `db_compression`. Needs
+    /// `db_line_protocol`.
+    #[serde(default, alias = "db_compression")]
+    pub(crate) db_gzip: bool,
+    /// PEM file with the CA InfluxDB's certificate is issued by, trusted besides the system
+    /// ones. Needs `db_line_protocol`.
+    pub(crate) db_ca_cert: Option<PathBuf>,
+    /// Accept any certificate, e.g. a self-signed one. Needs `db_line_protocol`.
+    #[serde(default)]
+    pub(crate) db_insecure_skip_verify: bool,
+    /// Create the bucket (the database with v1) and those of the routes on startup if they
+    /// don't exist yet.
+    #[serde(default)]
+    pub(crate) db_create_bucket: bool,
+    /// Seconds after which a created bucket drops data, kept forever if not set.
+    pub(crate) db_bucket_retention: Option<u64>,
+    /// Print measurements instead of writing them anywhere, like `--dry-run`.
+    #[serde(default)]
+    pub(crate) dry_run: bool,
+    #[serde(default)]
+    pub(crate) dry_run_format: DryRunFormat,
+    /// Log every frame read from the bus, like `--debug-packets`.
+    #[serde(default)]
+    pub(crate) debug_packets: bool,
+    /// Filter directive for log output, e.g. `info` or `vbus2influx=debug`.
+    #[serde(default = "default_log_level")]
+    pub(crate) log_level: String,
+    /// Log JSON lines instead of human readable text.
+    #[serde(default)]
+    pub(crate) log_json: bool,
+    pub(crate) uart_path: Option<PathBuf>,
+    /// Line settings for `uart_path`, only needed for adapters that don't speak VBus's 9600
+    /// baud 8N1.
+    #[serde(default = "source::default_baud")]
+    pub(crate) uart_baud: u32,
+    #[serde(default)]
+    pub(crate) uart_parity: UartParity,
+    #[serde(default = "source::default_stop_bits")]
+    pub(crate) uart_stop_bits: u8,
+    /// Frames in a row with a wrong checksum after which `uart_path` is reopened.
+    pub(crate) uart_max_invalid_frames: Option<NonZeroU32>,
+    /// Pin switching the adapter's power, cut for a moment before reopening `uart_path`.
+    pub(crate) uart_power_pin: Option<u8>,
+    pub(crate) source: Option<SourceConfig>,
+    /// Seconds without a matching packet after which a source is reopened.
+    #[serde(default = "default_stall_timeout")]
+    pub(crate) stall_timeout: u64,
+    /// Seconds after which packets of live sources are too old to be written, e.g. the last
+    /// ones a datalogger keeps serving after the controller stopped sending.
+    pub(crate) max_data_age: Option<u64>,
+    /// Several sources read at the same time, takes precedence over `source`.
+    #[serde(default)]
+    pub(crate) sources: Vec<DeviceSource>,
+    pub(crate) webserver_address: Option<SocketAddr>,
+    /// PEM certificate and key, serving HTTPS instead of HTTP if both are set.
+    pub(crate) webserver_tls_cert: Option<PathBuf>,
+    pub(crate) webserver_tls_key: Option<PathBuf>,
+    /// Bearer token required for every request except `/health`.
+    pub(crate) webserver_token: Option<String>,
+    /// Basic auth credentials required for every request except `/health`.
+    pub(crate) webserver_username: Option<String>,
+    pub(crate) webserver_password: Option<String>,
+    /// IANA time zone the webserver shows times in, e.g. `Europe/Berlin`, UTC if not set.
+    /// Everything written keeps UTC.
+    pub(crate) display_timezone: Option<String>,
+    /// Number of measurements kept in memory for `/history`.
+    #[serde(default = "default_history_size")]
+    pub(crate) history_size: usize,
+    /// File unsent measurements are kept in while InfluxDB is unreachable.
+    pub(crate) buffer_path: Option<PathBuf>,
+    /// Number of unsent measurements kept per output, the oldest are dropped beyond.
+    pub(crate) buffer_max_len: Option<usize>,
+    /// Seconds unsent measurements are kept, older ones are dropped.
+    pub(crate) buffer_max_age: Option<i64>,
+    #[serde(default)]
+    pub(crate) packet_filter: PacketFilter,
+    /// Further packets whose fields are decoded along with the next one matching the packet
+    /// filter, e.g. the heat quantity packets of a DeltaSol's HQM.
+    #[serde(default)]
+    pub(crate) merge_packets: Vec<PacketFilter>,
+    /// Milliseconds a packet matching the packet filter waits for a packet matching each of
+    /// `merge_packets`, by the packets' timestamps. Without it the latest merged packets are
+    /// used right away, possibly ones of the previous cycle.
+    pub(crate) merge_wait_ms: Option<u64>,
+    #[serde(default)]
+    pub(crate) fields: Vec<FieldConfig>,
+    pub(crate) mqtt: Option<MqttConfig>,
+    pub(crate) csv: Option<CsvConfig>,
+    pub(crate) sqlite: Option<SqliteConfig>,
+    pub(crate) postgres: Option<PostgresConfig>,
+    pub(crate) clickhouse: Option<ClickhouseConfig>,
+    pub(crate) remote_write: Option<RemoteWriteConfig>,
+    pub(crate) graphite: Option<GraphiteConfig>,
+    /// Latest values kept in Redis, optionally as RedisTimeSeries too.
+    pub(crate) redis: Option<RedisConfig>,
+    pub(crate) emoncms: Option<EmoncmsConfig>,
+    /// Solar thermal yield uploaded to PVOutput.org.
+    pub(crate) pvoutput: Option<PvoutputConfig>,
+    /// Line protocol printed to stdout, for running under Telegraf's `inputs.execd`.
+    pub(crate) stdout: Option<StdoutConfig>,
+    /// URLs measurements are POSTed to as JSON.
+    #[serde(default)]
+    pub(crate) webhooks: Vec<WebhookConfig>,
+    pub(crate) heat: Option<HeatConfig>,
+    /// Fields computed from other fields, in order.
+    #[serde(default)]
+    pub(crate) computed: Vec<ComputedField>,
+    pub(crate) relays: Option<RelayConfig>,
+    /// Notifications when fields breach thresholds.
+    pub(crate) alerts: Option<AlertConfig>,
+    /// Bot answering `/status` and sending alerts.
+    pub(crate) telegram: Option<TelegramConfig>,
+    /// Daily and weekly totals written at midnight.
+    pub(crate) totals: Option<TotalsConfig>,
+    /// File keeping the heat energy and relay runtimes across restarts, saved every few
+    /// minutes.
+    pub(crate) counters_path: Option<PathBuf>,
+    /// LED showing whether packets arrive and writes to InfluxDB succeed, needs the `rppal`
+    /// feature.
+    pub(crate) status_led: Option<StatusLedConfig>,
+    /// Read-only Modbus TCP slave serving the latest values.
+    pub(crate) modbus: Option<ModbusConfig>,
+    /// VBus/LAN compatible server forwarding the raw bus of a live source.
+    pub(crate) vbus_server: Option<VbusServerConfig>,
+    /// Writes relay switches, sensor faults, reconnects and starts as annotations.
+    pub(crate) annotations: Option<AnnotationConfig>,
+    /// Point written every few seconds whether or not packets arrive.
+    pub(crate) heartbeat: Option<HeartbeatConfig>,
+    /// Seconds of measurements combined into one point, each is written if not set.
+    pub(crate) write_interval: Option<u64>,
+    /// Only write measurements that changed, or when a heartbeat is due.
+    pub(crate) dedup: Option<DedupConfig>,
+    /// Records the raw bus traffic of live sources.
+    pub(crate) record: Option<RecordConfig>,
+    /// A newer `vbus_specification.vsf` than the embedded one, e.g. downloaded from RESOL.
+    pub(crate) spec_path: Option<PathBuf>,
+    /// Downloads the specification periodically, preferred over `spec_path` once it did.
+    pub(crate) spec_update: Option<SpecUpdateConfig>,
+    /// Where the time of the measurements comes from.
+    #[serde(default)]
+    pub(crate) timestamps: TimestampSource,
+    /// Write packets that lack mapped fields (e.g. after a firmware update) without them
+    /// instead of failing.
+    #[serde(default)]
+    pub(crate) skip_missing_fields: bool,
+    /// Write every field the specification knows for the packets, named after the field in
+    /// snake case (e.g. `temperature_sensor_1`) unless mapped in `fields`. Names taken by a
+    /// mapped or an earlier field get `_2`, `_3` and so on appended.
+    #[serde(default)]
+    pub(crate) map_all_fields: bool,
+    /// Names or packet field IDs `map_all_fields` leaves out.
+    #[serde(default)]
+    pub(crate) exclude_fields: Vec<String>,
+    /// Tags of every point, e.g. the site, below any other tags of the same name.
+    #[serde(default)]
+    pub(crate) tags: BTreeMap<String, String>,
+    /// Tag points with the name (`controller`) and address (`source_address`) of the
+    /// controller that sent them.
+    #[serde(default)]
+    pub(crate) controller_tags: bool,
+    /// Controller parameters read with datagram requests, only over UART and TCP.
+    #[serde(default)]
+    pub(crate) parameters: Vec<ParameterConfig>,
+    /// Seconds between reads of the parameters.
+    #[serde(default = "default_parameter_interval")]
+    pub(crate) parameter_interval: u64,
+}
+
+/// How measurements are printed in dry-run mode.
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum DryRunFormat {
+    #[default]
+    Json,
+    /// As they would be sent to InfluxDB.
+    LineProtocol,
+}
+
+/// Where measurements get their time from. Either way it stays with them through buffers
+/// and queues until they are written.
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum TimestampSource {
+    /// The data's own time for recordings, the decode time otherwise.
+    #[default]
+    Auto,
+    /// The time stored in the recording or, for live sources, when the packet was received.
+    Data,
+    /// The time the packet was decoded.
+    Now,
+}
+
+/// Maps a field of the VBus specification to an InfluxDB field.
+///
+/// Entries without `packet_field_id` don't map anything themselves, they only configure a
+/// field that exists anyway, e.g. one of the default fields or a computed one.
+#[derive(Deserialize)]
+pub(crate) struct FieldConfig {
+    /// Packet field ID as found in the specification, e.g. `00_0010_7E11_10_0100_000_2_0`.
+    pub(crate) packet_field_id: Option<String>,
+    pub(crate) name: String,
+    /// Values below are treated as sensor fault and dropped.
+    pub(crate) min: Option<f64>,
+    /// Values above are treated as sensor fault and dropped.
+    pub(crate) max: Option<f64>,
+    /// Added to the decoded value after scaling, e.g. to correct a sensor's known offset.
+    #[serde(default)]
+    pub(crate) offset: f64,
+    /// Factor the decoded value is multiplied with.
+    #[serde(default = "default_scale")]
+    pub(crate) scale: f64,
+    /// How values are combined within `write_interval`.
+    #[serde(default)]
+    pub(crate) aggregate: Aggregation,
+    /// Unit the decoded value is converted to, before scale, offset and the range check.
+    pub(crate) unit: Option<Unit>,
+    /// Written with InfluxDB, which puts the field into a point of its own.
+    #[serde(default)]
+    pub(crate) tags: BTreeMap<String, String>,
+    /// Also write `<name>_delta`, the increase since the last write, for counters like
+    /// operating hours or heat quantity. Counters going down count as reset to zero.
+    #[serde(default)]
+    pub(crate) delta: bool,
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+impl FieldConfig {
+    pub(crate) fn calibrate(&self, value: f64) -> f64 {
+        value * self.scale + self.offset
+    }
+
+    pub(crate) fn is_plausible(&self, value: f64) -> bool {
+        self.min.is_none_or(|min| value >= min) && self.max.is_none_or(|max| value <= max)
+    }
+}
+
+fn default_db_version() -> u8 {
+    2
+}
+
+fn default_db_measurement() -> String {
+    "vbus2influx".to_owned()
+}
+
+fn default_stall_timeout() -> u64 {
+    60
+}
+
+fn default_db_batch_size() -> usize {
+    1
+}
+
+fn default_db_batch_interval() -> u64 {
+    10
+}
+
+fn default_history_size() -> usize {
+    3600
+}
+
+fn default_db_precision() -> String {
+    "s".to_owned()
+}
+
+fn default_parameter_interval() -> u64 {
+    300
+}
+
+fn default_log_level() -> String {
+    "info".to_owned()
+}
+
+impl Config {
+    /// Whether a field is a cumulative value computed here, kept in `counters_path`.
+    pub(crate) fn is_counter(&self, field: &str) -> bool {
+        self.heat
+            .as_ref()
+            .is_some_and(|heat| heat.energy_field == field)
+            || self.relays.as_ref().is_some_and(|relays| {
+                runtime_relay(field).is_some_and(|relay| relays.is_tracked(relay))
+            })
+    }
+
+    pub(crate) fn stall_timeout(&self) -> Duration {
+        Duration::from_secs(self.stall_timeout)
+    }
+
+    /// Records the raw bytes of the source if configured, and passes them on to `forward`.
+    pub(crate) fn recorder(
+        &self,
+        device_source: &DeviceSource,
+        forward: Option<RawBytes>,
+    ) -> Option<Recorder> {
+        if self.record.is_none() && forward.is_none() {
+            return None;
+        }
+        Some(Recorder::new(
+            self.record.clone(),
+            device_source.device.clone(),
+            forward,
+        ))
+    }
+
+    /// Creates the InfluxDB client for the configured server version.
+    ///
+    /// InfluxDB 1.8 is written to through its 2.x compatibility API, which takes
+    /// `username:password` as token and `database/retention_policy` as bucket.
+    pub(crate) fn influx_client(&self) -> Result<Client> {
+        let (url, org, bucket, token) = self.influx_target()?;
+        Ok(Client::new(url, org, &bucket, &token))
+    }
+
+    /// Reads `db_token` from `db_token_file` or the systemd credential, unless it is set.
+    pub(crate) fn read_token(&mut self) -> Result<()> {
+        let path = match (&self.db_token, &self.db_token_file) {
+            (Some(_), Some(_)) => {
+                return Err(eyre!("Set either `db_token` or `db_token_file`, not both."))
+            }
+            (Some(_), None) => return Ok(()),
+            (None, Some(path)) => path.clone(),
+            (None, None) => match env::var_os("CREDENTIALS_DIRECTORY") {
+                Some(directory) => Path::new(&directory).join("db_token"),
+                None => return Ok(()),
+            },
+        };
+        if self.db_token_file.is_none() && !path.exists() {
+            return Ok(());
+        }
+        let token = fs::read_to_string(&path)
+            .map_err(|err| eyre!("Can't read the token from `{}`: {err}", path.display()))?;
+        self.db_token = Some(token.trim().to_owned());
+        Ok(())
+    }
+
+    /// URL, organisation, bucket and token to write to, depending on `db_version`.
+    pub(crate) fn influx_target(&self) -> Result<(&str, &str, String, String)> {
+        let url = required(&self.db_url, "db_url")?;
+        match self.db_version {
+            1 => {
+                let database = required(&self.db_database, "db_database")?;
+                let bucket = match &self.db_retention_policy {
+                    Some(retention_policy) => format!("{database}/{retention_policy}"),
+                    None => database.to_owned(),
+                };
+                let token = format!(
+                    "{}:{}",
+                    self.db_username.as_deref().unwrap_or_default(),
+                    self.db_password.as_deref().unwrap_or_default(),
+                );
+                Ok((url, "-", bucket, token))
+            }
+            2 => Ok((
+                url,
+                required(&self.db_org, "db_org")?,
+                required(&self.db_bucket, "db_bucket")?.to_owned(),
+                required(&self.db_token, "db_token")?.to_owned(),
+            )),
+            version => Err(eyre!("Unsupported `db_version` {version}.")),
+        }
+    }
+
+    /// Aggregator for `write_interval`, `None` if every measurement is written.
+    pub(crate) fn aggregator(&self) -> Option<Aggregator> {
+        let interval = self.write_interval?;
+        let aggregations = self
+            .fields
+            .iter()
+            .map(|field| (field.name.clone(), field.aggregate))
+            .collect();
+        Some(Aggregator::new(
+            chrono::Duration::seconds(i64::try_from(interval).ok()?),
+            aggregations,
+        ))
+    }
+
+    /// Computes the deltas of counters, `None` if no field asks for them.
+    pub(crate) fn deltas(&self) -> Option<Deltas> {
+        let fields: BTreeSet<_> = self
+            .fields
+            .iter()
+            .filter(|field| field.delta)
+            .map(|field| field.name.clone())
+            .collect();
+        (!fields.is_empty()).then(|| Deltas::new(fields))
+    }
+
+    pub(crate) fn display_timezone(&self) -> Result<Option<Tz>> {
+        self.display_timezone
+            .as_deref()
+            .map(|timezone| {
+                timezone
+                    .parse()
+                    .map_err(|err| eyre!("Invalid `display_timezone`: {err}"))
+            })
+            .transpose()
+    }
+
+    /// The `unit` configured for a field, `None` if it stays metric.
+    pub(crate) fn field_unit(&self, name: &str) -> Option<Unit> {
+        self.fields
+            .iter()
+            .find(|field| field.name == name)
+            .and_then(|field| field.unit)
+    }
+
+    /// Heat meter for `[heat]`, taking the units of its input fields into account.
+    pub(crate) fn heat_meter(&self) -> Option<HeatMeter> {
+        let heat = self.heat.clone()?;
+        Some(HeatMeter::new(heat, |name| self.field_unit(name)))
+    }
+
+    /// Alerter for the configured rules, `None` without `[alerts]`.
+    pub(crate) fn alerter(&self) -> Option<Alerter> {
+        let alerts = self.alerts.clone()?;
+        Some(Alerter::new(
+            alerts,
+            self.telegram.clone(),
+            self.mqtt.clone(),
+        ))
+    }
+
+    /// Poller for the configured controller parameters, `None` if there are none.
+    pub(crate) fn parameter_poller(&self) -> Option<ParameterPoller> {
+        (!self.parameters.is_empty()).then(|| {
+            ParameterPoller::new(
+                self.parameters.clone(),
+                Duration::from_secs(self.parameter_interval),
+            )
+        })
+    }
+
+    /// HTTP client for InfluxDB, trusting `db_ca_cert` or any certificate if configured.
+    pub(crate) fn http_client(&self) -> Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder();
+        if let Some(path) = &self.db_ca_cert {
+            let pem = std::fs::read(path)
+                .map_err(|e| eyre!("Failed to read `{}`: {e}", path.display()))?;
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+        }
+        if self.db_insecure_skip_verify {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        Ok(builder.build()?)
+    }
+
+    /// Checks whether the InfluxDB server answers at all.
+    pub(crate) async fn ping_influx(&self) -> Result<()> {
+        let url = required(&self.db_url, "db_url")?;
+        let response = self
+            .http_client()?
+            .get(format!("{}/ping", url.trim_end_matches('/')))
+            .send()
+            .await?;
+        response.error_for_status()?;
+        Ok(())
+    }
+
+    /// Adds the credentials to a request to InfluxDB's API.
+    pub(crate) fn influx_auth(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        if self.db_version == 1 {
+            request.basic_auth(
+                self.db_username.as_deref().unwrap_or_default(),
+                self.db_password.as_deref(),
+            )
+        } else {
+            let token = self.db_token.as_deref().unwrap_or_default();
+            request.header(reqwest::header::AUTHORIZATION, format!("Token {token}"))
+        }
+    }
+
+    /// Checks that InfluxDB accepts the credentials and knows the bucket or database.
+    pub(crate) async fn check_influx_access(&self) -> Result<()> {
+        let (url, org, bucket, _) = self.influx_target()?;
+        let url = url.trim_end_matches('/');
+        let client = self.http_client()?;
+        let request = if self.db_version == 1 {
+            client
+                .get(format!("{url}/query"))
+                .query(&[("q", "SHOW DATABASES")])
+        } else {
+            client
+                .get(format!("{url}/api/v2/buckets"))
+                .query(&[("org", org), ("name", &bucket)])
+        };
+        let body = influx_body(self.influx_auth(request).send().await?, "list buckets").await?;
+        // v1 lists database names, v2 the buckets matching the name.
+        let name = bucket.split('/').next().unwrap_or_default();
+        let known = if self.db_version == 1 {
+            database_names(&body)?.contains(name)
+        } else {
+            let buckets: serde_json::Value = serde_json::from_str(&body)?;
+            buckets["buckets"]
+                .as_array()
+                .is_some_and(|buckets| buckets.iter().any(|known| known["name"] == *bucket))
+        };
+        if !known {
+            return Err(eyre!(
+                "InfluxDB doesn't know the bucket or database `{bucket}`."
+            ));
+        }
+        Ok(())
+    }
+
+    /// Creates the bucket (the database with v1) and those of the routes unless they exist,
+    /// expiring data after `db_bucket_retention` seconds.
+    pub(crate) async fn create_buckets(&self) -> Result<()> {
+        let (url, org, bucket, _) = self.influx_target()?;
+        let url = url.trim_end_matches('/');
+        let client = self.http_client()?;
+        let mut names: Vec<_> = iter::once(bucket.as_str())
+            .chain(
+                self.routes
+                    .iter()
+                    .filter_map(|route| route.bucket.as_deref()),
+            )
+            .map(|bucket| bucket.split('/').next().unwrap_or_default())
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+
+        if self.db_version == 1 {
+            let request = client
+                .get(format!("{url}/query"))
+                .query(&[("q", "SHOW DATABASES")]);
+            let body =
+                influx_body(self.influx_auth(request).send().await?, "list databases").await?;
+            let existing = database_names(&body)?;
+            for name in names {
+                if existing.contains(name) {
+                    continue;
+                }
+                let mut query = format!("CREATE DATABASE {}", quote_influxql(name));
+                if let Some(retention) = self.db_bucket_retention {
+                    let _ = write!(query, " WITH DURATION {retention}s");
+                }
+                let request = client.post(format!("{url}/query")).query(&[("q", query)]);
+                influx_body(self.influx_auth(request).send().await?, "create databases").await?;
+                info!("Created the InfluxDB database `{name}`.");
+            }
+            return Ok(());
+        }
+
+        let request = client
+            .get(format!("{url}/api/v2/orgs"))
+            .query(&[("org", org)]);
+        let body = influx_body(
+            self.influx_auth(request).send().await?,
+            "read organizations",
+        )
+        .await?;
+        let orgs: serde_json::Value = serde_json::from_str(&body)?;
+        let org_id = orgs["orgs"][0]["id"]
+            .as_str()
+            .ok_or_else(|| eyre!("InfluxDB doesn't know the organization `{org}`."))?;
+        for name in names {
+            let request = client
+                .get(format!("{url}/api/v2/buckets"))
+                .query(&[("orgID", org_id), ("name", name)]);
+            let body = influx_body(self.influx_auth(request).send().await?, "list buckets").await?;
+            let buckets: serde_json::Value = serde_json::from_str(&body)?;
+            if buckets["buckets"]
+                .as_array()
+                .is_some_and(|buckets| !buckets.is_empty())
+            {
+                continue;
+            }
+            let retention_rules: Vec<_> = self
+                .db_bucket_retention
+                .map(|seconds| serde_json::json!({ "type": "expire", "everySeconds": seconds }))
+                .into_iter()
+                .collect();
+            let request = client
+                .post(format!("{url}/api/v2/buckets"))
+                .json(&serde_json::json!({
+                    "orgID": org_id,
+                    "name": name,
+                    "retentionRules": retention_rules,
+                }));
+            influx_body(self.influx_auth(request).send().await?, "create buckets").await?;
+            info!("Created the InfluxDB bucket `{name}`.");
+        }
+        Ok(())
+    }
+
+    /// Checks that every mapped packet field is known to the specification.
+    pub(crate) fn check_fields(&self, spec: &Specification) -> Result<()> {
+        let unknown: Vec<_> = self
+            .fields
+            .iter()
+            .filter_map(|field| field.packet_field_id.as_deref())
+            .filter(|id| !spec_knows_field(spec, id))
+            .collect();
+        if unknown.is_empty() {
+            Ok(())
+        } else {
+            Err(eyre!(
+                "Unknown packet field IDs {}, see `vbus2influx list-fields` for the fields your \
+                 controller sends.",
+                unknown.join(", ")
+            ))
+        }
+    }
+
+    /// Checks everything that can be checked without connecting anywhere.
+    pub(crate) fn validate(&self) -> Result<()> {
+        self.sources()?;
+        self.display_timezone()?;
+        load_specification(self)?;
+        if self.status_led.is_some() && !cfg!(feature = "rppal") {
+            return Err(eyre!(
+                "`status_led` needs a build with the `rppal` feature."
+            ));
+        }
+        if self
+            .alerts
+            .as_ref()
+            .is_some_and(|alerts| alerts.mqtt_topic.is_some())
+            && self.mqtt.is_none()
+        {
+            return Err(eyre!(
+                "`alerts.mqtt_topic` needs a broker configured in `[mqtt]`."
+            ));
+        }
+        if self.db_url.is_some() {
+            self.influx_client()?;
+            self.http_client()?;
+            Precision::parse(&self.db_precision)?;
+            // The client library always uses its own HTTP client
+            if (self.db_ca_cert.is_some() || self.db_insecure_skip_verify) && !self.db_line_protocol
+            {
+                return Err(eyre!(
+                    "`db_ca_cert` and `db_insecure_skip_verify` need `db_line_protocol = true`."
+                ));
+            }
+            if self.db_gzip && !self.db_line_protocol {
+                return Err(eyre!("`db_compression` needs `db_line_protocol = true`."));
+            }
+            if self.db_batch_interval == 0 {
+                return Err(eyre!("`db_batch_interval` has to be at least 1 second."));
+            }
+        }
+        if self
+            .clickhouse
+            .as_ref()
+            .is_some_and(|clickhouse| clickhouse.batch_interval == 0)
+        {
+            return Err(eyre!(
+                "`clickhouse.batch_interval` has to be at least 1 second."
+            ));
+        }
+        if let Some(emoncms) = &self.emoncms {
+            EmoncmsSink::new(emoncms.clone())?;
+        }
+        if let Some(redis) = &self.redis {
+            RedisSink::new(redis.clone())?;
+        }
+        if let Some(stdout) = &self.stdout {
+            Precision::parse(&stdout.precision)?;
+        }
+        // Sinks of the same name would share their counters and be mixed up in logs
+        let mut webhook_names = BTreeSet::new();
+        for webhook in &self.webhooks {
+            if !webhook_names.insert(&webhook.name) {
+                return Err(eyre!(
+                    "Several `[[webhooks]]` are named `{}`, give each a `name` of its own.",
+                    webhook.name
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates all configured sinks.
+    pub(crate) fn sinks(&self) -> Result<Vec<SinkRunner>> {
+        let mut sinks = Vec::new();
+        if self.db_url.is_some() {
+            let (url, org, bucket, token) = self.influx_target()?;
+            let mut runner = if self.db_line_protocol {
+                let mut sink = LineProtocolSink::new(
+                    url,
+                    org,
+                    &bucket,
+                    &token,
+                    &self.db_measurement,
+                    Precision::parse(&self.db_precision)?,
+                    self.db_gzip,
+                )?;
+                sink.client = self.http_client()?;
+                sink.routes = self.routes.clone();
+                sink.field_tags = self.field_tags();
+                SinkRunner::new(sink)
+            } else {
+                let route_clients = self
+                    .routes
+                    .iter()
+                    .filter_map(|route| route.bucket.clone())
+                    .map(|bucket| {
+                        let client = Client::new(url, org, &bucket, &token);
+                        (bucket, client)
+                    })
+                    .collect();
+                SinkRunner::new(InfluxSink {
+                    client: self.influx_client()?,
+                    route_clients,
+                    measurement: self.db_measurement.clone(),
+                    routes: self.routes.clone(),
+                    field_tags: self.field_tags(),
+                    precision: Precision::parse(&self.db_precision)?,
+                })
+            };
+            runner.buffer = Buffer::open(self.buffer_path.clone())?;
+            runner.batch_size = self.db_batch_size;
+            runner.batch_interval = Duration::from_secs(self.db_batch_interval);
+            sinks.push(runner);
+        }
+        if let Some(mqtt) = &self.mqtt {
+            sinks.push(SinkRunner::new(MqttSink::new(mqtt.clone())?));
+        }
+        if let Some(remote_write) = &self.remote_write {
+            sinks.push(SinkRunner::new(RemoteWriteSink::new(remote_write.clone())));
+        }
+        if let Some(graphite) = &self.graphite {
+            sinks.push(SinkRunner::new(GraphiteSink::new(graphite.clone())));
+        }
+        if let Some(redis) = &self.redis {
+            sinks.push(SinkRunner::new(RedisSink::new(redis.clone())?));
+        }
+        if let Some(emoncms) = &self.emoncms {
+            sinks.push(SinkRunner::new(EmoncmsSink::new(emoncms.clone())?));
+        }
+        if let Some(pvoutput) = &self.pvoutput {
+            let mut runner = SinkRunner::new(PvoutputSink::new(pvoutput.clone()));
+            runner.buffer = Buffer::open(pvoutput.buffer_path.clone())?;
+            sinks.push(runner);
+        }
+        if let Some(stdout) = &self.stdout {
+            sinks.push(SinkRunner::new(StdoutSink {
+                measurement: self.db_measurement.clone(),
+                routes: self.routes.clone(),
+                field_tags: self.field_tags(),
+                precision: Precision::parse(&stdout.precision)?,
+            }));
+        }
+        for webhook in &self.webhooks {
+            sinks.push(SinkRunner::new(WebhookSink::new(webhook.clone())));
+        }
+        if let Some(csv) = &self.csv {
+            sinks.push(SinkRunner::new(CsvSink::new(
+                csv.clone(),
+                self.field_names(),
+            )));
+        }
+        if let Some(sqlite) = &self.sqlite {
+            sinks.push(SinkRunner::new(SqliteSink::new(
+                sqlite.clone(),
+                self.field_names(),
+            )?));
+        }
+        if let Some(postgres) = &self.postgres {
+            sinks.push(SinkRunner::new(PostgresSink::new(
+                postgres.clone(),
+                self.field_names(),
+            )?));
+        }
+        if let Some(clickhouse) = &self.clickhouse {
+            let mut runner =
+                SinkRunner::new(ClickhouseSink::new(clickhouse.clone(), self.field_names())?);
+            runner.batch_size = clickhouse.batch_size;
+            runner.batch_interval = clickhouse.batch_interval();
+            sinks.push(runner);
+        }
+        let max_age = self.buffer_max_age.map(chrono::Duration::seconds);
+        for runner in &mut sinks {
+            runner.buffer.set_limits(self.buffer_max_len, max_age);
+        }
+        Ok(sinks)
+    }
+
+    /// The `tags` of the fields that have any.
+    pub(crate) fn field_tags(&self) -> FieldTags {
+        self.fields
+            .iter()
+            .filter(|field| !field.tags.is_empty())
+            .map(|field| (field.name.clone(), field.tags.clone()))
+            .collect()
+    }
+
+    /// Names of the fields decoded from packets, in mapping order.
+    pub(crate) fn mapped_field_names(&self) -> Vec<String> {
+        if !self.map_all_fields && self.fields.iter().all(|f| f.packet_field_id.is_none()) {
+            LEGACY_FIELD_NAMES
+                .iter()
+                .map(|name| name.to_string())
+                .collect()
+        } else {
+            self.fields
+                .iter()
+                .filter(|f| f.packet_field_id.is_some())
+                .map(|f| f.name.clone())
+                .collect()
+        }
+    }
+
+    /// Names of all fields the measurements can contain, in mapping order.
+    pub(crate) fn field_names(&self) -> Vec<String> {
+        let mut names = self.mapped_field_names();
+        if let Some(relays) = &self.relays {
+            let runtimes: Vec<_> = names
+                .iter()
+                .filter(|name| relays.is_tracked(name))
+                .map(|name| format!("{name}_runtime_h"))
+                .collect();
+            names.extend(runtimes);
+        }
+        names.extend(self.parameters.iter().map(|p| p.name.clone()));
+        if let Some(heat) = &self.heat {
+            names.push(heat.power_field.clone());
+            names.push(heat.energy_field.clone());
+        }
+        names.extend(self.computed.iter().map(|field| field.name.clone()));
+        let deltas: Vec<_> = self
+            .fields
+            .iter()
+            .filter(|field| field.delta && names.contains(&field.name))
+            .map(|field| format!("{}_delta", field.name))
+            .collect();
+        names.extend(deltas);
+        names
+    }
+
+    /// The configured data sources, falling back to `source` and the legacy `uart_path` key.
+    pub(crate) fn sources(&self) -> Result<Vec<DeviceSource>> {
+        let source = match (&self.source, &self.uart_path) {
+            _ if !self.sources.is_empty() => return Ok(self.sources.clone()),
+            (Some(source), _) => source.clone(),
+            (None, Some(path)) => SourceConfig::Uart(UartSource {
+                path: path.clone(),
+                baud: self.uart_baud,
+                parity: self.uart_parity,
+                stop_bits: self.uart_stop_bits,
+                max_invalid_frames: self.uart_max_invalid_frames,
+                power_pin: self.uart_power_pin,
+            }),
+            (None, None) => {
+                return Err(eyre!(
+                    "Neither `sources`, `source` nor `uart_path` is configured."
+                ))
+            }
+        };
+        Ok(vec![DeviceSource {
+            device: None,
+            source,
+        }])
+    }
+}
+
+fn required<'a>(value: &'a Option<String>, key: &str) -> Result<&'a str> {
+    value
+        .as_deref()
+        .ok_or_else(|| eyre!("`{key}` has to be configured."))
+}
+
+/// Reads the config file (TOML, or YAML or JSON by its extension), keys can be overridden by
+/// `VBUS2INFLUX_` environment variables.
+pub fn load_config(path: &Path) -> Result<Config> {
+    let path = config_file(path);
+    let figment = match path.extension().and_then(OsStr::to_str) {
+        Some("yaml" | "yml") => Figment::new().merge(Yaml::file(&path)),
+        Some("json") => Figment::new().merge(Json::file(&path)),
+        _ => Figment::new().merge(Toml::file(&path)),
+    };
+    extract_config(figment)
+}
+
+/// The configuration from a file's contents, overridden by environment variables.
+pub(crate) fn extract_config(figment: Figment) -> Result<Config> {
+    let mut config: Config = figment
+        .merge(Env::prefixed("VBUS2INFLUX_").split("__"))
+        .extract()?;
+    config.read_token()?;
+    Ok(config)
+}
+
+/// The config file to read for `path`: a missing `vbus2influx.toml` may as well be a
+/// `vbus2influx.yaml`, `.yml` or `.json` next to it.
+pub(crate) fn config_file(path: &Path) -> PathBuf {
+    if path.exists()
+        || path
+            .extension()
+            .is_some_and(|extension| extension != "toml")
+    {
+        return path.to_owned();
+    }
+    ["yaml", "yml", "json"]
+        .into_iter()
+        .map(|extension| path.with_extension(extension))
+        .find(|candidate| candidate.exists())
+        .unwrap_or_else(|| path.to_owned())
+}
+
+/// The body of a successful response of InfluxDB's API, otherwise an error saying what went
+/// wrong while trying to `action`.
+async fn influx_body(response: reqwest::Response, action: &str) -> Result<String> {
+    match response.status() {
+        status if status.is_success() => Ok(response.text().await?),
+        reqwest::StatusCode::UNAUTHORIZED => Err(eyre!(
+            "InfluxDB rejected the credentials, check `db_token` or `db_username`/`db_password`."
+        )),
+        reqwest::StatusCode::FORBIDDEN => Err(eyre!(
+            "The InfluxDB token or user lacks the permission to {action}."
+        )),
+        status => {
+            let message = response.text().await.unwrap_or_default();
+            Err(eyre!(
+                "InfluxDB answered {status} when trying to {action}: {message}"
+            ))
+        }
+    }
+}
+
+/// The names in InfluxDB v1's answer to `SHOW DATABASES`.
+fn database_names(body: &str) -> Result<BTreeSet<String>> {
+    let response: serde_json::Value = serde_json::from_str(body)?;
+    let values = response["results"][0]["series"][0]["values"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    Ok(values
+        .iter()
+        .filter_map(|row| row[0].as_str())
+        .map(str::to_owned)
+        .collect())
+}
+
+/// Quotes an identifier for InfluxQL, which escapes with backslashes.
+fn quote_influxql(name: &str) -> String {
+    format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// The current configuration, replaced when it is reloaded.
+#[derive(Clone)]
+pub struct SharedConfig(Arc<RwLock<Arc<Config>>>);
+
+impl SharedConfig {
+    pub fn new(config: Arc<Config>) -> Self {
+        SharedConfig(Arc::new(RwLock::new(config)))
+    }
+
+    pub(crate) fn get(&self) -> Arc<Config> {
+        Arc::clone(&self.0.read().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    pub(crate) fn set(&self, config: Arc<Config>) {
+        *self.0.write().unwrap_or_else(PoisonError::into_inner) = config;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_database_names() {
+        let body = r#"{"results":[{"statement_id":0,"series":[{"name":"databases",
+            "columns":["name"],"values":[["_internal"],["solar \"roof\""]]}]}]}"#;
+        let names = database_names(body).unwrap();
+        assert_eq!(
+            names,
+            BTreeSet::from(["_internal".to_owned(), "solar \"roof\"".to_owned()])
+        );
+        assert!(database_names(r#"{"results":[{"statement_id":0}]}"#)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn quotes_influxql_identifiers() {
+        assert_eq!(quote_influxql("solar"), r#""solar""#);
+        assert_eq!(quote_influxql(r#"a"b\c"#), r#""a\"b\\c""#);
+    }
+}
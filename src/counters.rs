@@ -0,0 +1,73 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::Result;
+use resol_vbus::chrono::{DateTime, Utc};
+use tracing::warn;
+
+use crate::{state_file, Measurements};
+
+/// Latest values of the counter fields, by field name.
+pub type DeviceCounters = BTreeMap<String, f64>;
+
+/// Keeps the cumulative fields computed here, the heat energy and relay runtimes, in a file
+/// they are restored from when a source starts, so they continue after a restart.
+pub struct Counters {
+    path: PathBuf,
+    /// By device, unnamed sources use an empty name.
+    devices: BTreeMap<String, DeviceCounters>,
+    last_save: Option<DateTime<Utc>>,
+}
+
+impl Counters {
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let devices = state_file::load(&path)?;
+        Ok(Counters {
+            path,
+            devices,
+            last_save: None,
+        })
+    }
+
+    /// Takes over the latest counters of the ones this replaces, e.g. on a reload.
+    pub fn continue_from(&mut self, old: Counters) {
+        self.devices = old.devices;
+        self.last_save = old.last_save;
+    }
+
+    /// The counters saved for a device, none if there are none or the file can't be read.
+    pub fn saved(path: &Path, device: &str) -> DeviceCounters {
+        match state_file::load::<BTreeMap<String, DeviceCounters>>(path) {
+            Ok(mut devices) => devices.remove(device).unwrap_or_default(),
+            Err(err) => {
+                warn!("Error while reading the counters, starting from zero: {err}");
+                DeviceCounters::new()
+            }
+        }
+    }
+
+    /// Takes the counter fields from the measurements, saving them every few minutes.
+    pub fn update(&mut self, measurements: &Measurements, is_counter: impl Fn(&str) -> bool) {
+        let device = measurements.device.clone().unwrap_or_default();
+        let counters = self.devices.entry(device).or_default();
+        for (name, value) in &measurements.fields {
+            if is_counter(name) && value.is_finite() {
+                counters.insert(name.clone(), *value);
+            }
+        }
+
+        let time = measurements.time;
+        if state_file::save_due(self.last_save, time) {
+            if let Err(err) = self.save() {
+                warn!("Error while saving the counters: {err}");
+            }
+            self.last_save = Some(time);
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        state_file::save(&self.path, &self.devices)
+    }
+}
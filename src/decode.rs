@@ -0,0 +1,622 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    time::Instant,
+};
+
+use color_eyre::{eyre::eyre, Result};
+use resol_vbus::{
+    chrono::{self, Utc},
+    Data, DataSet, Language, Packet, Specification, SpecificationFile,
+};
+use tracing::{debug, instrument, warn};
+
+use crate::{
+    config::Config, parameters::ParameterPoller, source::DataReader, spec_update, Measurements,
+};
+
+/// Whether the specification has a field with the given ID, e.g.
+/// `00_0010_7E11_10_0100_000_2_0` (channel, destination, source, protocol version, command
+/// and field).
+pub(crate) fn spec_knows_field(spec: &Specification, packet_field_id: &str) -> bool {
+    let parts: Vec<_> = packet_field_id.split('_').collect();
+    let [channel, destination, source, _, command, ..] = parts[..] else {
+        return false;
+    };
+    let parse = |hex| u16::from_str_radix(hex, 16).ok();
+    let (Some(channel), Some(destination), Some(source), Some(command)) = (
+        parse(channel),
+        parse(destination),
+        parse(source),
+        parse(command),
+    ) else {
+        return false;
+    };
+    let Ok(channel) = u8::try_from(channel) else {
+        return false;
+    };
+    spec.get_packet_spec(channel, destination, source, command)
+        .fields
+        .iter()
+        .any(|field| field.packet_field_id == packet_field_id)
+}
+
+/// The VSF file the binary ships with.
+pub(crate) const EMBEDDED_SPECIFICATION: &[u8] = include_bytes!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/src/vbus_specification.vsf",
+));
+
+/// Decodes the specification from `spec_path`, or the one included in the binary if that
+/// isn't configured or doesn't exist.
+pub fn load_specification(config: &Config) -> Result<Specification> {
+    if let Some(latest) = spec_update::latest() {
+        let spec_file = SpecificationFile::from_bytes(&latest)?;
+        return Ok(Specification::from_file(spec_file, Language::En));
+    }
+    let spec_bytes = match &config.spec_path {
+        Some(path) if path.exists() => {
+            debug!(path = %path.display(), "Loading specification");
+            Cow::Owned(fs::read(path)?)
+        }
+        Some(path) => {
+            warn!(
+                "Specification `{}` not found, using the embedded one.",
+                path.display()
+            );
+            Cow::Borrowed(EMBEDDED_SPECIFICATION)
+        }
+        None => Cow::Borrowed(EMBEDDED_SPECIFICATION),
+    };
+    let spec_file = SpecificationFile::from_bytes(&spec_bytes)?;
+    Ok(Specification::from_file(spec_file, Language::En))
+}
+
+/// Field names used when no `[[fields]]` mapping is configured, in the order the
+/// DeltaSol BX Plus emits them.
+pub(crate) const LEGACY_FIELD_NAMES: [&str; 22] = [
+    "temperature_01",
+    "temperature_02",
+    "temperature_03",
+    "temperature_04",
+    "temperature_05",
+    "temperature_06",
+    "temperature_07",
+    "temperature_08",
+    "temperature_09",
+    "irradiation_10",
+    "temperature_11",
+    "temperature_12",
+    "flow_rate_09",
+    "flow_rate_11",
+    "flow_rate_12",
+    "pressure_11",
+    "pressure_12",
+    "relay_01",
+    "relay_02",
+    "relay_03",
+    "relay_04",
+    "relay_05",
+];
+
+/// What decoding keeps from one packet to the next: the data set packets are read into and
+/// where the mapped fields are in the packets, so neither a data set is allocated nor the
+/// fields searched by ID for every packet.
+pub struct DecodeState {
+    pub(crate) dataset: DataSet,
+    /// The latest packet matching each of `merge_packets`.
+    merged: Vec<Data>,
+    /// Whether each of `merge_packets` matched a packet since the last one was decoded.
+    fresh: Vec<bool>,
+    /// Packet matching the packet filter that waits for the merged packets of its cycle.
+    pending: Option<Data>,
+    /// Packets the positions were resolved for, in data set order.
+    pub(crate) packet_ids: Vec<String>,
+    /// The mapped `packet_field_id`s the positions were resolved for, in config order.
+    ids: Vec<String>,
+    /// Index of the packet and the field in it of each of `ids`, `None` if no packet has it.
+    positions: Vec<Option<(usize, usize)>>,
+}
+
+impl Default for DecodeState {
+    fn default() -> Self {
+        DecodeState {
+            dataset: DataSet::new(),
+            merged: Vec::new(),
+            fresh: Vec::new(),
+            pending: None,
+            packet_ids: Vec::new(),
+            ids: Vec::new(),
+            positions: Vec::new(),
+        }
+    }
+}
+
+/// Identifies a packet regardless of its content.
+fn packet_key(packet: &Packet) -> (u8, u16, u16, u16) {
+    let header = &packet.header;
+    (
+        header.channel,
+        header.destination_address,
+        header.source_address,
+        packet.command,
+    )
+}
+
+/// Reads data until a packet matching the filter arrives and puts it into the `state` in
+/// place of the previous one, followed by the latest packets matching `merge_packets`. With
+/// `merge_wait_ms` it waits until every merge filter matched a packet since the previous
+/// one, so the packets are of the same cycle. Returns `false` once the source is exhausted.
+/// Datagrams on the way are handed to the parameter poller, if any.
+///
+/// Fails if only other data arrives for longer than the stall timeout.
+pub fn read_packet(
+    reader: &mut dyn DataReader,
+    config: &Config,
+    mut parameters: Option<&mut ParameterPoller>,
+    state: &mut DecodeState,
+) -> Result<bool> {
+    let deadline = Instant::now() + config.stall_timeout();
+    let merge_wait = config
+        .merge_wait_ms
+        .and_then(|wait| i64::try_from(wait).ok())
+        .map(chrono::Duration::milliseconds);
+    state.fresh.resize(config.merge_packets.len(), false);
+    while let Some(data) = reader.read_data()? {
+        match &data {
+            // Checked first, as the packet filter may match any source address
+            Data::Packet(packet) if config.merge_packets.iter().any(|f| f.matches(packet)) => {
+                for (fresh, filter) in state.fresh.iter_mut().zip(&config.merge_packets) {
+                    *fresh |= filter.matches(packet);
+                }
+                let key = packet_key(packet);
+                state.merged.retain(|merged| match merged {
+                    Data::Packet(merged) => packet_key(merged) != key,
+                    _ => false,
+                });
+                state.merged.push(data.clone());
+            }
+            // One of the next cycle replaces a packet still waiting for its merged ones
+            Data::Packet(packet) if config.packet_filter.matches(packet) => {
+                state.pending = Some(data.clone());
+            }
+            Data::Datagram(datagram) => {
+                if let Some(parameters) = parameters.as_deref_mut() {
+                    parameters.handle(datagram, reader)?;
+                }
+            }
+            _ => {}
+        }
+        // Checked for every kind of data, as merged packets alone don't make a cycle either
+        let Some(pending) = &state.pending else {
+            if Instant::now() >= deadline {
+                return Err(eyre!("No matching packet within the stall timeout."));
+            }
+            continue;
+        };
+        let waited = data.as_header().timestamp - pending.as_header().timestamp;
+        let complete = state.fresh.iter().all(|fresh| *fresh);
+        if complete || merge_wait.is_none_or(|merge_wait| waited >= merge_wait) {
+            if !complete && merge_wait.is_some() {
+                let missing: Vec<_> = config
+                    .merge_packets
+                    .iter()
+                    .zip(&state.fresh)
+                    .filter(|(_, fresh)| !**fresh)
+                    .map(|(filter, _)| filter.to_string())
+                    .collect();
+                debug!(
+                    ?missing,
+                    "Cycle incomplete, using the latest merged packets"
+                );
+            }
+            assemble(state, config);
+            return Ok(true);
+        }
+    }
+    // The last cycle of a recording
+    if state.pending.is_some() {
+        assemble(state, config);
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+/// Puts the pending packet into the data set, followed by the merged ones, and starts the
+/// next cycle.
+fn assemble(state: &mut DecodeState, config: &Config) {
+    let Some(data) = state.pending.take() else {
+        return;
+    };
+    let timestamp = data.as_header().timestamp;
+    // Packets that stopped arriving don't contribute their last values forever
+    state.merged.retain(|merged| {
+        (timestamp - merged.as_header().timestamp)
+            .to_std()
+            .map_or(true, |age| age <= config.stall_timeout())
+    });
+    state.dataset.remove_all_data();
+    state.dataset.add_data(data);
+    for merged in &state.merged {
+        state.dataset.add_data(merged.clone());
+    }
+    // Adding data only ever moves the timestamp forward
+    state.dataset.timestamp = timestamp;
+    state.fresh.iter_mut().for_each(|fresh| *fresh = false);
+}
+
+/// Reads measurements from vbus data, `None` once the source is exhausted.
+///
+/// With `data_timestamps` the measurements are stamped with the time the packet was recorded
+/// or received, otherwise with the time it was decoded.
+pub fn read_data(
+    reader: &mut dyn DataReader,
+    spec: &Specification,
+    config: &Config,
+    data_timestamps: bool,
+    mut parameters: Option<&mut ParameterPoller>,
+    state: &mut DecodeState,
+) -> Result<Option<Measurements>> {
+    if !read_packet(reader, config, parameters.as_deref_mut(), state)? {
+        return Ok(None);
+    }
+    decode(state, spec, config, data_timestamps, parameters.as_deref()).map(Some)
+}
+
+/// Decodes the mapped fields of the packet read last, adding the latest parameter values if
+/// any.
+#[instrument(skip_all)]
+pub fn decode(
+    state: &mut DecodeState,
+    spec: &Specification,
+    config: &Config,
+    data_timestamps: bool,
+    parameters: Option<&ParameterPoller>,
+) -> Result<Measurements> {
+    let DecodeState {
+        dataset,
+        packet_ids,
+        ids,
+        positions,
+        ..
+    } = state;
+    let time = if data_timestamps {
+        dataset.timestamp
+    } else {
+        Utc::now()
+    };
+    debug!(%time, "Decoding packet");
+    // The packet matching the filter comes first, the merged ones after it
+    let packets: Vec<_> = dataset
+        .as_data_slice()
+        .iter()
+        .filter_map(|data| match data {
+            Data::Packet(packet) => Some(packet),
+            _ => None,
+        })
+        .map(|packet| {
+            let header = &packet.header;
+            let packet_spec = spec.get_packet_spec(
+                header.channel,
+                header.destination_address,
+                header.source_address,
+                packet.command,
+            );
+            let frame_data = &packet.frame_data[..usize::from(packet.frame_count) * 4];
+            (packet_spec, frame_data, header)
+        })
+        .collect();
+    let Some((packet_spec, frame_data, header)) = packets.first() else {
+        return Err(eyre!("No packet to decode."));
+    };
+    let mut values = BTreeMap::new();
+    if config.map_all_fields {
+        let mut taken: BTreeSet<_> = config
+            .fields
+            .iter()
+            .map(|field| field.name.clone())
+            .collect();
+        let all_fields = packets.iter().flat_map(|(packet_spec, frame_data, _)| {
+            packet_spec
+                .fields
+                .iter()
+                .map(move |field_spec| (field_spec, *frame_data))
+        });
+        for (field_spec, frame_data) in all_fields {
+            let id = &field_spec.packet_field_id;
+            let name = match config
+                .fields
+                .iter()
+                .find(|field| field.packet_field_id.as_ref() == Some(id))
+            {
+                Some(field) => field.name.clone(),
+                None => unique_field_key(&field_spec.name, &mut taken),
+            };
+            if config
+                .exclude_fields
+                .iter()
+                .any(|excluded| excluded == id || *excluded == name)
+            {
+                continue;
+            }
+            // Fields beyond the end of a short packet are left out
+            let Some(value) = field_spec.raw_value_f64(frame_data) else {
+                continue;
+            };
+            let value = convert_unit(config, &name, value, &field_spec.unit_code)?;
+            // Only mapped names can repeat, the first one wins
+            values.entry(name).or_insert(value);
+        }
+    } else if config.fields.iter().all(|f| f.packet_field_id.is_none()) {
+        for (index, name) in LEGACY_FIELD_NAMES.iter().enumerate() {
+            let field_spec = match packet_spec.fields.get(index) {
+                Some(field_spec) => field_spec,
+                None if config.skip_missing_fields => continue,
+                None => return Err(eyre!("Field `{name}` not set.")),
+            };
+            let value = field_spec
+                .raw_value_f64(frame_data)
+                .ok_or_else(|| eyre!("Field `{name}` can't be converted to `f64`."))?;
+            let value = convert_unit(config, name, value, &field_spec.unit_code)?;
+            values.insert(name.to_string(), value);
+        }
+    } else {
+        // Resolved again for other packets or a reloaded config
+        let mapped = config
+            .fields
+            .iter()
+            .filter_map(|field| field.packet_field_id.as_ref());
+        let current_ids = packets
+            .iter()
+            .map(|(packet_spec, _, _)| &packet_spec.packet_id);
+        if !current_ids.clone().eq(packet_ids.iter()) || !mapped.clone().eq(ids.iter()) {
+            *packet_ids = current_ids.cloned().collect();
+            *ids = mapped.cloned().collect();
+            *positions =
+                ids.iter()
+                    .map(|id| {
+                        packets.iter().enumerate().find_map(
+                            |(packet_index, (packet_spec, _, _))| {
+                                let fields = &packet_spec.fields;
+                                let field_index = fields
+                                    .iter()
+                                    .position(|field_spec| &field_spec.packet_field_id == id)?;
+                                Some((packet_index, field_index))
+                            },
+                        )
+                    })
+                    .collect();
+        }
+        let mapped_fields = config
+            .fields
+            .iter()
+            .filter(|field| field.packet_field_id.is_some());
+        for (field, position) in mapped_fields.zip(positions.iter()) {
+            let name = &field.name;
+            let found = position.and_then(|(packet_index, field_index)| {
+                let (packet_spec, frame_data, _) = packets.get(packet_index)?;
+                Some((packet_spec.fields.get(field_index)?, *frame_data))
+            });
+            let (field_spec, frame_data) = match found {
+                Some(found) => found,
+                None if config.skip_missing_fields => continue,
+                None => return Err(eyre!("Field `{name}` not set.")),
+            };
+            let value = field_spec
+                .raw_value_f64(frame_data)
+                .ok_or_else(|| eyre!("Field `{name}` can't be converted to `f64`."))?;
+            let value = convert_unit(config, name, value, &field_spec.unit_code)?;
+            values.insert(name.clone(), value);
+        }
+    }
+
+    let mut measurements = Measurements {
+        time,
+        device: None,
+        measurement: None,
+        fields: values,
+        text: None,
+        tags: BTreeMap::new(),
+    };
+    if config.controller_tags {
+        let device_spec = spec.get_device_spec(
+            header.channel,
+            header.source_address,
+            header.destination_address,
+        );
+        measurements
+            .tags
+            .insert("controller".to_owned(), device_spec.name.clone());
+        measurements.tags.insert(
+            "source_address".to_owned(),
+            format!("0x{:04X}", header.source_address),
+        );
+    }
+    if let Some(parameters) = parameters {
+        parameters.apply(&mut measurements);
+    }
+    Ok(measurements)
+}
+
+/// Converts a decoded value to the `unit` configured for the field, if any.
+fn convert_unit(config: &Config, name: &str, value: f64, unit_code: &str) -> Result<f64> {
+    match config.field_unit(name) {
+        Some(unit) => unit
+            .convert(value, unit_code)
+            .ok_or_else(|| eyre!("Field `{name}` in `{unit_code}` can't be converted to {unit}.")),
+        None => Ok(value),
+    }
+}
+
+/// Field name for a field of the specification, e.g. `temperature_sensor_1` for
+/// `Temperature sensor 1` or `waermemenge` for `Wärmemenge`.
+fn field_key(name: &str) -> String {
+    let mut key = String::with_capacity(name.len());
+    for c in name.chars().flat_map(char::to_lowercase) {
+        match c {
+            'ä' => key.push_str("ae"),
+            'ö' => key.push_str("oe"),
+            'ü' => key.push_str("ue"),
+            'ß' => key.push_str("ss"),
+            _ if c.is_ascii_alphanumeric() => key.push(c),
+            _ if !key.is_empty() && !key.ends_with('_') => key.push('_'),
+            _ => {}
+        }
+    }
+    let key = key.trim_end_matches('_');
+    if key.is_empty() {
+        "field".to_owned()
+    } else {
+        key.to_owned()
+    }
+}
+
+/// The `field_key` of a field of the specification, with `_2`, `_3` and so on appended while
+/// it is `taken` by a mapped or an earlier field, and added to `taken`. Keys stay the same as
+/// long as the packets and the mapped names do.
+pub(crate) fn unique_field_key(name: &str, taken: &mut BTreeSet<String>) -> String {
+    let key = field_key(name);
+    let mut unique = key.clone();
+    let mut suffix = 2;
+    while !taken.insert(unique.clone()) {
+        unique = format!("{key}_{suffix}");
+        suffix += 1;
+    }
+    unique
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use figment::{
+        providers::{Format, Toml},
+        Figment,
+    };
+    use resol_vbus::Header;
+
+    use super::*;
+
+    /// Temperature sensor 1 of the DeltaSol BX Plus.
+    const TEMPERATURE_1: &str = "00_0010_7E11_10_0100_000_2_0";
+
+    /// Hands out canned data, e.g. packets captured from a controller.
+    struct CannedReader(VecDeque<Data>);
+
+    impl DataReader for CannedReader {
+        fn read_data(&mut self) -> Result<Option<Data>> {
+            Ok(self.0.pop_front())
+        }
+    }
+
+    fn config(toml: &str) -> Config {
+        Figment::from(Toml::string(toml)).extract().unwrap()
+    }
+
+    fn spec() -> Specification {
+        let spec_file = SpecificationFile::from_bytes(EMBEDDED_SPECIFICATION).unwrap();
+        Specification::from_file(spec_file, Language::En)
+    }
+
+    /// A packet of the DeltaSol BX Plus with temperature sensor 1 at `temperature` °C.
+    fn packet(temperature: f64) -> Data {
+        let mut frame_data = [0; 508];
+        let raw = (temperature * 10.0).round() as i16;
+        frame_data[..2].copy_from_slice(&raw.to_le_bytes());
+        Data::Packet(Packet {
+            header: Header {
+                timestamp: Utc::now(),
+                channel: 0,
+                destination_address: 0x0010,
+                source_address: 0x7E11,
+                protocol_version: 0x10,
+            },
+            command: 0x0100,
+            frame_count: 127,
+            frame_data,
+        })
+    }
+
+    fn read(config: &Config, data: Vec<Data>) -> Option<Measurements> {
+        let mut reader = CannedReader(data.into());
+        let mut state = DecodeState::default();
+        read_data(&mut reader, &spec(), config, true, None, &mut state).unwrap()
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{actual} != {expected}");
+    }
+
+    #[test]
+    fn derives_field_keys_from_names() {
+        assert_eq!(field_key("Temperature sensor 1"), "temperature_sensor_1");
+        assert_eq!(field_key("Wärmemenge"), "waermemenge");
+        assert_eq!(field_key("Flow rate (l/h)"), "flow_rate_l_h");
+        assert_eq!(field_key("---"), "field");
+    }
+
+    #[test]
+    fn suffixes_taken_field_keys() {
+        let mut taken = BTreeSet::from(["temperature_sensor_1".to_owned()]);
+        assert_eq!(
+            unique_field_key("Temperature sensor 1", &mut taken),
+            "temperature_sensor_1_2"
+        );
+        assert_eq!(
+            unique_field_key("Temperature sensor 1", &mut taken),
+            "temperature_sensor_1_3"
+        );
+    }
+
+    #[test]
+    fn knows_fields_of_the_specification() {
+        let spec = spec();
+        assert!(spec_knows_field(&spec, TEMPERATURE_1));
+        assert!(!spec_knows_field(&spec, "00_0010_7E11_10_0100_999_2_0"));
+        assert!(!spec_knows_field(&spec, "not an id"));
+    }
+
+    #[test]
+    fn decodes_mapped_fields() {
+        let config = config(&format!(
+            "[[fields]]\nname = \"collector\"\npacket_field_id = \"{TEMPERATURE_1}\"\n"
+        ));
+        let measurements = read(&config, vec![packet(21.5)]).unwrap();
+        assert_eq!(measurements.fields.len(), 1);
+        assert_close(measurements.fields["collector"], 21.5);
+    }
+
+    #[test]
+    fn converts_mapped_fields_to_their_unit() {
+        let config = config(&format!(
+            "[[fields]]\nname = \"collector\"\npacket_field_id = \"{TEMPERATURE_1}\"\n\
+             unit = \"°F\"\n"
+        ));
+        let measurements = read(&config, vec![packet(21.5)]).unwrap();
+        assert_close(measurements.fields["collector"], 70.7);
+    }
+
+    #[test]
+    fn skips_packets_not_matching_the_filter() {
+        let config = config(&format!(
+            "[[fields]]\nname = \"collector\"\npacket_field_id = \"{TEMPERATURE_1}\"\n"
+        ));
+        let Data::Packet(mut other) = packet(21.5) else {
+            unreachable!()
+        };
+        other.command = 0x0200;
+        assert!(read(&config, vec![Data::Packet(other)]).is_none());
+    }
+
+    #[test]
+    fn stalls_on_merged_packets_alone() {
+        let config = config(
+            "stall_timeout = 0\n[packet_filter]\ncommand = \"0x0200\"\n\
+             [[merge_packets]]\ncommand = \"0x0100\"\n",
+        );
+        let mut reader = CannedReader(vec![packet(21.5), packet(22.0)].into());
+        let mut state = DecodeState::default();
+        assert!(read_packet(&mut reader, &config, None, &mut state).is_err());
+    }
+}
@@ -0,0 +1,70 @@
+use std::collections::BTreeMap;
+
+use resol_vbus::chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+
+use crate::Measurements;
+
+#[derive(Deserialize, Clone)]
+pub struct DedupConfig {
+    /// Changes up to this much don't count as change.
+    #[serde(default)]
+    pub delta: f64,
+    /// Seconds after which measurements are written even if nothing changed.
+    #[serde(default = "default_max_interval")]
+    pub max_interval: i64,
+}
+
+fn default_max_interval() -> i64 {
+    300
+}
+
+/// Skips measurements that don't differ from the last written ones of the same device.
+pub struct Deduplicator {
+    config: DedupConfig,
+    last_written: BTreeMap<Option<String>, Measurements>,
+}
+
+impl Deduplicator {
+    pub fn new(config: DedupConfig) -> Self {
+        Deduplicator {
+            config,
+            last_written: BTreeMap::new(),
+        }
+    }
+
+    /// Takes over the last written measurements of the deduplicator this one replaces.
+    pub fn continue_from(&mut self, old: Deduplicator) {
+        self.last_written = old.last_written;
+    }
+
+    /// Whether the measurements should be written, remembering them if so.
+    pub fn should_write(&mut self, measurements: &Measurements) -> bool {
+        // Events are changes by definition
+        if measurements.measurement.is_some() {
+            return true;
+        }
+        let changed = match self.last_written.get(&measurements.device) {
+            Some(last) => {
+                let heartbeat_due =
+                    measurements.time - last.time >= Duration::seconds(self.config.max_interval);
+                heartbeat_due || self.differs(last, measurements)
+            }
+            None => true,
+        };
+        if changed {
+            self.last_written
+                .insert(measurements.device.clone(), measurements.clone());
+        }
+        changed
+    }
+
+    fn differs(&self, last: &Measurements, current: &Measurements) -> bool {
+        current.fields.len() != last.fields.len()
+            || current.fields.iter().any(|(name, value)| {
+                last.fields
+                    .get(name)
+                    .is_none_or(|last| (value - last).abs() > self.config.delta)
+            })
+    }
+}
@@ -0,0 +1,45 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::Measurements;
+
+/// Adds `<name>_delta` fields with the increase of cumulative counters since the measurements
+/// last written for the device, e.g. the Wh a heat quantity gained.
+pub struct Deltas {
+    fields: BTreeSet<String>,
+    last: BTreeMap<(Option<String>, String), f64>,
+}
+
+impl Deltas {
+    pub fn new(fields: BTreeSet<String>) -> Self {
+        Deltas {
+            fields,
+            last: BTreeMap::new(),
+        }
+    }
+
+    /// Takes over the last values of the fields still tracked from the deltas this replaces.
+    pub fn continue_from(&mut self, old: Deltas) {
+        self.last = old.last;
+        self.last.retain(|(_, name), _| self.fields.contains(name));
+    }
+
+    pub fn apply(&mut self, measurements: &mut Measurements) {
+        // Events carry no counters
+        if measurements.measurement.is_some() {
+            return;
+        }
+        let mut deltas = Vec::new();
+        for name in &self.fields {
+            let Some(&value) = measurements.fields.get(name) else {
+                continue;
+            };
+            let key = (measurements.device.clone(), name.clone());
+            if let Some(last) = self.last.insert(key, value) {
+                // A counter that went down was reset, so it counted up from zero since
+                let delta = if value >= last { value - last } else { value };
+                deltas.push((format!("{name}_delta"), delta));
+            }
+        }
+        measurements.fields.extend(deltas);
+    }
+}
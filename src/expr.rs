@@ -0,0 +1,197 @@
+use std::{collections::BTreeMap, iter::Peekable, str::Chars};
+
+use color_eyre::{eyre::eyre, Result};
+use serde::{Deserialize, Deserializer};
+
+use crate::Measurements;
+
+/// A field computed from other fields each cycle, e.g. `delta_t` from
+/// `temperature_01 - temperature_02`.
+#[derive(Deserialize, Clone)]
+pub struct ComputedField {
+    pub name: String,
+    /// Arithmetic on field names and numbers with `+ - * /`, parentheses and the functions
+    /// `abs`, `min` and `max`.
+    #[serde(deserialize_with = "deserialize_expression")]
+    pub expression: Expr,
+}
+
+/// Adds the computed fields in the configured order, so later ones can use earlier ones. A
+/// field is left out if an input is missing (e.g. dropped as implausible) or the result isn't
+/// finite.
+pub fn apply(computed: &[ComputedField], measurements: &mut Measurements) {
+    for field in computed {
+        if let Some(value) = field
+            .expression
+            .eval(&measurements.fields)
+            .filter(|value| value.is_finite())
+        {
+            measurements.fields.insert(field.name.clone(), value);
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Expr {
+    Number(f64),
+    Field(String),
+    Neg(Box<Expr>),
+    Binary(Box<Expr>, char, Box<Expr>),
+    Call(Function, Vec<Expr>),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Function {
+    Abs,
+    Min,
+    Max,
+}
+
+impl Expr {
+    pub fn parse(source: &str) -> Result<Expr> {
+        let mut parser = Parser {
+            chars: source.chars().peekable(),
+        };
+        let expr = parser.sum()?;
+        parser.skip_whitespace();
+        match parser.chars.next() {
+            Some(c) => Err(eyre!("Unexpected `{c}` in `{source}`.")),
+            None => Ok(expr),
+        }
+    }
+
+    /// `None` if a field isn't in `fields`.
+    pub fn eval(&self, fields: &BTreeMap<String, f64>) -> Option<f64> {
+        match self {
+            Expr::Number(value) => Some(*value),
+            Expr::Field(name) => fields.get(name).copied(),
+            Expr::Neg(expr) => expr.eval(fields).map(|value| -value),
+            Expr::Binary(left, op, right) => {
+                let (left, right) = (left.eval(fields)?, right.eval(fields)?);
+                match op {
+                    '+' => Some(left + right),
+                    '-' => Some(left - right),
+                    '*' => Some(left * right),
+                    _ => Some(left / right),
+                }
+            }
+            Expr::Call(function, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| arg.eval(fields))
+                    .collect::<Option<Vec<_>>>()?;
+                match function {
+                    Function::Abs => args.first().map(|value| value.abs()),
+                    Function::Min => args.into_iter().reduce(f64::min),
+                    Function::Max => args.into_iter().reduce(f64::max),
+                }
+            }
+        }
+    }
+}
+
+/// Recursive descent over `sum := product (('+' | '-') product)*`,
+/// `product := unary (('*' | '/') unary)*` and `unary := '-' unary | atom`.
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.chars.peek().copied()
+    }
+
+    fn sum(&mut self) -> Result<Expr> {
+        let mut expr = self.product()?;
+        while let Some(op @ ('+' | '-')) = self.peek() {
+            self.chars.next();
+            expr = Expr::Binary(Box::new(expr), op, Box::new(self.product()?));
+        }
+        Ok(expr)
+    }
+
+    fn product(&mut self) -> Result<Expr> {
+        let mut expr = self.unary()?;
+        while let Some(op @ ('*' | '/')) = self.peek() {
+            self.chars.next();
+            expr = Expr::Binary(Box::new(expr), op, Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if self.peek() == Some('-') {
+            self.chars.next();
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        self.atom()
+    }
+
+    fn atom(&mut self) -> Result<Expr> {
+        match self.peek() {
+            Some('(') => {
+                self.chars.next();
+                let expr = self.sum()?;
+                self.expect(')')?;
+                Ok(expr)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let number = self.take_while(|c| c.is_ascii_digit() || c == '.');
+                number
+                    .parse()
+                    .map(Expr::Number)
+                    .map_err(|_| eyre!("Invalid number `{number}`."))
+            }
+            Some(c) if c.is_alphabetic() || c == '_' => {
+                let name = self.take_while(|c| c.is_alphanumeric() || c == '_');
+                if self.peek() != Some('(') {
+                    return Ok(Expr::Field(name));
+                }
+                let function = match name.as_str() {
+                    "abs" => Function::Abs,
+                    "min" => Function::Min,
+                    "max" => Function::Max,
+                    _ => return Err(eyre!("Unknown function `{name}`.")),
+                };
+                self.chars.next();
+                let mut args = vec![self.sum()?];
+                while self.peek() == Some(',') {
+                    self.chars.next();
+                    args.push(self.sum()?);
+                }
+                self.expect(')')?;
+                Ok(Expr::Call(function, args))
+            }
+            Some(c) => Err(eyre!("Unexpected `{c}`.")),
+            None => Err(eyre!("Unexpected end of expression.")),
+        }
+    }
+
+    fn take_while(&mut self, predicate: impl Fn(char) -> bool) -> String {
+        let mut taken = String::new();
+        while let Some(c) = self.chars.next_if(|&c| predicate(c)) {
+            taken.push(c);
+        }
+        taken
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.chars.next();
+                Ok(())
+            }
+            _ => Err(eyre!("Expected `{expected}`.")),
+        }
+    }
+}
+
+fn deserialize_expression<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Expr, D::Error> {
+    let source = String::deserialize(deserializer)?;
+    Expr::parse(&source).map_err(serde::de::Error::custom)
+}
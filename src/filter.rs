@@ -0,0 +1,84 @@
+use std::fmt;
+
+use resol_vbus::Packet;
+use serde::{de::Error, Deserialize, Deserializer};
+
+/// Selects the packets measurements are decoded from.
+///
+/// Each criterion takes a number, a hex string like `"0x0100"` or `"any"`.
+/// Criteria left out keep their default, which selects the main data packet of a
+/// DeltaSol BX Plus (command `0x0100` sent to `0x0010`).
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct PacketFilter {
+    #[serde(deserialize_with = "deserialize_address")]
+    pub command: Option<u16>,
+    #[serde(deserialize_with = "deserialize_address")]
+    pub source_address: Option<u16>,
+    #[serde(deserialize_with = "deserialize_address")]
+    pub destination_address: Option<u16>,
+}
+
+impl Default for PacketFilter {
+    fn default() -> Self {
+        PacketFilter {
+            command: Some(0x0100),
+            source_address: None,
+            destination_address: Some(0x0010),
+        }
+    }
+}
+
+impl PacketFilter {
+    pub fn matches(&self, packet: &Packet) -> bool {
+        let criteria = [
+            (self.command, packet.command),
+            (self.source_address, packet.header.source_address),
+            (self.destination_address, packet.header.destination_address),
+        ];
+        criteria
+            .iter()
+            .all(|(expected, actual)| expected.is_none_or(|expected| expected == *actual))
+    }
+}
+
+impl fmt::Display for PacketFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let criterion = |value: Option<u16>| match value {
+            Some(value) => format!("0x{value:04X}"),
+            None => "any".to_owned(),
+        };
+        write!(
+            f,
+            "command {}, source {}, destination {}",
+            criterion(self.command),
+            criterion(self.source_address),
+            criterion(self.destination_address)
+        )
+    }
+}
+
+pub fn deserialize_address<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u16>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Address {
+        Number(u16),
+        Text(String),
+    }
+
+    match Address::deserialize(deserializer)? {
+        Address::Number(number) => Ok(Some(number)),
+        Address::Text(text) if text.eq_ignore_ascii_case("any") => Ok(None),
+        Address::Text(text) => {
+            let digits = text
+                .strip_prefix("0x")
+                .or_else(|| text.strip_prefix("0X"))
+                .unwrap_or(&text);
+            u16::from_str_radix(digits, 16)
+                .map(Some)
+                .map_err(|_| D::Error::custom(format!("invalid hex value `{text}`")))
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    sync::Mutex,
+};
+
+use color_eyre::Result;
+use resol_vbus::{
+    chrono::{DateTime, Utc},
+    Data, Datagram, Header, Specification,
+};
+use serde::Serialize;
+use tracing::info;
+
+use crate::{decode::unique_field_key, source::DataReader};
+
+/// A frame seen on the bus, as shown by `/debug/last-packet` and `--debug-packets`.
+#[derive(Serialize, Clone)]
+pub struct FrameInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    /// `packet`, `datagram` or `telegram`.
+    pub kind: &'static str,
+    pub timestamp: DateTime<Utc>,
+    pub channel: u8,
+    pub destination_address: String,
+    pub source_address: String,
+    pub protocol_version: String,
+    pub command: String,
+    /// Payload without the septett bytes, as hex.
+    pub payload: String,
+}
+
+impl FrameInfo {
+    pub fn new(data: &Data, device: Option<String>) -> Self {
+        let (kind, command, payload) = match data {
+            Data::Packet(packet) => {
+                let length = usize::from(packet.frame_count) * 4;
+                ("packet", packet.command, hex(&packet.frame_data[..length]))
+            }
+            Data::Datagram(Datagram {
+                command,
+                param16,
+                param32,
+                ..
+            }) => {
+                let mut bytes = param16.to_le_bytes().to_vec();
+                bytes.extend_from_slice(&param32.to_le_bytes());
+                ("datagram", *command, hex(&bytes))
+            }
+            Data::Telegram(telegram) => {
+                let length = usize::from(telegram.frame_count()) * 7;
+                (
+                    "telegram",
+                    u16::from(telegram.command),
+                    hex(&telegram.frame_data[..length]),
+                )
+            }
+        };
+        let header: &Header = data.as_header();
+        FrameInfo {
+            device,
+            kind,
+            timestamp: header.timestamp,
+            channel: header.channel,
+            destination_address: format!("0x{:04X}", header.destination_address),
+            source_address: format!("0x{:04X}", header.source_address),
+            protocol_version: format!("0x{:02X}", header.protocol_version),
+            command: format!("0x{command:04X}"),
+            payload,
+        }
+    }
+}
+
+impl fmt::Display for FrameInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} -> {} (protocol {}, command {}): {}",
+            self.kind,
+            self.source_address,
+            self.destination_address,
+            self.protocol_version,
+            self.command,
+            self.payload
+        )
+    }
+}
+
+/// A packet seen on the bus as the specification describes it, as shown by `/spec`.
+#[derive(Serialize, Clone)]
+pub struct PacketInfo {
+    pub packet_id: String,
+    pub name: String,
+    pub fields: Vec<FieldInfo>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct FieldInfo {
+    pub packet_field_id: String,
+    pub name: String,
+    pub unit: String,
+    /// Name of the field with `map_all_fields` unless mapped.
+    pub key: String,
+}
+
+impl PacketInfo {
+    pub fn new(spec: &Specification, header: &Header, command: u16) -> Self {
+        let packet_spec = spec.get_packet_spec(
+            header.channel,
+            header.destination_address,
+            header.source_address,
+            command,
+        );
+        let mut taken = BTreeSet::new();
+        PacketInfo {
+            packet_id: packet_spec.packet_id.clone(),
+            name: packet_spec.name.clone(),
+            fields: packet_spec
+                .fields
+                .iter()
+                .map(|field_spec| FieldInfo {
+                    packet_field_id: field_spec.packet_field_id.clone(),
+                    name: field_spec.name.clone(),
+                    unit: field_spec.unit_text.trim().to_owned(),
+                    key: unique_field_key(&field_spec.name, &mut taken),
+                })
+                .collect(),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02X}")).collect()
+}
+
+/// Remembers every frame read through it and logs it if requested. Packets are described in
+/// `packets` the first time one with their ID is read.
+pub struct FrameTap<'a> {
+    pub reader: &'a mut dyn DataReader,
+    pub last_frame: &'a Mutex<Option<FrameInfo>>,
+    pub spec: &'a Specification,
+    pub packets: &'a Mutex<BTreeMap<String, PacketInfo>>,
+    pub device: &'a Option<String>,
+    pub log: bool,
+}
+
+impl DataReader for FrameTap<'_> {
+    fn read_data(&mut self) -> Result<Option<Data>> {
+        let data = self.reader.read_data()?;
+        if let Some(data) = &data {
+            let frame = FrameInfo::new(data, self.device.clone());
+            if self.log {
+                info!(device = ?self.device, "Frame: {frame}");
+            }
+            if let Ok(mut last_frame) = self.last_frame.lock() {
+                *last_frame = Some(frame);
+            }
+            if let (Data::Packet(packet), Ok(mut packets)) = (data, self.packets.lock()) {
+                let header = &packet.header;
+                let packet_id = format!(
+                    "{:02X}_{:04X}_{:04X}_10_{:04X}",
+                    header.channel,
+                    header.destination_address,
+                    header.source_address,
+                    packet.command
+                );
+                packets
+                    .entry(packet_id)
+                    .or_insert_with(|| PacketInfo::new(self.spec, header, packet.command));
+            }
+        }
+        Ok(data)
+    }
+
+    fn send_datagram(&mut self, datagram: &Datagram) -> Result<()> {
+        self.reader.send_datagram(datagram)
+    }
+}
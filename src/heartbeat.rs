@@ -0,0 +1,45 @@
+use std::collections::BTreeMap;
+
+use resol_vbus::chrono::Utc;
+use serde::Deserialize;
+
+use crate::{stats::Stats, Measurements};
+
+/// A point written every `interval` seconds whatever the bus does, so alerts can tell a
+/// collector that stopped from a controller that was switched off.
+#[derive(Deserialize, Clone)]
+pub struct HeartbeatConfig {
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    #[serde(default = "default_measurement")]
+    pub measurement: String,
+}
+
+fn default_interval() -> u64 {
+    60
+}
+
+fn default_measurement() -> String {
+    "heartbeat".to_owned()
+}
+
+impl HeartbeatConfig {
+    /// `collector_alive` set to 1 and, once a packet was decoded, the seconds since then as
+    /// `last_packet_age_s`.
+    pub fn heartbeat(&self, stats: &Stats) -> Measurements {
+        let now = Utc::now();
+        let mut fields = BTreeMap::from([("collector_alive".to_owned(), 1.0)]);
+        if let Some(last_decoded) = Stats::time(&stats.last_decoded) {
+            let age = (now - last_decoded).num_milliseconds() as f64 / 1000.0;
+            fields.insert("last_packet_age_s".to_owned(), age);
+        }
+        Measurements {
+            time: now,
+            device: None,
+            measurement: Some(self.measurement.clone()),
+            fields,
+            text: None,
+            tags: BTreeMap::new(),
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use resol_vbus::chrono::{DateTime, Utc};
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::{counters::DeviceCounters, integration_seconds, units::Unit, Measurements};
+
+/// Computes thermal power and energy from a flow rate and a temperature difference.
+#[derive(Deserialize, Clone)]
+pub struct HeatConfig {
+    pub flow_rate_field: String,
+    /// Field holding the hot side (collector flow) temperature, in °C unless it has a `unit`.
+    pub flow_temperature_field: String,
+    /// Field holding the cold side (return) temperature, in °C unless it has a `unit`.
+    pub return_temperature_field: String,
+    #[serde(default)]
+    pub flow_rate_unit: FlowRateUnit,
+    /// Specific heat capacity of the heat transfer fluid in kJ/(kg·K).
+    #[serde(default = "default_specific_heat")]
+    pub specific_heat: f64,
+    /// Density of the heat transfer fluid in kg/l.
+    #[serde(default = "default_density")]
+    pub density: f64,
+    #[serde(default = "default_power_field")]
+    pub power_field: String,
+    #[serde(default = "default_energy_field")]
+    pub energy_field: String,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+pub enum FlowRateUnit {
+    #[default]
+    #[serde(rename = "l/h")]
+    LitersPerHour,
+    #[serde(rename = "l/min")]
+    LitersPerMinute,
+}
+
+fn default_specific_heat() -> f64 {
+    4.19
+}
+
+fn default_density() -> f64 {
+    1.0
+}
+
+fn default_power_field() -> String {
+    "heat_power_kw".to_owned()
+}
+
+fn default_energy_field() -> String {
+    "heat_energy_kwh".to_owned()
+}
+
+/// Integrates the thermal power of one source into its energy total.
+pub struct HeatMeter {
+    config: HeatConfig,
+    /// Units the input fields are converted to from metric, if any.
+    flow_rate_unit: Option<Unit>,
+    flow_temperature_unit: Option<Unit>,
+    return_temperature_unit: Option<Unit>,
+    energy: f64,
+    last_time: Option<DateTime<Utc>>,
+}
+
+impl HeatMeter {
+    /// `unit` tells the unit a field is converted to from metric, if any.
+    pub fn new(config: HeatConfig, unit: impl Fn(&str) -> Option<Unit>) -> Self {
+        HeatMeter {
+            flow_rate_unit: unit(&config.flow_rate_field),
+            flow_temperature_unit: unit(&config.flow_temperature_field),
+            return_temperature_unit: unit(&config.return_temperature_field),
+            config,
+            energy: 0.0,
+            last_time: None,
+        }
+    }
+
+    /// Continues with the energy of a previous run.
+    pub fn restore(&mut self, counters: &DeviceCounters) {
+        if let Some(&energy) = counters.get(&self.config.energy_field) {
+            self.energy = energy;
+        }
+    }
+
+    /// Adds the power and energy fields to the measurements, unless an input is missing
+    /// (e.g. dropped as implausible).
+    pub fn apply(&mut self, measurements: &mut Measurements) {
+        let field = |name: &str| measurements.fields.get(name).copied();
+        let (Some(flow_rate), Some(flow_temperature), Some(return_temperature)) = (
+            field(&self.config.flow_rate_field),
+            field(&self.config.flow_temperature_field),
+            field(&self.config.return_temperature_field),
+        ) else {
+            debug!("Skipping heat calculation, an input field is missing");
+            return;
+        };
+        // Calculated in metric units whatever the fields are written in
+        let metric =
+            |value: f64, unit: Option<Unit>| unit.map_or(value, |unit| unit.to_metric(value));
+        let delta_t = metric(flow_temperature, self.flow_temperature_unit)
+            - metric(return_temperature, self.return_temperature_unit);
+
+        let flow_rate_unit = match self.flow_rate_unit {
+            Some(Unit::GallonsPerMinute) => FlowRateUnit::LitersPerMinute,
+            Some(Unit::GallonsPerHour) => FlowRateUnit::LitersPerHour,
+            _ => self.config.flow_rate_unit,
+        };
+        let flow_rate = metric(flow_rate, self.flow_rate_unit);
+        let liters_per_second = match flow_rate_unit {
+            FlowRateUnit::LitersPerHour => flow_rate / 3600.0,
+            FlowRateUnit::LitersPerMinute => flow_rate / 60.0,
+        };
+        // kg/s * kJ/(kg·K) * K = kW, heat flowing back into the collector isn't counted
+        let power = (liters_per_second * self.config.density * self.config.specific_heat * delta_t)
+            .max(0.0);
+
+        if let Some(seconds) = self
+            .last_time
+            .and_then(|last_time| integration_seconds(last_time, measurements.time))
+        {
+            self.energy += power * seconds / 3600.0;
+        }
+        self.last_time = Some(measurements.time);
+
+        measurements
+            .fields
+            .insert(self.config.power_field.clone(), power);
+        measurements
+            .fields
+            .insert(self.config.energy_field.clone(), self.energy);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use resol_vbus::chrono::Duration;
+
+    use super::*;
+    use crate::MAX_GAP_SECONDS;
+
+    /// 360 l/h heated by 10 K, 4.19 kW with the default fluid.
+    const POWER: f64 = 4.19;
+
+    fn config() -> HeatConfig {
+        HeatConfig {
+            flow_rate_field: "flow_rate".to_owned(),
+            flow_temperature_field: "temperature_flow".to_owned(),
+            return_temperature_field: "temperature_return".to_owned(),
+            flow_rate_unit: FlowRateUnit::LitersPerHour,
+            specific_heat: default_specific_heat(),
+            density: default_density(),
+            power_field: default_power_field(),
+            energy_field: default_energy_field(),
+        }
+    }
+
+    fn measurements(time: DateTime<Utc>, inputs: [f64; 3]) -> Measurements {
+        let mut measurements = Measurements::empty();
+        measurements.time = time;
+        let [flow_rate, flow_temperature, return_temperature] = inputs;
+        measurements.fields.extend([
+            ("flow_rate".to_owned(), flow_rate),
+            ("temperature_flow".to_owned(), flow_temperature),
+            ("temperature_return".to_owned(), return_temperature),
+        ]);
+        measurements
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{actual} != {expected}");
+    }
+
+    #[test]
+    fn integrates_power_into_energy() {
+        let mut meter = HeatMeter::new(config(), |_| None);
+        let start = Utc::now();
+        let mut first = measurements(start, [360.0, 50.0, 40.0]);
+        meter.apply(&mut first);
+        assert_close(first.fields["heat_power_kw"], POWER);
+        assert_close(first.fields["heat_energy_kwh"], 0.0);
+
+        let mut second = measurements(start + Duration::seconds(60), [360.0, 50.0, 40.0]);
+        meter.apply(&mut second);
+        assert_close(second.fields["heat_energy_kwh"], POWER / 60.0);
+    }
+
+    #[test]
+    fn skips_gaps_and_reverse_flow() {
+        let mut meter = HeatMeter::new(config(), |_| None);
+        let start = Utc::now();
+        meter.apply(&mut measurements(start, [360.0, 50.0, 40.0]));
+        let after_gap = start + Duration::seconds(MAX_GAP_SECONDS as i64 + 1);
+        let mut measured = measurements(after_gap, [360.0, 30.0, 40.0]);
+        meter.apply(&mut measured);
+        assert_close(measured.fields["heat_power_kw"], 0.0);
+        assert_close(measured.fields["heat_energy_kwh"], 0.0);
+    }
+
+    #[test]
+    fn calculates_in_metric_units() {
+        let unit = |name: &str| match name {
+            "flow_rate" => Some(Unit::GallonsPerMinute),
+            "temperature_flow" | "temperature_return" => Some(Unit::Fahrenheit),
+            _ => None,
+        };
+        let mut meter = HeatMeter::new(config(), unit);
+        let gallons_per_minute = 6.0 / Unit::GallonsPerMinute.to_metric(1.0);
+        let mut measured = measurements(Utc::now(), [gallons_per_minute, 122.0, 104.0]);
+        meter.apply(&mut measured);
+        assert_close(measured.fields["heat_power_kw"], POWER);
+    }
+
+    #[test]
+    fn skips_missing_inputs() {
+        let mut meter = HeatMeter::new(config(), |_| None);
+        let mut measured = measurements(Utc::now(), [360.0, 50.0, 40.0]);
+        measured.fields.remove("temperature_return");
+        meter.apply(&mut measured);
+        assert!(!measured.fields.contains_key("heat_power_kw"));
+    }
+}
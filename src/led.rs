@@ -0,0 +1,73 @@
+use serde::Deserialize;
+
+/// LED on a GPIO pin showing the pipeline's health: lit while healthy, blinking slowly while
+/// writes to InfluxDB fail and fast while no packets arrive.
+#[derive(Deserialize, Clone)]
+pub struct StatusLedConfig {
+    /// BCM number of the pin, e.g. `17` for physical pin 11.
+    pub pin: u8,
+    /// Whether the LED lights up on a low level, i.e. is wired between the pin and 3.3 V.
+    #[serde(default)]
+    pub active_low: bool,
+}
+
+#[cfg(feature = "rppal")]
+pub use self::gpio::run_led;
+
+#[cfg(feature = "rppal")]
+mod gpio {
+    use std::{
+        sync::{atomic::Ordering, Arc},
+        time::Duration,
+    };
+
+    use color_eyre::Result;
+    use rppal::gpio::Gpio;
+    use tokio::{sync::watch, time};
+
+    use super::StatusLedConfig;
+    use crate::stats::Stats;
+
+    /// A fast blink toggles every tick, a slow one every `SLOW_BLINK_TICKS`.
+    const TICK: Duration = Duration::from_millis(125);
+    const SLOW_BLINK_TICKS: u32 = 8;
+
+    /// Drives the LED until shutdown, leaving it off.
+    pub async fn run_led(
+        config: StatusLedConfig,
+        stats: Arc<Stats>,
+        mut shutdown: watch::Receiver<bool>,
+    ) -> Result<()> {
+        let mut pin = Gpio::new()?.get(config.pin)?.into_output();
+        let mut interval = time::interval(TICK);
+        let mut ticks: u32 = 0;
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.changed() => break,
+            }
+            ticks = ticks.wrapping_add(1);
+            let influx_failing = stats.sinks().iter().any(|(name, sink_stats)| {
+                name == "influxdb" && sink_stats.write_failing.load(Ordering::Relaxed)
+            });
+            let lit = if !stats.packets_arriving() {
+                ticks % 2 == 0
+            } else if influx_failing {
+                ticks / SLOW_BLINK_TICKS % 2 == 0
+            } else {
+                true
+            };
+            if lit != config.active_low {
+                pin.set_high();
+            } else {
+                pin.set_low();
+            }
+        }
+        if config.active_low {
+            pin.set_high();
+        } else {
+            pin.set_low();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,355 @@
+//! Decodes RESOL VBus data and writes the measurements to InfluxDB and other sinks.
+
+mod aggregate;
+mod alerts;
+mod annotations;
+mod buffer;
+mod config;
+mod counters;
+mod decode;
+mod dedup;
+mod delta;
+mod expr;
+pub mod filter;
+mod frames;
+mod heartbeat;
+mod heat;
+mod led;
+mod mapping;
+mod modbus;
+pub mod parameters;
+mod pipeline;
+mod recorder;
+mod relays;
+mod routes;
+mod run;
+pub mod sink;
+pub mod source;
+mod spec_update;
+mod state_file;
+mod stats;
+mod systemd;
+mod telegram;
+mod totals;
+mod units;
+mod vbus_server;
+mod webserver;
+
+use std::{
+    collections::BTreeMap,
+    io,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use aggregate::Aggregator;
+use color_eyre::{eyre::eyre, Result};
+use influxdb::{Timestamp, WriteQuery};
+use resol_vbus::{
+    chrono::{DateTime, Utc},
+    Data, DataSet,
+};
+use run::{add_tags, calibrate, dispatch, drop_implausible, start_sinks, stop_sinks, Sinks};
+use serde::{Deserialize, Serialize};
+use sink::{line_protocol::Precision, SinkControl};
+use source::{ReplaySource, Source};
+use stats::Stats;
+use tokio::{task, time};
+use tracing::info;
+use tracing_subscriber::{fmt::writer::BoxMakeWriter, EnvFilter};
+
+pub use config::{load_config, Config, SharedConfig};
+pub use decode::{decode, load_specification, read_data, read_packet, DecodeState};
+pub use run::run;
+
+/// How often `import` logs its progress.
+const IMPORT_PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Gaps between measurements longer than this aren't integrated over, e.g. after an outage.
+const MAX_GAP_SECONDS: f64 = 300.0;
+
+/// Checks the configuration, the field mapping, the sources and InfluxDB one after another,
+/// printing the outcome of each check.
+pub async fn validate_config(config: &Config) -> Result<()> {
+    let mut failed = !report("Configuration", config.validate());
+    match load_specification(config) {
+        Ok(spec) => failed |= !report("Field mapping", config.check_fields(&spec)),
+        Err(err) => failed |= !report("Specification", Err(err)),
+    }
+    for device_source in config.sources().unwrap_or_default() {
+        let name = match &device_source.device {
+            Some(device) => format!("Source `{device}`"),
+            None => "Source".to_owned(),
+        };
+        failed |= !report(&name, device_source.source.check());
+    }
+    if config.db_url.is_some() {
+        let reachable = report("InfluxDB reachable", config.ping_influx().await);
+        failed |= !reachable;
+        if reachable {
+            failed |= !report("InfluxDB access", config.check_influx_access().await);
+        }
+    }
+    if failed {
+        return Err(eyre!("The configuration has problems, see above."));
+    }
+    println!("Configuration is valid.");
+    Ok(())
+}
+
+/// Prints the outcome of a check, returning whether it passed.
+fn report(name: &str, result: Result<()>) -> bool {
+    match result {
+        Ok(()) => {
+            println!("[ok]     {name}");
+            true
+        }
+        Err(err) => {
+            println!("[failed] {name}: {err}");
+            false
+        }
+    }
+}
+
+/// Prints the fields of all packets each source emits within `duration`, regardless of the
+/// packet filter, as a starting point for the `[[fields]]` mapping.
+pub fn list_fields(config: &Config, duration: Duration) -> Result<()> {
+    let spec = load_specification(config)?;
+    for device_source in config.sources()? {
+        if let Some(device) = &device_source.device {
+            println!("{device}:");
+        }
+        let mut data_reader = device_source
+            .source
+            .source()
+            .open(config.stall_timeout(), None)?;
+        // Later packets with the same ID replace earlier ones, so the values are current
+        let mut dataset = DataSet::new();
+        let deadline = Instant::now() + duration;
+        while Instant::now() < deadline {
+            match data_reader.read_data()? {
+                Some(data @ Data::Packet(_)) => dataset.add_data(data),
+                Some(_) => {}
+                None => break,
+            }
+        }
+        if dataset.as_data_slice().is_empty() {
+            println!("No packet received.");
+            continue;
+        }
+        println!("packet_id\tpacket_field_id\tname\tunit\tvalue");
+        for field in spec.fields_in_data_set(&dataset) {
+            println!(
+                "{}\t{}\t{}\t{}\t{}",
+                field.packet_spec().packet_id,
+                field.field_spec().packet_field_id,
+                field.field_spec().name,
+                field.field_spec().unit_text.trim(),
+                field.fmt_raw_value(false),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Writes the packets of a recording (e.g. made with `record_path` or downloaded from a
+/// datalogger) between `from` and `to` with their recorded timestamps, at most `rate` points
+/// per second so a busy database keeps up.
+pub async fn import(
+    config: &Config,
+    path: &Path,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    rate: Option<u32>,
+    dry_run: bool,
+) -> Result<()> {
+    let spec = load_specification(config)?;
+    let source = ReplaySource {
+        path: path.to_owned(),
+    };
+    let mut data_reader = source.open(config.stall_timeout(), None)?;
+    let stats = Arc::new(Stats::default());
+    let sinks = if dry_run {
+        (Vec::new(), Vec::new())
+    } else {
+        start_sinks(config, &stats, &Arc::new(SinkControl::default()), None)?
+    };
+    let mut state = DecodeState::default();
+    let mut heat_meter = config.heat_meter();
+    let mut aggregator = config.aggregator();
+    let mut deltas = config.deltas();
+    let mut limiter = rate.map(|rate| time::interval(Duration::from_secs(1) / rate.max(1)));
+    let mut imported: u64 = 0;
+    let mut last_progress = Instant::now();
+    info!("Importing `{}`.", path.display());
+    loop {
+        let read = task::block_in_place(|| {
+            read_data(data_reader.as_mut(), &spec, config, true, None, &mut state)
+        })?;
+        let Some(mut measurements) = read else {
+            break;
+        };
+        if from.is_some_and(|from| measurements.time < from) {
+            continue;
+        }
+        // Recordings are in chronological order
+        if to.is_some_and(|to| measurements.time >= to) {
+            break;
+        }
+        calibrate(&mut measurements, config);
+        drop_implausible(&mut measurements, config);
+        if let Some(heat_meter) = &mut heat_meter {
+            heat_meter.apply(&mut measurements);
+        }
+        expr::apply(&config.computed, &mut measurements);
+        let mut measurements = match &mut aggregator {
+            Some(aggregator) => match aggregator.push(measurements) {
+                Some(aggregated) => aggregated,
+                None => continue,
+            },
+            None => measurements,
+        };
+        if let Some(deltas) = &mut deltas {
+            deltas.apply(&mut measurements);
+        }
+        if let Some(limiter) = &mut limiter {
+            limiter.tick().await;
+        }
+        let time = measurements.time;
+        write_imported(measurements, config, dry_run, &sinks).await?;
+        imported += 1;
+        if last_progress.elapsed() >= IMPORT_PROGRESS_INTERVAL {
+            info!(imported, %time, "Importing");
+            last_progress = Instant::now();
+        }
+    }
+    // The last interval ends with the recording
+    let flushed = aggregator
+        .as_mut()
+        .map(Aggregator::flush)
+        .unwrap_or_default();
+    for mut measurements in flushed {
+        if let Some(deltas) = &mut deltas {
+            deltas.apply(&mut measurements);
+        }
+        write_imported(measurements, config, dry_run, &sinks).await?;
+        imported += 1;
+    }
+    stop_sinks(sinks).await?;
+    info!(imported, "Import finished.");
+    Ok(())
+}
+
+/// Prints imported measurements in a dry run, hands them to every sink otherwise.
+async fn write_imported(
+    mut measurements: Measurements,
+    config: &Config,
+    dry_run: bool,
+    sinks: &Sinks,
+) -> Result<()> {
+    if dry_run {
+        return dispatch(measurements, config, true, sinks);
+    }
+    add_tags(&mut measurements, config);
+    // Unlike live data nothing is dropped, reading waits for the sinks instead
+    for (name, sender) in &sinks.0 {
+        sender
+            .send(measurements.clone())
+            .await
+            .map_err(|_| eyre!("Sink `{name}` stopped."))?;
+    }
+    Ok(())
+}
+
+pub fn init_logging(config: &Config) -> Result<()> {
+    // The stdout sink needs stdout to itself
+    let writer = if config.stdout.is_some() {
+        BoxMakeWriter::new(io::stderr)
+    } else {
+        BoxMakeWriter::new(io::stdout)
+    };
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_new(&config.log_level)?)
+        .with_writer(writer);
+    if config.log_json {
+        subscriber.json().init();
+    } else {
+        subscriber.init();
+    }
+    Ok(())
+}
+
+/// Seconds between two measurements of a source to integrate a value over, `None` for gaps
+/// too long to tell what happened in between.
+fn integration_seconds(last_time: DateTime<Utc>, time: DateTime<Utc>) -> Option<f64> {
+    let seconds = (time - last_time).num_milliseconds() as f64 / 1000.0;
+    (seconds > 0.0 && seconds <= MAX_GAP_SECONDS).then_some(seconds)
+}
+
+/// Prometheus metric name and `sensor` label of a field. Numbered fields of the same kind
+/// share one metric, e.g. `temperature_01` becomes `vbus_temperature{sensor="01"}`.
+fn metric_name(field: &str) -> (String, Option<&str>) {
+    match field.rsplit_once('_') {
+        Some((kind, sensor)) if sensor.chars().all(|c| c.is_ascii_digit()) => {
+            (format!("vbus_{kind}"), Some(sensor))
+        }
+        _ => (format!("vbus_{field}"), None),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Measurements {
+    pub time: DateTime<Utc>,
+    /// Name of the controller the measurements come from, written as tag.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    /// Measurement to write to instead of the configured one, used for events.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub measurement: Option<String>,
+    #[serde(flatten)]
+    pub fields: BTreeMap<String, f64>,
+    /// Text of an annotation, written as string field `text`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    /// Tags besides `device`, used by annotations.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+}
+
+impl Measurements {
+    fn empty() -> Self {
+        Measurements {
+            time: Utc::now(),
+            device: None,
+            measurement: None,
+            fields: BTreeMap::new(),
+            text: None,
+            tags: BTreeMap::new(),
+        }
+    }
+
+    fn into_query(self, name: &str, precision: Precision) -> WriteQuery {
+        let name = self.measurement.as_deref().unwrap_or(name);
+        let timestamp = precision.timestamp(self.time) as u128;
+        let timestamp = match precision {
+            Precision::Seconds => Timestamp::Seconds(timestamp),
+            Precision::Milliseconds => Timestamp::Milliseconds(timestamp),
+            Precision::Microseconds => Timestamp::Microseconds(timestamp),
+            Precision::Nanoseconds => Timestamp::Nanoseconds(timestamp),
+        };
+        let mut query = WriteQuery::new(timestamp, name);
+        if let Some(device) = self.device {
+            query = query.add_tag("device", device);
+        }
+        for (tag, value) in self.tags {
+            query = query.add_tag(tag, value);
+        }
+        if let Some(text) = self.text {
+            query = query.add_field("text", text);
+        }
+        self.fields
+            .into_iter()
+            .fold(query, |query, (field, value)| query.add_field(field, value))
+    }
+}
@@ -0,0 +1,88 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use color_eyre::{eyre::eyre, Result};
+use figment::{
+    providers::{Format, Toml},
+    Figment,
+};
+use serde::{Deserialize, Serialize};
+use toml_edit::{value, ArrayOfTables, Document, Item, Table};
+
+use crate::{
+    config::{config_file, extract_config},
+    load_specification, Config,
+};
+
+/// A packet field mapped to a name, as edited on `/admin`.
+#[derive(Serialize, Deserialize)]
+pub struct FieldMapping {
+    pub packet_field_id: String,
+    pub name: String,
+}
+
+/// The `[[fields]]` entries with a `packet_field_id`.
+pub fn mapping(config: &Config) -> Vec<FieldMapping> {
+    config
+        .fields
+        .iter()
+        .filter_map(|field| {
+            Some(FieldMapping {
+                packet_field_id: field.packet_field_id.clone()?,
+                name: field.name.clone(),
+            })
+        })
+        .collect()
+}
+
+/// Replaces the mapped `[[fields]]` of the config file with `mapping`, keeping the other
+/// settings of fields that stay mapped, the entries without `packet_field_id` and comments.
+/// Fails without touching the file if the result isn't a valid config.
+pub fn save_mapping(config_path: &Path, mapping: &[FieldMapping]) -> Result<()> {
+    let path = config_file(config_path);
+    if path
+        .extension()
+        .is_some_and(|extension| extension != "toml")
+    {
+        return Err(eyre!("Only TOML config files can be edited."));
+    }
+    let mut document: Document = fs::read_to_string(&path)?.parse()?;
+    let mut names: BTreeMap<_, _> = mapping
+        .iter()
+        .map(|field| (field.packet_field_id.as_str(), field.name.as_str()))
+        .collect();
+
+    let mut fields = ArrayOfTables::new();
+    if let Some(existing) = document.get("fields").and_then(Item::as_array_of_tables) {
+        for table in existing.iter() {
+            let id = table.get("packet_field_id").and_then(Item::as_str);
+            match id {
+                Some(id) => {
+                    // Unmapped fields are dropped
+                    if let Some(name) = names.remove(id) {
+                        let mut table = table.clone();
+                        table["name"] = value(name);
+                        fields.push(table);
+                    }
+                }
+                None => fields.push(table.clone()),
+            }
+        }
+    }
+    // Newly mapped fields in the order they were given
+    for field in mapping {
+        if names.remove(field.packet_field_id.as_str()).is_some() {
+            let mut table = Table::new();
+            table["packet_field_id"] = value(field.packet_field_id.as_str());
+            table["name"] = value(field.name.as_str());
+            fields.push(table);
+        }
+    }
+    document["fields"] = Item::ArrayOfTables(fields);
+
+    let content = document.to_string();
+    let config = extract_config(Figment::new().merge(Toml::string(&content)))?;
+    config.validate()?;
+    config.check_fields(&load_specification(&config)?)?;
+    fs::write(&path, content)?;
+    Ok(())
+}
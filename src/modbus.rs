@@ -0,0 +1,154 @@
+use std::{collections::BTreeMap, net::SocketAddr, sync::Arc};
+
+use color_eyre::Result;
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{watch, Mutex},
+};
+use tracing::{debug, info, warn};
+
+use crate::Measurements;
+
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const READ_INPUT_REGISTERS: u8 = 0x04;
+
+const ILLEGAL_FUNCTION: u8 = 0x01;
+const ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+const ILLEGAL_DATA_VALUE: u8 = 0x03;
+const GATEWAY_TARGET_FAILED: u8 = 0x0B;
+
+/// Most registers a single read may ask for.
+const MAX_REGISTERS: u16 = 125;
+
+#[derive(Deserialize, Clone)]
+pub struct ModbusConfig {
+    #[serde(default = "default_address")]
+    pub address: SocketAddr,
+    #[serde(default = "default_unit_id")]
+    pub unit_id: u8,
+    /// Source whose values are served, the one without `device` if not set.
+    pub device: Option<String>,
+}
+
+fn default_address() -> SocketAddr {
+    ([0, 0, 0, 0], 502).into()
+}
+
+fn default_unit_id() -> u8 {
+    1
+}
+
+/// Serves the latest values as a read-only Modbus TCP slave. Each field takes two registers
+/// holding a big-endian IEEE 754 float, in the order of `fields`, NaN while it has no value.
+/// Both holding and input registers can be read.
+pub async fn run_server(
+    config: ModbusConfig,
+    fields: Vec<String>,
+    measurements: Arc<Mutex<BTreeMap<String, Measurements>>>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let listener = TcpListener::bind(config.address).await?;
+    let map: Vec<_> = fields
+        .iter()
+        .enumerate()
+        .map(|(index, name)| format!("{}={name}", index * 2))
+        .collect();
+    info!(
+        "Modbus server listening on {}, registers {}",
+        config.address,
+        map.join(", ")
+    );
+    let fields = Arc::new(fields);
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = shutdown.changed() => return Ok(()),
+        };
+        debug!(%peer, "Modbus client connected");
+        let config = config.clone();
+        let fields = Arc::clone(&fields);
+        let measurements = Arc::clone(&measurements);
+        tokio::spawn(async move {
+            if let Err(err) = serve(stream, &config, &fields, &measurements).await {
+                warn!(%peer, "Error while serving Modbus client: {err}");
+            }
+        });
+    }
+}
+
+/// Answers the requests of one client until it disconnects.
+async fn serve(
+    mut stream: TcpStream,
+    config: &ModbusConfig,
+    fields: &[String],
+    measurements: &Mutex<BTreeMap<String, Measurements>>,
+) -> Result<()> {
+    let mut header = [0; 7];
+    loop {
+        if stream.read_exact(&mut header).await.is_err() {
+            // Disconnected
+            return Ok(());
+        }
+        let [transaction_high, transaction_low, _, _, length_high, length_low, unit_id] = header;
+        // The length counts the unit ID too
+        let length = u16::from_be_bytes([length_high, length_low]).saturating_sub(1);
+        let mut pdu = vec![0; usize::from(length)];
+        stream.read_exact(&mut pdu).await?;
+
+        let response = if unit_id != config.unit_id {
+            exception(
+                pdu.first().copied().unwrap_or_default(),
+                GATEWAY_TARGET_FAILED,
+            )
+        } else {
+            let values = measurements
+                .lock()
+                .await
+                .get(config.device.as_deref().unwrap_or_default())
+                .map(|measurements| measurements.fields.clone())
+                .unwrap_or_default();
+            respond(&pdu, fields, &values)
+        };
+        let mut frame = vec![transaction_high, transaction_low, 0, 0];
+        frame.extend_from_slice(&u16::try_from(response.len() + 1)?.to_be_bytes());
+        frame.push(unit_id);
+        frame.extend_from_slice(&response);
+        stream.write_all(&frame).await?;
+    }
+}
+
+/// Builds the response PDU to a request PDU.
+fn respond(pdu: &[u8], fields: &[String], values: &BTreeMap<String, f64>) -> Vec<u8> {
+    let &[function, start_high, start_low, count_high, count_low] = pdu else {
+        return exception(pdu.first().copied().unwrap_or_default(), ILLEGAL_DATA_VALUE);
+    };
+    if function != READ_HOLDING_REGISTERS && function != READ_INPUT_REGISTERS {
+        return exception(function, ILLEGAL_FUNCTION);
+    }
+    let start = usize::from(u16::from_be_bytes([start_high, start_low]));
+    let count = u16::from_be_bytes([count_high, count_low]);
+    if count == 0 || count > MAX_REGISTERS {
+        return exception(function, ILLEGAL_DATA_VALUE);
+    }
+    let end = start + usize::from(count);
+    if end > fields.len() * 2 {
+        return exception(function, ILLEGAL_DATA_ADDRESS);
+    }
+
+    let registers: Vec<u8> = fields
+        .iter()
+        .flat_map(|name| {
+            let value = values.get(name).map_or(f32::NAN, |&value| value as f32);
+            value.to_be_bytes()
+        })
+        .collect();
+    let mut response = vec![function, (count * 2) as u8];
+    response.extend_from_slice(&registers[start * 2..end * 2]);
+    response
+}
+
+fn exception(function: u8, code: u8) -> Vec<u8> {
+    vec![function | 0x80, code]
+}
@@ -0,0 +1,167 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use color_eyre::Result;
+use resol_vbus::{chrono::Utc, Data, Datagram, Header};
+use serde::Deserialize;
+use tracing::{debug, warn};
+
+use crate::{filter::deserialize_address, source::DataReader, Measurements};
+
+/// Datagram commands of the VBus protocol used to read values.
+const COMMAND_ANSWER_VALUE: u16 = 0x0100;
+const COMMAND_GET_VALUE: u16 = 0x0300;
+const COMMAND_BUS_OFFERED: u16 = 0x0500;
+const COMMAND_RELEASE_BUS: u16 = 0x0600;
+
+/// Address requests are sent from, the one RESOL uses for PCs.
+pub const SELF_ADDRESS: u16 = 0x0020;
+
+/// Number of data items to wait for an answer before giving up on a request.
+const MAX_ANSWER_WAIT: usize = 20;
+
+/// A controller parameter (setpoint, operating hours, ...) read by its value index.
+#[derive(Deserialize, Clone)]
+pub struct ParameterConfig {
+    pub name: String,
+    /// Value index, as found in the controller's documentation or RESOL ServiceCenter.
+    pub index: i16,
+    #[serde(default)]
+    pub subindex: u8,
+    /// Controller to ask, defaults to the one offering the bus.
+    #[serde(default, deserialize_with = "deserialize_address")]
+    pub address: Option<u16>,
+    /// Factor the raw integer value is multiplied with.
+    #[serde(default = "default_factor")]
+    pub factor: f64,
+}
+
+fn default_factor() -> f64 {
+    1.0
+}
+
+/// Reads parameters whenever the controller offers the bus and the interval has passed.
+pub struct ParameterPoller {
+    parameters: Vec<ParameterConfig>,
+    interval: Duration,
+    last_poll: Option<Instant>,
+    /// Latest value of every parameter, added to each measurement.
+    values: BTreeMap<String, f64>,
+}
+
+impl ParameterPoller {
+    pub fn new(parameters: Vec<ParameterConfig>, interval: Duration) -> Self {
+        ParameterPoller {
+            parameters,
+            interval,
+            last_poll: None,
+            values: BTreeMap::new(),
+        }
+    }
+
+    /// Handles a datagram seen on the bus, polling the parameters if it offers the bus and
+    /// they are due.
+    pub fn handle(&mut self, datagram: &Datagram, reader: &mut dyn DataReader) -> Result<()> {
+        let due = self
+            .last_poll
+            .is_none_or(|last_poll| last_poll.elapsed() >= self.interval);
+        if datagram.command != COMMAND_BUS_OFFERED || !due {
+            return Ok(());
+        }
+        self.last_poll = Some(Instant::now());
+
+        let master = datagram.header.source_address;
+        for parameter in &self.parameters {
+            let address = parameter.address.unwrap_or(master);
+            match get_value(reader, address, parameter.index, parameter.subindex)? {
+                Some(value) => {
+                    let value = f64::from(value) * parameter.factor;
+                    debug!(parameter = %parameter.name, value, "Read parameter");
+                    self.values.insert(parameter.name.clone(), value);
+                }
+                None => {
+                    warn!(parameter = %parameter.name, "No answer to parameter request.");
+                    self.values.remove(&parameter.name);
+                }
+            }
+        }
+        // Hand the bus back, otherwise the controller stops sending data for a while
+        reader.send_datagram(&datagram_to(master, COMMAND_RELEASE_BUS, 0))?;
+        Ok(())
+    }
+
+    /// Adds the latest parameter values as fields.
+    pub fn apply(&self, measurements: &mut Measurements) {
+        for (name, value) in &self.values {
+            measurements.fields.insert(name.clone(), *value);
+        }
+    }
+}
+
+/// Asks a controller for a value, `None` if no answer arrived in time.
+fn get_value(
+    reader: &mut dyn DataReader,
+    address: u16,
+    index: i16,
+    subindex: u8,
+) -> Result<Option<i32>> {
+    let command = COMMAND_GET_VALUE | u16::from(subindex);
+    reader.send_datagram(&datagram_to(address, command, index))?;
+    for _ in 0..MAX_ANSWER_WAIT {
+        match reader.read_data()? {
+            Some(Data::Datagram(answer))
+                if answer.header.source_address == address
+                    && answer.header.destination_address == SELF_ADDRESS
+                    && answer.command == COMMAND_ANSWER_VALUE | u16::from(subindex)
+                    && answer.param16 == index =>
+            {
+                return Ok(Some(answer.param32));
+            }
+            Some(_) => {}
+            None => break,
+        }
+    }
+    Ok(None)
+}
+
+fn datagram_to(address: u16, command: u16, param16: i16) -> Datagram {
+    Datagram {
+        header: Header {
+            timestamp: Utc::now(),
+            channel: 0,
+            destination_address: address,
+            source_address: SELF_ADDRESS,
+            protocol_version: 0x20,
+        },
+        command,
+        param16,
+        param32: 0,
+    }
+}
+
+/// Encodes a datagram as it goes over the wire (VBus protocol version 2.0).
+pub fn encode_datagram(datagram: &Datagram) -> [u8; 16] {
+    let mut bytes = [0; 16];
+    bytes[0] = 0xAA;
+    bytes[1..3].copy_from_slice(&datagram.header.destination_address.to_le_bytes());
+    bytes[3..5].copy_from_slice(&datagram.header.source_address.to_le_bytes());
+    bytes[5] = 0x20;
+    bytes[6..8].copy_from_slice(&datagram.command.to_le_bytes());
+    bytes[8..10].copy_from_slice(&datagram.param16.to_le_bytes());
+    bytes[10..14].copy_from_slice(&datagram.param32.to_le_bytes());
+    // Only the sync byte may have the MSB set, the MSBs of the payload go into a septett byte
+    let mut septett = 0;
+    for (bit, byte) in bytes[8..14].iter_mut().enumerate() {
+        if *byte & 0x80 != 0 {
+            *byte &= 0x7F;
+            septett |= 1 << bit;
+        }
+    }
+    bytes[14] = septett;
+    bytes[15] = bytes[1..15].iter().fold(0x7F_u8, |checksum, byte| {
+        checksum.wrapping_sub(*byte) & 0x7F
+    });
+    bytes
+}
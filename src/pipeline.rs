@@ -0,0 +1,134 @@
+use color_eyre::Result;
+use tracing::{debug, warn};
+
+use crate::{
+    aggregate::Aggregator, alerts::Alerter, counters::Counters, dedup::Deduplicator, delta::Deltas,
+    totals::Totals, Config, Measurements,
+};
+
+/// The steps with state of their own the measurements take between the readers and the sinks.
+pub struct Pipeline {
+    pub aggregator: Option<Aggregator>,
+    pub deduplicator: Option<Deduplicator>,
+    pub deltas: Option<Deltas>,
+    pub alerter: Option<Alerter>,
+    pub totals: Option<Totals>,
+    pub counters: Option<Counters>,
+}
+
+impl Pipeline {
+    pub fn new(config: &Config) -> Result<Self> {
+        Ok(Pipeline {
+            aggregator: config.aggregator(),
+            deduplicator: config.dedup.clone().map(Deduplicator::new),
+            deltas: config.deltas(),
+            alerter: config.alerter(),
+            totals: config.totals.clone().map(Totals::load).transpose()?,
+            counters: config
+                .counters_path
+                .clone()
+                .map(Counters::load)
+                .transpose()?,
+        })
+    }
+
+    /// Switches to the steps of a reloaded config. Steps configured before and after keep
+    /// their state, e.g. open intervals, firing alerts and running totals. Returns the
+    /// measurements of the open intervals if the new config doesn't aggregate anymore.
+    pub fn reconfigure(&mut self, config: &Config) -> Result<Vec<Measurements>> {
+        // Steps that were removed don't lose anything, and the new ones load what they kept
+        self.save();
+        let mut new = Pipeline::new(config)?;
+        let flushed = if new.aggregator.is_none() {
+            self.flush()
+        } else {
+            Vec::new()
+        };
+        continue_from(
+            &mut new.aggregator,
+            self.aggregator.take(),
+            Aggregator::continue_from,
+        );
+        continue_from(
+            &mut new.deduplicator,
+            self.deduplicator.take(),
+            Deduplicator::continue_from,
+        );
+        continue_from(&mut new.deltas, self.deltas.take(), Deltas::continue_from);
+        continue_from(
+            &mut new.alerter,
+            self.alerter.take(),
+            Alerter::continue_from,
+        );
+        continue_from(&mut new.totals, self.totals.take(), Totals::continue_from);
+        continue_from(
+            &mut new.counters,
+            self.counters.take(),
+            Counters::continue_from,
+        );
+        *self = new;
+        Ok(flushed)
+    }
+
+    /// Runs aggregated measurements through the steps after the aggregation, `None` if they
+    /// are skipped.
+    pub fn after_aggregation(&mut self, mut measurements: Measurements) -> Option<Measurements> {
+        if let Some(deduplicator) = &mut self.deduplicator {
+            if !deduplicator.should_write(&measurements) {
+                debug!("Skipping unchanged measurements");
+                return None;
+            }
+        }
+        if let Some(deltas) = &mut self.deltas {
+            deltas.apply(&mut measurements);
+        }
+        Some(measurements)
+    }
+
+    /// Finishes all open intervals of the aggregation, e.g. before shutting down.
+    pub fn flush(&mut self) -> Vec<Measurements> {
+        let finished = self
+            .aggregator
+            .as_mut()
+            .map(Aggregator::flush)
+            .unwrap_or_default();
+        self.after_aggregation_all(finished)
+    }
+
+    /// Finishes the open intervals of devices that stopped sending.
+    pub fn flush_stale(&mut self) -> Vec<Measurements> {
+        let finished = self
+            .aggregator
+            .as_mut()
+            .map(Aggregator::flush_stale)
+            .unwrap_or_default();
+        self.after_aggregation_all(finished)
+    }
+
+    fn after_aggregation_all(&mut self, finished: Vec<Measurements>) -> Vec<Measurements> {
+        finished
+            .into_iter()
+            .filter_map(|measurements| self.after_aggregation(measurements))
+            .collect()
+    }
+
+    /// Saves the totals and counters, e.g. before shutting down.
+    pub fn save(&self) {
+        if let Some(totals) = &self.totals {
+            if let Err(err) = totals.save() {
+                warn!("Error while saving the totals: {err}");
+            }
+        }
+        if let Some(counters) = &self.counters {
+            if let Err(err) = counters.save() {
+                warn!("Error while saving the counters: {err}");
+            }
+        }
+    }
+}
+
+fn continue_from<T>(new: &mut Option<T>, old: Option<T>, take_over: fn(&mut T, T)) {
+    if let (Some(new), Some(old)) = (new, old) {
+        take_over(new, old);
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use resol_vbus::chrono::{DateTime, Utc};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::vbus_server::RawBytes;
+
+#[derive(Deserialize, Clone)]
+pub struct RecordConfig {
+    /// Directory the recordings are written to.
+    pub directory: PathBuf,
+    /// Size in bytes after which a new file is started.
+    #[serde(default = "default_max_file_size")]
+    pub max_file_size: u64,
+    /// Seconds after which a new file is started.
+    #[serde(default = "default_max_file_age")]
+    pub max_file_age: i64,
+}
+
+fn default_max_file_size() -> u64 {
+    10_000_000
+}
+
+fn default_max_file_age() -> i64 {
+    24 * 60 * 60
+}
+
+/// Writes raw bus bytes to rotating `.vbus` files and passes them on to the VBus/LAN server.
+///
+/// The files use the VBus recording format with one raw data record (type `0x88`) per chunk,
+/// which is what `LiveDataRecordingReader` and therefore the `replay` source read.
+pub struct Recorder {
+    /// Writes no files if not set.
+    config: Option<RecordConfig>,
+    device: Option<String>,
+    file: Option<File>,
+    file_size: u64,
+    file_started: DateTime<Utc>,
+    forward: Option<RawBytes>,
+}
+
+impl Recorder {
+    pub fn new(
+        config: Option<RecordConfig>,
+        device: Option<String>,
+        forward: Option<RawBytes>,
+    ) -> Self {
+        Recorder {
+            config,
+            device,
+            file: None,
+            file_size: 0,
+            file_started: Utc::now(),
+            forward,
+        }
+    }
+
+    /// A recorder with the same settings, for a source opened later on.
+    pub fn another(&self) -> Self {
+        Recorder::new(
+            self.config.clone(),
+            self.device.clone(),
+            self.forward.clone(),
+        )
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if let Some(forward) = &self.forward {
+            // Fails only while no client is connected
+            let _ = forward.send(Arc::from(bytes));
+        }
+        let Some(config) = &self.config else {
+            return Ok(());
+        };
+        let now = Utc::now();
+        let expired = self.file_size >= config.max_file_size
+            || (now - self.file_started).num_seconds() >= config.max_file_age;
+        if self.file.is_none() || expired {
+            self.rotate(now)?;
+        }
+
+        // Header: sync byte, type, length twice, timestamp, followed by channel and data
+        let length = u16::try_from(16 + bytes.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "chunk too large"))?;
+        let mut record = Vec::with_capacity(usize::from(length));
+        record.extend_from_slice(&[0xA5, 0x88]);
+        record.extend_from_slice(&length.to_le_bytes());
+        record.extend_from_slice(&length.to_le_bytes());
+        record.extend_from_slice(&now.timestamp_millis().to_le_bytes());
+        record.extend_from_slice(&0u16.to_le_bytes());
+        record.extend_from_slice(bytes);
+
+        if let Some(file) = &mut self.file {
+            file.write_all(&record)?;
+            self.file_size += record.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self, now: DateTime<Utc>) -> io::Result<()> {
+        let Some(config) = &self.config else {
+            return Ok(());
+        };
+        fs::create_dir_all(&config.directory)?;
+        let timestamp = now.format("%Y%m%d_%H%M%S");
+        let name = match &self.device {
+            Some(device) => format!("{device}_{timestamp}.vbus"),
+            None => format!("{timestamp}.vbus"),
+        };
+        let path = config.directory.join(name);
+        info!(path = %path.display(), "Starting new recording");
+        self.file = Some(File::create(path)?);
+        self.file_size = 0;
+        self.file_started = now;
+        Ok(())
+    }
+}
+
+/// Passes bytes through while recording them.
+pub struct Tee<R> {
+    pub inner: R,
+    pub recorder: Recorder,
+}
+
+impl<R: Read> Read for Tee<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        // Only the chunk size is limited by the record format, so split large reads
+        for chunk in buf[..len].chunks(u16::MAX as usize - 16) {
+            if let Err(err) = self.recorder.write(chunk) {
+                warn!("Error while recording: {err}");
+            }
+        }
+        Ok(len)
+    }
+}
@@ -0,0 +1,127 @@
+use std::collections::BTreeMap;
+
+use resol_vbus::chrono::{DateTime, Utc};
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::{counters::DeviceCounters, integration_seconds, Measurements};
+
+#[derive(Deserialize, Clone)]
+pub struct RelayConfig {
+    /// Relay fields to track, all fields starting with `relay_` if empty.
+    #[serde(default)]
+    pub fields: Vec<String>,
+    /// Measurement the on/off transitions are written to.
+    #[serde(default = "default_event_measurement")]
+    pub event_measurement: String,
+}
+
+fn default_event_measurement() -> String {
+    "relay_events".to_owned()
+}
+
+impl RelayConfig {
+    pub fn is_tracked(&self, field: &str) -> bool {
+        if self.fields.is_empty() {
+            field.starts_with("relay_")
+        } else {
+            self.fields.iter().any(|tracked| tracked == field)
+        }
+    }
+}
+
+/// The relay a `<relay>_runtime_h` field is the runtime of.
+pub fn runtime_relay(field: &str) -> Option<&str> {
+    field.strip_suffix("_runtime_h")
+}
+
+struct RelayState {
+    on: bool,
+    since: DateTime<Utc>,
+    last_time: DateTime<Utc>,
+}
+
+/// Tracks relays of one source, adding their runtime in hours as `<relay>_runtime_h` and
+/// reporting every switch as an event.
+pub struct RelayTracker {
+    config: RelayConfig,
+    states: BTreeMap<String, RelayState>,
+    runtimes: BTreeMap<String, f64>,
+}
+
+impl RelayTracker {
+    pub fn new(config: RelayConfig) -> Self {
+        RelayTracker {
+            config,
+            states: BTreeMap::new(),
+            runtimes: BTreeMap::new(),
+        }
+    }
+
+    /// Continues with the runtimes of a previous run.
+    pub fn restore(&mut self, counters: &DeviceCounters) {
+        for (name, runtime) in counters {
+            if let Some(relay) = runtime_relay(name) {
+                if self.config.is_tracked(relay) {
+                    self.runtimes.insert(relay.to_owned(), *runtime);
+                }
+            }
+        }
+    }
+
+    /// Adds the runtime fields to the measurements and returns an event for every relay that
+    /// switched since the last ones. A relay counts as on with any value above zero.
+    pub fn apply(&mut self, measurements: &mut Measurements) -> Vec<Measurements> {
+        let time = measurements.time;
+        let relays: Vec<_> = measurements
+            .fields
+            .iter()
+            .filter(|(name, _)| self.config.is_tracked(name))
+            .map(|(name, value)| (name.clone(), *value > 0.0))
+            .collect();
+
+        let mut events = Vec::new();
+        for (name, on) in relays {
+            let runtime = self.runtimes.entry(name.clone()).or_default();
+            match self.states.get_mut(&name) {
+                Some(state) => {
+                    if let Some(seconds) = integration_seconds(state.last_time, time) {
+                        if state.on {
+                            *runtime += seconds / 3600.0;
+                        }
+                    }
+                    if state.on != on {
+                        let duration = (time - state.since).num_milliseconds() as f64 / 1000.0;
+                        debug!(relay = %name, on, "Relay switched");
+                        events.push(Measurements {
+                            time,
+                            device: measurements.device.clone(),
+                            measurement: Some(self.config.event_measurement.clone()),
+                            fields: BTreeMap::from([
+                                (name.clone(), f64::from(u8::from(on))),
+                                (format!("{name}_previous_duration_s"), duration),
+                            ]),
+                            text: None,
+                            tags: BTreeMap::new(),
+                        });
+                        state.on = on;
+                        state.since = time;
+                    }
+                    state.last_time = time;
+                }
+                None => {
+                    let state = RelayState {
+                        on,
+                        since: time,
+                        last_time: time,
+                    };
+                    self.states.insert(name.clone(), state);
+                }
+            }
+            measurements
+                .fields
+                .insert(format!("{name}_runtime_h"), *runtime);
+        }
+        events
+    }
+}
@@ -0,0 +1,72 @@
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+use crate::Measurements;
+
+/// Sends fields to their own measurement and/or bucket instead of `db_measurement` in the
+/// configured database.
+#[derive(Deserialize, Clone)]
+pub struct RouteConfig {
+    /// Field names, a trailing `*` matches any field starting with the rest.
+    pub fields: Vec<String>,
+    /// Measurement the fields are written to, `db_measurement` if not set.
+    pub measurement: Option<String>,
+    /// Bucket the fields are written to, with `db_version` 1 the database, optionally
+    /// followed by `/<retention policy>`.
+    pub bucket: Option<String>,
+}
+
+impl RouteConfig {
+    pub fn matches(&self, field: &str) -> bool {
+        self.fields
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => field.starts_with(prefix),
+                None => pattern == field,
+            })
+    }
+}
+
+/// Tags of single fields by field name.
+pub type FieldTags = BTreeMap<String, BTreeMap<String, String>>;
+
+/// Splits measurements into one point per route and set of field tags, the first matching
+/// route of each field wins. Fields without a route stay in a point without measurement and
+/// bucket, events are passed on as they are.
+pub fn split<'a>(
+    routes: &'a [RouteConfig],
+    field_tags: &FieldTags,
+    measurements: &Measurements,
+) -> Vec<(Option<&'a str>, Measurements)> {
+    if (routes.is_empty() && field_tags.is_empty()) || measurements.measurement.is_some() {
+        return vec![(None, measurements.clone())];
+    }
+    let mut parts: BTreeMap<_, BTreeMap<String, f64>> = BTreeMap::new();
+    for (name, value) in &measurements.fields {
+        let route = routes.iter().position(|route| route.matches(name));
+        parts
+            .entry((route, field_tags.get(name)))
+            .or_default()
+            .insert(name.clone(), *value);
+    }
+    parts
+        .into_iter()
+        .map(|((route, extra_tags), fields)| {
+            let route = route.map(|index| &routes[index]);
+            let mut tags = measurements.tags.clone();
+            if let Some(extra_tags) = extra_tags {
+                tags.extend(extra_tags.clone());
+            }
+            let part = Measurements {
+                time: measurements.time,
+                device: measurements.device.clone(),
+                measurement: route.and_then(|route| route.measurement.clone()),
+                fields,
+                text: None,
+                tags,
+            };
+            (route.and_then(|route| route.bucket.as_deref()), part)
+        })
+        .collect()
+}
@@ -0,0 +1,778 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    iter, mem,
+    path::Path,
+    sync::{atomic::Ordering, Arc},
+    thread,
+    time::{Duration, Instant},
+};
+
+use color_eyre::{eyre::eyre, Report, Result};
+use futures_util::FutureExt;
+use resol_vbus::{chrono::Utc, DataSet};
+use sd_notify::NotifyState;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{
+        broadcast,
+        mpsc::{self, error::TrySendError},
+        watch, Mutex, Notify,
+    },
+    task::JoinHandle,
+    time,
+};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    annotations::FaultTracker,
+    config::{load_config, Config, DryRunFormat, SharedConfig, TimestampSource},
+    counters::Counters,
+    decode::{decode, load_specification, read_packet, DecodeState},
+    expr,
+    frames::FrameTap,
+    led, modbus,
+    pipeline::Pipeline,
+    relays::RelayTracker,
+    routes,
+    sink::{
+        line_protocol::{self, Precision},
+        SinkControl,
+    },
+    source::{DataReader, DeviceSource},
+    spec_update::{self, SpecUpdater},
+    stats::Stats,
+    systemd::{self, Watchdog},
+    telegram,
+    vbus_server::{self, RawBytes, RAW_QUEUE_SIZE},
+    webserver::{self, AppState},
+    Measurements,
+};
+
+/// Number of measurements queued between the readers and the writer. Live sources drop new
+/// ones when it is full rather than stop reading, which would overrun the UART's buffer.
+const READER_QUEUE_SIZE: usize = 64;
+
+/// Number of measurements queued for each `/events` client before it misses some.
+const UPDATES_QUEUE_SIZE: usize = 16;
+
+/// Number of measurements queued per sink before new ones are dropped.
+const SINK_QUEUE_SIZE: usize = 1024;
+
+/// Delay before the first attempt to reopen a failed source, doubled on every failure.
+const MIN_RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How often intervals of devices that stopped sending are checked for.
+const STALE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Queues of the running sinks and the tasks feeding them.
+pub(crate) type Sinks = (
+    Vec<(String, mpsc::Sender<Measurements>)>,
+    Vec<JoinHandle<Result<()>>>,
+);
+
+/// Starts every sink on its own task with its own queue, stopping the `previous` ones in
+/// the background if it succeeds. The new sinks take measurements right away, but only start
+/// writing once the previous ones are done with their buffer files.
+pub(crate) fn start_sinks(
+    config: &Config,
+    stats: &Arc<Stats>,
+    control: &Arc<SinkControl>,
+    previous: Option<&mut Sinks>,
+) -> Result<Sinks> {
+    let runners = config.sinks()?;
+    let previous = previous.map(mem::take).unwrap_or_default();
+    let previous_stopped = tokio::spawn(async move {
+        if let Err(err) = stop_sinks(previous).await {
+            error!("Error while stopping the replaced sinks: {err}");
+        }
+    })
+    .map(|_| ())
+    .shared();
+    let mut sinks = Vec::new();
+    let mut sink_tasks = Vec::new();
+    for runner in runners {
+        let (sender, receiver) = mpsc::channel(SINK_QUEUE_SIZE);
+        sinks.push((runner.sink.name().to_owned(), sender));
+        let previous_stopped = previous_stopped.clone();
+        let stats = Arc::clone(stats);
+        let control = Arc::clone(control);
+        sink_tasks.push(tokio::spawn(async move {
+            previous_stopped.await;
+            runner.run(receiver, stats, control).await
+        }));
+    }
+    Ok((sinks, sink_tasks))
+}
+
+/// Closes the queues, which makes the sinks flush what they still have and stop. Every sink
+/// gets to finish, even if others failed.
+pub(crate) async fn stop_sinks((sinks, sink_tasks): Sinks) -> Result<()> {
+    let names: Vec<_> = sinks.into_iter().map(|(name, _)| name).collect();
+    let mut failed = Vec::new();
+    for (name, sink_task) in names.into_iter().zip(sink_tasks) {
+        if let Err(err) = sink_task
+            .await
+            .map_err(Report::from)
+            .and_then(|result| result)
+        {
+            error!(sink = %name, "Sink failed: {err}");
+            failed.push(name);
+        }
+    }
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(eyre!("Sinks failed: {}", failed.join(", ")))
+    }
+}
+
+/// Collects measurements until all sources are exhausted or a shutdown is requested.
+///
+/// Sources are read on threads of their own, feeding this task through a bounded queue. It
+/// only hands the measurements on to the queues of the sinks, so slow writes never hold up
+/// reading.
+///
+/// On `SIGHUP` the config file is read again. Field mappings, plausibility ranges, the packet
+/// filter and sinks follow the new config, while sources keep running as they are.
+///
+/// With `dry_run` (or the `dry_run` key) measurements are printed instead of written, with
+/// `debug_packets` (or the `debug_packets` key) every frame is logged.
+pub async fn run(
+    shared_config: SharedConfig,
+    config_path: &Path,
+    dry_run: bool,
+    debug_packets: bool,
+) -> Result<()> {
+    let config = shared_config.get();
+    let dry_run = dry_run || config.dry_run;
+    let debug_packets = debug_packets || config.debug_packets;
+    let measurements = Arc::new(Mutex::new(BTreeMap::new()));
+    let history = Arc::new(Mutex::new(VecDeque::with_capacity(config.history_size)));
+    let stats = Arc::new(Stats::default());
+    let control = Arc::new(SinkControl::default());
+    let (updates, _) = broadcast::channel(UPDATES_QUEUE_SIZE);
+
+    let (shutdown_sender, mut shutdown) = watch::channel(false);
+    tokio::spawn(async move {
+        match shutdown_signal().await {
+            Ok(()) => {
+                info!("Shutting down, send the signal again to exit immediately.");
+                let _ = shutdown_sender.send(true);
+            }
+            Err(err) => error!("Error while installing signal handlers: {err}"),
+        }
+        if shutdown_signal().await.is_ok() {
+            std::process::exit(1);
+        }
+    });
+    // Saving the field mapping on `/admin` reloads the config just like SIGHUP
+    let reload = Arc::new(Notify::new());
+    let mut hangup = signal(SignalKind::hangup())?;
+    let hangup_reload = Arc::clone(&reload);
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            hangup_reload.notify_one();
+        }
+    });
+
+    let timezone = config.display_timezone()?;
+    let webserver = config.webserver_address.is_some().then(|| {
+        tokio::spawn(webserver::run_webserver(
+            Arc::clone(&config),
+            AppState {
+                measurements: Arc::clone(&measurements),
+                history: Arc::clone(&history),
+                stats: Arc::clone(&stats),
+                control: Arc::clone(&control),
+                updates: updates.clone(),
+                shutdown: shutdown.clone(),
+                timezone,
+                config: shared_config.clone(),
+                config_path: config_path.to_owned(),
+                reload: Arc::clone(&reload),
+            },
+            shutdown.clone(),
+        ))
+    });
+
+    if let Some(telegram) = config.telegram.clone() {
+        tokio::spawn(telegram::run_bot(
+            telegram,
+            Arc::clone(&measurements),
+            shutdown.clone(),
+        ));
+    }
+
+    #[cfg(feature = "rppal")]
+    if let Some(status_led) = config.status_led.clone() {
+        let led = led::run_led(status_led, Arc::clone(&stats), shutdown.clone());
+        tokio::spawn(async move {
+            if let Err(err) = led.await {
+                error!("Error while driving the status LED: {err}");
+            }
+        });
+    }
+
+    if let Some(modbus) = config.modbus.clone() {
+        let server = modbus::run_server(
+            modbus,
+            config.field_names(),
+            Arc::clone(&measurements),
+            shutdown.clone(),
+        );
+        tokio::spawn(async move {
+            if let Err(err) = server.await {
+                error!("Error in the Modbus server: {err}");
+            }
+        });
+    }
+
+    let raw = config.vbus_server.clone().map(|vbus_server| {
+        let (raw, _) = broadcast::channel(RAW_QUEUE_SIZE);
+        let server = vbus_server::run_server(vbus_server.clone(), raw.clone(), shutdown.clone());
+        tokio::spawn(async move {
+            if let Err(err) = server.await {
+                error!("Error in the VBus/LAN server: {err}");
+            }
+        });
+        (vbus_server.device, raw)
+    });
+
+    let mut spec_updater = SpecUpdater::start(config.spec_update.clone(), shutdown.clone())?;
+
+    if !dry_run && config.db_create_bucket {
+        config.create_buckets().await?;
+    }
+
+    let mut sinks = if dry_run {
+        (Vec::new(), Vec::new())
+    } else {
+        start_sinks(&config, &stats, &control, None)?
+    };
+
+    // Read data from every configured source on its own thread, as reading blocks
+    let (sender, mut receiver) = mpsc::channel(READER_QUEUE_SIZE);
+    let mut readers = Vec::new();
+    for device_source in config.sources()? {
+        let forward = raw
+            .as_ref()
+            .filter(|(device, _)| *device == device_source.device)
+            .map(|(_, raw)| raw.clone());
+        let data_reader = device_source.source.source().open(
+            config.stall_timeout(),
+            config.recorder(&device_source, forward.clone()),
+        )?;
+        let shared_config = shared_config.clone();
+        let stats = Arc::clone(&stats);
+        let sender = sender.clone();
+        let name = match &device_source.device {
+            Some(device) => format!("reader-{device}"),
+            None => "reader".to_owned(),
+        };
+        readers.push(thread::Builder::new().name(name).spawn(move || {
+            run_reader(
+                data_reader,
+                &device_source,
+                &shared_config,
+                &stats,
+                sender,
+                forward,
+                debug_packets,
+            )
+        })?);
+    }
+    if let Some(annotations) = &config.annotations {
+        let text = format!("vbus2influx {} started", env!("CARGO_PKG_VERSION"));
+        let _ = sender.try_send(annotations.annotation(None, "start", text));
+    }
+    drop(sender);
+
+    // Sources are open at this point, only InfluxDB is left to check before being ready
+    if !dry_run && config.db_url.is_some() {
+        if let Err(err) = config.ping_influx().await {
+            warn!("InfluxDB isn't reachable, buffering until it is: {err}");
+        }
+    }
+    systemd::notify(NotifyState::Ready);
+    let mut watchdog = Watchdog::from_env();
+    let mut pipeline = Pipeline::new(&config)?;
+    let mut heartbeat_timer = heartbeat_interval(&config);
+    let mut stale_timer = time::interval(STALE_CHECK_INTERVAL);
+    stale_timer.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+
+    loop {
+        let current_measurements = tokio::select! {
+            current_measurements = receiver.recv() => match current_measurements {
+                Some(current_measurements) => current_measurements,
+                None => break,
+            },
+            _ = reload.notified() => {
+                systemd::notify(NotifyState::Reloading);
+                let reloaded = reload_config(
+                    &shared_config,
+                    config_path,
+                    &mut sinks,
+                    &stats,
+                    &control,
+                    dry_run,
+                );
+                if reloaded {
+                    let config = shared_config.get();
+                    heartbeat_timer = heartbeat_interval(&config);
+                    match pipeline.reconfigure(&config) {
+                        Ok(flushed) => {
+                            for aggregated in flushed {
+                                dispatch(aggregated, &config, dry_run, &sinks)?;
+                            }
+                        }
+                        Err(err) => {
+                            error!("Error while applying the reloaded configuration: {err}");
+                        }
+                    }
+                    if let Err(err) = spec_updater.reconfigure(config.spec_update.clone()) {
+                        error!("Error while applying the reloaded `[spec_update]`: {err}");
+                    }
+                }
+                systemd::notify(NotifyState::Ready);
+                continue;
+            }
+            _ = heartbeat_timer.tick(), if shared_config.get().heartbeat.is_some() => {
+                let config = shared_config.get();
+                if let Some(heartbeat) = &config.heartbeat {
+                    dispatch(heartbeat.heartbeat(&stats), &config, dry_run, &sinks)?;
+                }
+                continue;
+            }
+            _ = stale_timer.tick(), if pipeline.aggregator.is_some() => {
+                let config = shared_config.get();
+                for aggregated in pipeline.flush_stale() {
+                    dispatch(aggregated, &config, dry_run, &sinks)?;
+                }
+                continue;
+            }
+            _ = shutdown.changed() => break,
+        };
+        let config = shared_config.get();
+        debug!(measurements = ?current_measurements, "Received measurements");
+        if let Some(watchdog) = &mut watchdog {
+            watchdog.ping();
+        }
+        // Events of a separate measurement only go to the sinks
+        if current_measurements.measurement.is_none() {
+            stats.packets_decoded.fetch_add(1, Ordering::Relaxed);
+            Stats::touch(&stats.last_decoded);
+            measurements.lock().await.insert(
+                current_measurements.device.clone().unwrap_or_default(),
+                current_measurements.clone(),
+            );
+            let mut history = history.lock().await;
+            while history.len() >= config.history_size.max(1) {
+                history.pop_front();
+            }
+            if config.history_size > 0 {
+                history.push_back(current_measurements.clone());
+            }
+            // Fails only while nobody listens
+            let _ = updates.send(current_measurements.clone());
+            if let Some(alerter) = &mut pipeline.alerter {
+                for event in alerter.check(&current_measurements) {
+                    dispatch(event, &config, dry_run, &sinks)?;
+                }
+            }
+            if let Some(finished) = pipeline
+                .totals
+                .as_mut()
+                .and_then(|totals| totals.update(&current_measurements))
+            {
+                dispatch(finished, &config, dry_run, &sinks)?;
+            }
+            if let Some(counters) = &mut pipeline.counters {
+                counters.update(&current_measurements, |field| config.is_counter(field));
+            }
+        }
+        let current_measurements = match &mut pipeline.aggregator {
+            Some(aggregator) => match aggregator.push(current_measurements) {
+                Some(aggregated) => aggregated,
+                None => continue,
+            },
+            None => current_measurements,
+        };
+        if let Some(current_measurements) = pipeline.after_aggregation(current_measurements) {
+            dispatch(current_measurements, &config, dry_run, &sinks)?;
+        }
+    }
+
+    systemd::notify(NotifyState::Stopping);
+    let config = shared_config.get();
+    for aggregated in pipeline.flush() {
+        dispatch(aggregated, &config, dry_run, &sinks)?;
+    }
+    pipeline.save();
+    stop_sinks(sinks).await?;
+
+    // Let the webserver finish requests in flight
+    if let Some(webserver) = webserver {
+        if *shutdown.borrow() {
+            let _ = webserver.await;
+        }
+    }
+
+    // Readers still blocked on their source are left behind, but errors of finished ones
+    // are reported
+    for reader in readers {
+        if reader.is_finished() {
+            reader
+                .join()
+                .map_err(|_| eyre!("Reader thread panicked."))??;
+        }
+    }
+    Ok(())
+}
+
+/// Adds the static tags, then prints the measurements in a dry run or hands them to every
+/// sink otherwise.
+pub(crate) fn dispatch(
+    mut measurements: Measurements,
+    config: &Config,
+    dry_run: bool,
+    sinks: &Sinks,
+) -> Result<()> {
+    add_tags(&mut measurements, config);
+    if dry_run {
+        match config.dry_run_format {
+            DryRunFormat::Json => {
+                println!("{}", serde_json::to_string(&measurements)?);
+            }
+            DryRunFormat::LineProtocol => {
+                let precision = Precision::parse(&config.db_precision)?;
+                let field_tags = config.field_tags();
+                for (_, part) in routes::split(&config.routes, &field_tags, &measurements) {
+                    let line = line_protocol::line(&part, &config.db_measurement, precision);
+                    println!("{line}");
+                }
+            }
+        }
+    }
+    for (name, sender) in &sinks.0 {
+        if sender.try_send(measurements.clone()).is_err() {
+            warn!(sink = %name, "Sink is lagging behind, dropping measurements.");
+        }
+    }
+    Ok(())
+}
+
+/// Adds the static tags, below any tags of the same name the measurements already have.
+pub(crate) fn add_tags(measurements: &mut Measurements, config: &Config) {
+    for (tag, value) in &config.tags {
+        measurements
+            .tags
+            .entry(tag.clone())
+            .or_insert_with(|| value.clone());
+    }
+}
+
+/// Ticks at the interval of `[heartbeat]`, starting at once.
+fn heartbeat_interval(config: &Config) -> time::Interval {
+    let interval = config
+        .heartbeat
+        .as_ref()
+        .map_or(60, |heartbeat| heartbeat.interval);
+    let mut timer = time::interval(Duration::from_secs(interval.max(1)));
+    timer.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+    timer
+}
+
+/// Reads the config file again and restarts the sinks with it, keeping the old config if the
+/// new one is invalid. Returns whether the new config was applied.
+///
+/// The old sinks flush what they still have in the background, so measurements keep being
+/// read meanwhile.
+fn reload_config(
+    shared_config: &SharedConfig,
+    config_path: &Path,
+    sinks: &mut Sinks,
+    stats: &Arc<Stats>,
+    control: &Arc<SinkControl>,
+    dry_run: bool,
+) -> bool {
+    info!("Reloading configuration from `{}`.", config_path.display());
+    let config = match load_config(config_path).and_then(|config| {
+        config.validate()?;
+        Ok(config)
+    }) {
+        Ok(config) => Arc::new(config),
+        Err(err) => {
+            error!("Keeping the old configuration, the new one is invalid: {err}");
+            return false;
+        }
+    };
+    if !dry_run {
+        match start_sinks(&config, stats, control, Some(sinks)) {
+            Ok(new_sinks) => *sinks = new_sinks,
+            Err(err) => {
+                error!("Keeping the old configuration, starting its sinks failed: {err}");
+                return false;
+            }
+        }
+    }
+    shared_config.set(config);
+    info!(
+        "Configuration reloaded, changes to sources, logging, the webserver, heat and record \
+         settings take effect after a restart."
+    );
+    true
+}
+
+/// Decodes measurements from a single source and hands them to the writer.
+fn run_reader(
+    mut data_reader: Box<dyn DataReader + Send>,
+    device_source: &DeviceSource,
+    shared_config: &SharedConfig,
+    stats: &Stats,
+    sender: mpsc::Sender<Measurements>,
+    forward: Option<RawBytes>,
+    debug_packets: bool,
+) -> Result<()> {
+    // The specification isn't `Send`, so each reader decodes its own copy
+    let mut spec_version = spec_update::latest();
+    let mut spec = load_specification(&shared_config.get())?;
+    let device = &device_source.device;
+    let source = device_source.source.source();
+    let data_timestamps = match shared_config.get().timestamps {
+        TimestampSource::Auto => source.has_timestamps(),
+        TimestampSource::Data => true,
+        TimestampSource::Now => false,
+    };
+    let mut heat_meter = shared_config.get().heat_meter();
+    let mut relay_tracker = shared_config.get().relays.clone().map(RelayTracker::new);
+    if let Some(path) = &shared_config.get().counters_path {
+        let counters = Counters::saved(path, device.as_deref().unwrap_or_default());
+        if let Some(heat_meter) = &mut heat_meter {
+            heat_meter.restore(&counters);
+        }
+        if let Some(relay_tracker) = &mut relay_tracker {
+            relay_tracker.restore(&counters);
+        }
+    }
+    // Requests can only be sent to a live bus
+    let mut parameters = if source.is_live() {
+        shared_config.get().parameter_poller()
+    } else {
+        None
+    };
+    let mut fault_tracker = FaultTracker::default();
+    let mut missing_fields = BTreeSet::new();
+    let mut state = DecodeState::default();
+    let mut stale = false;
+    let mut backoff = MIN_RECONNECT_BACKOFF;
+    loop {
+        // Picks up a reloaded config and a downloaded specification with the next packet
+        let config = shared_config.get();
+        if !spec_update::is_latest(&spec_version) {
+            spec_version = spec_update::latest();
+            spec = load_specification(&config)?;
+            // Field positions and packet descriptions may have changed with it
+            state.packet_ids.clear();
+            stats.packets.lock().unwrap().clear();
+        }
+        let mut tap = FrameTap {
+            reader: data_reader.as_mut(),
+            last_frame: &stats.last_frame,
+            spec: &spec,
+            packets: &stats.packets,
+            device,
+            log: debug_packets,
+        };
+        let result =
+            read_packet(&mut tap, &config, parameters.as_mut(), &mut state).and_then(|read| {
+                read.then(|| {
+                    let age = packet_age(&state.dataset);
+                    let started = Instant::now();
+                    let decoded = decode(
+                        &mut state,
+                        &spec,
+                        &config,
+                        data_timestamps,
+                        parameters.as_ref(),
+                    );
+                    stats.decode_duration.observe(started.elapsed());
+                    decoded.map(|decoded| (decoded, age))
+                })
+                .transpose()
+            });
+        let (mut current_measurements, age) = match result {
+            Ok(Some(decoded)) => decoded,
+            Ok(None) => break,
+            Err(err) if source.is_live() => {
+                stats.read_errors.fetch_add(1, Ordering::Relaxed);
+                if data_reader.invalid_frames_exceeded() {
+                    stats.reader_restarts.fetch_add(1, Ordering::Relaxed);
+                    if let Err(err) = source.power_cycle() {
+                        warn!(?device, "Error while power cycling the adapter: {err}");
+                    }
+                }
+                warn!(
+                    ?device,
+                    "Error while reading, reconnecting in {backoff:?}: {err}"
+                );
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+                let recorder = config.recorder(device_source, forward.clone());
+                match source.open(config.stall_timeout(), recorder) {
+                    Ok(reader) => {
+                        data_reader = reader;
+                        stats.reconnects.fetch_add(1, Ordering::Relaxed);
+                        if let Some(annotations) = &config.annotations {
+                            let text = format!("Reconnected after error: {err}");
+                            let annotation =
+                                annotations.annotation(device.clone(), "reconnect", text);
+                            if !send(&sender, annotation, true, stats) {
+                                return Ok(());
+                            }
+                        }
+                    }
+                    Err(err) => warn!(?device, "Error while reconnecting: {err}"),
+                }
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        backoff = MIN_RECONNECT_BACKOFF;
+        if let Some(max_age) = config.max_data_age.filter(|_| source.is_live()) {
+            let is_stale = age > max_age as f64;
+            if is_stale != stale {
+                if is_stale {
+                    warn!(
+                        ?device,
+                        "Data is {age:.0} s old, not writing it until fresh data arrives."
+                    );
+                } else {
+                    info!(?device, "Fresh data is arriving again.");
+                }
+                stale = is_stale;
+            }
+            if stale {
+                continue;
+            }
+        }
+        current_measurements.device = device.clone();
+        warn_missing_fields(&current_measurements, &config, &mut missing_fields);
+        calibrate(&mut current_measurements, &config);
+        let dropped = drop_implausible(&mut current_measurements, &config);
+        stats
+            .sensor_faults
+            .fetch_add(dropped.len() as u64, Ordering::Relaxed);
+        let faults = fault_tracker.update(&dropped, &current_measurements);
+        if let Some(heat_meter) = &mut heat_meter {
+            heat_meter.apply(&mut current_measurements);
+        }
+        expr::apply(&config.computed, &mut current_measurements);
+        let mut events = match &mut relay_tracker {
+            Some(relay_tracker) => relay_tracker.apply(&mut current_measurements),
+            None => Vec::new(),
+        };
+        if let Some(annotations) = &config.annotations {
+            let relays: Vec<_> = events
+                .iter()
+                .filter_map(|event| annotations.relay_switched(event))
+                .collect();
+            events.extend(relays);
+            events.extend(faults.into_iter().map(|text| {
+                let mut annotation = annotations.annotation(device.clone(), "sensor_fault", text);
+                annotation.time = current_measurements.time;
+                annotation
+            }));
+        }
+        for measurements in iter::once(current_measurements).chain(events) {
+            if !send(&sender, measurements, source.is_live(), stats) {
+                // Writer is shutting down
+                return Ok(());
+            }
+        }
+    }
+    info!(?device, "End of data reached.");
+    Ok(())
+}
+
+/// Seconds since the packet was received or, when read from a datalogger, recorded.
+fn packet_age(dataset: &DataSet) -> f64 {
+    (Utc::now() - dataset.timestamp).num_milliseconds() as f64 / 1000.0
+}
+
+/// Hands measurements to the writer, returning `false` once it is gone. Live sources drop
+/// them if the queue is full, the others wait for room.
+fn send(
+    sender: &mpsc::Sender<Measurements>,
+    measurements: Measurements,
+    live: bool,
+    stats: &Stats,
+) -> bool {
+    if !live {
+        return sender.blocking_send(measurements).is_ok();
+    }
+    match sender.try_send(measurements) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+            stats.queue_dropped.fetch_add(1, Ordering::Relaxed);
+            warn!("Writer is lagging behind, dropping measurements.");
+            true
+        }
+        Err(TrySendError::Closed(_)) => false,
+    }
+}
+
+/// Warns once about each mapped field a packet lacked, remembering it in `missing`.
+fn warn_missing_fields(
+    measurements: &Measurements,
+    config: &Config,
+    missing: &mut BTreeSet<String>,
+) {
+    for name in config.mapped_field_names() {
+        if !measurements.fields.contains_key(&name) && missing.insert(name.clone()) {
+            warn!(
+                device = ?measurements.device,
+                "Field `{name}` is missing from the packet, writing the others without it."
+            );
+        }
+    }
+}
+
+/// Applies the configured scale and offset, before the plausibility check so the ranges
+/// refer to corrected values.
+pub(crate) fn calibrate(measurements: &mut Measurements, config: &Config) {
+    for field in &config.fields {
+        if let Some(value) = measurements.fields.get_mut(&field.name) {
+            *value = field.calibrate(*value);
+        }
+    }
+}
+
+/// Removes values outside of their plausible range, e.g. sentinels of broken sensors,
+/// returning the removed ones.
+pub(crate) fn drop_implausible(
+    measurements: &mut Measurements,
+    config: &Config,
+) -> Vec<(String, f64)> {
+    let mut dropped = Vec::new();
+    for field in &config.fields {
+        if let Some(&value) = measurements.fields.get(&field.name) {
+            if !field.is_plausible(value) {
+                debug!(field = %field.name, value, "Dropping implausible value");
+                measurements.fields.remove(&field.name);
+                dropped.push((field.name.clone(), value));
+            }
+        }
+    }
+    dropped
+}
+
+/// Resolves once SIGINT or SIGTERM is received.
+async fn shutdown_signal() -> Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        res = tokio::signal::ctrl_c() => res?,
+        _ = terminate.recv() => {}
+    }
+    Ok(())
+}
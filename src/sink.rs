@@ -0,0 +1,337 @@
+pub mod clickhouse;
+pub mod csv;
+pub mod emoncms;
+pub mod graphite;
+pub mod influx;
+pub mod line_protocol;
+pub mod mqtt;
+pub mod postgres;
+pub mod pvoutput;
+pub mod redis;
+pub mod remote_write;
+pub mod sqlite;
+pub mod stdout;
+pub mod webhook;
+
+use std::{
+    error::Error,
+    fmt,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use color_eyre::Result;
+use rand::Rng;
+use tokio::{
+    sync::{mpsc, watch},
+    task,
+    time::{self, Instant, MissedTickBehavior},
+};
+use tracing::{debug, error, instrument, warn};
+
+use crate::{
+    buffer::Buffer,
+    stats::{SinkStats, Stats},
+    Measurements,
+};
+
+/// Delay before retrying a failed write, doubled on every further failure.
+const MIN_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(300);
+
+/// How long to keep trying to write buffered measurements on shutdown.
+const FINAL_FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An output measurements are written to.
+#[async_trait]
+pub trait Sink: Send + Sync {
+    /// Name used in logs and metrics.
+    fn name(&self) -> &str;
+
+    /// Writes the points, oldest first, all or nothing.
+    async fn write(&self, points: &[Measurements]) -> Result<()>;
+}
+
+/// A write failure retrying won't fix, e.g. a wrong token, a missing bucket or points the
+/// database rejects. The batch is dropped instead of blocking the ones behind it.
+#[derive(Debug)]
+pub struct PermanentError(pub String);
+
+impl fmt::Display for PermanentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for PermanentError {}
+
+/// Quotes a table or column name for SQL, so field names may contain any character.
+pub(crate) fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Pauses, resumes and flushes all sinks, e.g. during maintenance of the database. Outlives
+/// the sinks, which are restarted on reloads.
+pub struct SinkControl {
+    paused: watch::Sender<bool>,
+    /// Counts requested flushes, so each one wakes every sink.
+    flushes: watch::Sender<u64>,
+}
+
+impl Default for SinkControl {
+    fn default() -> Self {
+        SinkControl {
+            paused: watch::channel(false).0,
+            flushes: watch::channel(0).0,
+        }
+    }
+}
+
+impl SinkControl {
+    /// While paused, measurements are only buffered.
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.send_replace(paused);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    /// Makes every sink write its buffer at once, even while paused or waiting for a retry.
+    pub fn flush(&self) {
+        self.flushes.send_modify(|flushes| *flushes += 1);
+    }
+}
+
+/// A sink together with how it is fed.
+pub struct SinkRunner {
+    pub sink: Box<dyn Sink>,
+    /// Where points wait until they are written.
+    pub buffer: Buffer,
+    /// Number of points written at once.
+    pub batch_size: usize,
+    /// Time after which an incomplete batch is written anyway.
+    pub batch_interval: Duration,
+}
+
+impl SinkRunner {
+    pub fn new(sink: impl Sink + 'static) -> Self {
+        SinkRunner {
+            sink: Box::new(sink),
+            buffer: Buffer::in_memory(),
+            batch_size: 1,
+            batch_interval: Duration::from_secs(10),
+        }
+    }
+
+    /// Feeds the sink from its own queue until the channel is closed, so a slow or failing
+    /// sink doesn't hold up the others.
+    pub async fn run(
+        mut self,
+        mut receiver: mpsc::Receiver<Measurements>,
+        stats: Arc<Stats>,
+        control: Arc<SinkControl>,
+    ) -> Result<()> {
+        let sink_stats = stats.register_sink(self.sink.name());
+        let mut batch_timer = time::interval(self.batch_interval);
+        batch_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut backoff = Backoff::default();
+        let mut paused = control.paused.subscribe();
+        let mut flushes = control.flushes.subscribe();
+        loop {
+            let is_paused = *paused.borrow();
+            tokio::select! {
+                point = receiver.recv() => {
+                    let Some(point) = point else {
+                        break;
+                    };
+                    let dropped = self.buffer.push_back(point);
+                    if dropped > 0 {
+                        // Happens with every new point during an outage, so keep quiet
+                        debug!(sink = self.sink.name(), dropped, "Dropped the oldest measurements.");
+                        sink_stats.dropped_points.fetch_add(dropped as u64, Ordering::Relaxed);
+                    }
+                    if is_paused || self.buffer.len() < self.batch_size || backoff.is_waiting() {
+                        self.persist();
+                        continue;
+                    }
+                }
+                // Write incomplete batches from time to time
+                _ = batch_timer.tick(), if !backoff.is_waiting() && !is_paused => {}
+                _ = backoff.wait(), if backoff.is_waiting() && !is_paused => {}
+                // Resuming writes what piled up in the meantime
+                _ = paused.changed() => {
+                    if *paused.borrow() {
+                        continue;
+                    }
+                    backoff.reset();
+                }
+                _ = flushes.changed() => backoff.reset(),
+            }
+
+            if self.write_buffer(&sink_stats).await {
+                backoff.reset();
+            } else {
+                let delay = backoff.fail();
+                warn!(
+                    sink = self.sink.name(),
+                    failures = backoff.failures,
+                    "Retrying in {delay:?}."
+                );
+            }
+            self.persist();
+        }
+
+        // Give the sink one last chance to receive what is still buffered, unless it is paused
+        let name = self.sink.name().to_owned();
+        if !control.is_paused()
+            && time::timeout(FINAL_FLUSH_TIMEOUT, self.write_buffer(&sink_stats))
+                .await
+                .is_err()
+        {
+            warn!(sink = %name, "Timeout while flushing the buffer.");
+        }
+        if let Err(err) = self.buffer.persist() {
+            error!(sink = %name, "Error while saving the buffer: {err}");
+        }
+        if !self.buffer.is_empty() && !self.buffer.is_persistent() {
+            warn!(
+                sink = %name,
+                "Dropping {} measurements that couldn't be written.",
+                self.buffer.len()
+            );
+        }
+        Ok(())
+    }
+
+    /// Mirrors the buffer to its file. Failing to do so keeps the points in memory only, so
+    /// they are still written once the sink is back.
+    fn persist(&mut self) {
+        // Writing the buffer file may block on slow SD cards
+        if let Err(err) = task::block_in_place(|| self.buffer.persist()) {
+            error!(
+                sink = self.sink.name(),
+                "Error while saving the buffer: {err}"
+            );
+        }
+    }
+
+    /// Writes buffered measurements in batches, oldest first, until the buffer is empty or
+    /// a write fails. Returns whether everything was written.
+    #[instrument(skip_all, fields(sink = self.sink.name(), buffered = self.buffer.len()))]
+    async fn write_buffer(&mut self, stats: &SinkStats) -> bool {
+        while !self.buffer.is_empty() {
+            let batch: Vec<_> = self
+                .buffer
+                .iter()
+                .take(self.batch_size.max(1))
+                .cloned()
+                .collect();
+
+            let started = Instant::now();
+            match self.sink.write(&batch).await {
+                Ok(()) => stats.record_success(started.elapsed()),
+                Err(err) if err.is::<PermanentError>() => {
+                    error!(
+                        "Dropping {} measurements, {} won't accept them: {err}",
+                        batch.len(),
+                        self.sink.name()
+                    );
+                    stats.record_failure();
+                    stats
+                        .dropped_points
+                        .fetch_add(batch.len() as u64, Ordering::Relaxed);
+                }
+                Err(err) => {
+                    error!("Error while writing to {}: {err}", self.sink.name());
+                    stats.record_failure();
+                    stats.set_buffered(self.buffer.len());
+                    return false;
+                }
+            }
+            for _ in 0..batch.len() {
+                self.buffer.pop_front();
+            }
+        }
+        stats.set_buffered(self.buffer.len());
+        true
+    }
+}
+
+/// Exponential backoff with jitter between retries of failed writes, so a database that is
+/// down isn't hit with every new measurement.
+#[derive(Default)]
+struct Backoff {
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl Backoff {
+    fn is_waiting(&self) -> bool {
+        self.retry_at.is_some()
+    }
+
+    /// Waits until the next retry is due, returns immediately if none is pending.
+    async fn wait(&self) {
+        if let Some(retry_at) = self.retry_at {
+            time::sleep_until(retry_at).await;
+        }
+    }
+
+    /// Records a failure and returns the delay until the next retry.
+    fn fail(&mut self) -> Duration {
+        self.failures += 1;
+        let backoff = MIN_RETRY_BACKOFF
+            .saturating_mul(2u32.saturating_pow(self.failures - 1))
+            .min(MAX_RETRY_BACKOFF);
+        // Somewhere between half and the full backoff, so several instances don't retry in step
+        let delay = rand::thread_rng().gen_range(backoff / 2..=backoff);
+        self.retry_at = Some(Instant::now() + delay);
+        delay
+    }
+
+    fn reset(&mut self) {
+        self.failures = 0;
+        self.retry_at = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let mut backoff = Backoff::default();
+        assert!(!backoff.is_waiting());
+        for failures in 1..=12 {
+            let delay = backoff.fail();
+            let full = MIN_RETRY_BACKOFF
+                .saturating_mul(1 << (failures - 1))
+                .min(MAX_RETRY_BACKOFF);
+            assert!(
+                (full / 2..=full).contains(&delay),
+                "{delay:?} after {failures}"
+            );
+        }
+        assert!(backoff.is_waiting());
+    }
+
+    #[test]
+    fn backoff_starts_over_after_reset() {
+        let mut backoff = Backoff::default();
+        for _ in 0..5 {
+            backoff.fail();
+        }
+        backoff.reset();
+        assert!(!backoff.is_waiting());
+        assert!(backoff.fail() <= MIN_RETRY_BACKOFF);
+    }
+
+    #[test]
+    fn quotes_identifiers() {
+        assert_eq!(quote_identifier("temperature_1"), "\"temperature_1\"");
+        assert_eq!(quote_identifier("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+}
@@ -0,0 +1,197 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use color_eyre::{eyre::eyre, Result};
+use reqwest::{Client, RequestBuilder, Url};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use tokio::sync::Mutex;
+use tracing::info;
+
+use super::{quote_identifier, PermanentError, Sink};
+use crate::Measurements;
+
+#[derive(Deserialize, Clone)]
+pub struct ClickhouseConfig {
+    /// URL of the HTTP interface, e.g. `http://clickhouse.local:8123`.
+    pub url: String,
+    #[serde(default = "default_database")]
+    pub database: String,
+    #[serde(default = "default_table")]
+    pub table: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Let the server collect the rows of several inserts into one part, waiting for it to be
+    /// written before a batch counts as sent.
+    #[serde(default = "default_async_insert")]
+    pub async_insert: bool,
+    /// Rows sent in one insert.
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    /// Seconds after which an incomplete batch is sent anyway.
+    #[serde(default = "default_batch_interval")]
+    pub batch_interval: u64,
+}
+
+fn default_database() -> String {
+    "default".to_owned()
+}
+
+fn default_table() -> String {
+    "measurements".to_owned()
+}
+
+fn default_async_insert() -> bool {
+    true
+}
+
+fn default_batch_size() -> usize {
+    100
+}
+
+fn default_batch_interval() -> u64 {
+    60
+}
+
+impl ClickhouseConfig {
+    pub fn batch_interval(&self) -> Duration {
+        Duration::from_secs(self.batch_interval)
+    }
+}
+
+/// Inserts every measurement as a row into a ClickHouse table through the HTTP interface,
+/// with a column per field. The table and missing columns are created before the first insert.
+pub struct ClickhouseSink {
+    client: Client,
+    config: ClickhouseConfig,
+    url: Url,
+    /// Field columns, after `time` and `device`.
+    columns: Vec<String>,
+    /// Whether the table was set up, tried again with the next write until it was.
+    created: Mutex<bool>,
+}
+
+impl ClickhouseSink {
+    pub fn new(config: ClickhouseConfig, columns: Vec<String>) -> Result<Self> {
+        let url = Url::parse(&config.url)?;
+        Ok(ClickhouseSink {
+            client: Client::new(),
+            config,
+            url,
+            columns,
+            created: Mutex::new(false),
+        })
+    }
+
+    fn request(&self, query: &str) -> RequestBuilder {
+        let mut request = self.client.post(self.url.clone()).query(&[
+            ("database", self.config.database.as_str()),
+            ("query", query),
+        ]);
+        if let Some(username) = &self.config.username {
+            request = request.header("X-ClickHouse-User", username);
+        }
+        if let Some(password) = &self.config.password {
+            request = request.header("X-ClickHouse-Key", password);
+        }
+        request
+    }
+
+    async fn send(&self, request: RequestBuilder) -> Result<()> {
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let message = format!(
+                "ClickHouse answered {status}: {}",
+                response.text().await.unwrap_or_default().trim()
+            );
+            // Wrong credentials and rows the table doesn't take stay that way
+            return Err(match status.as_u16() {
+                400 | 401 | 403 | 404 => PermanentError(message).into(),
+                _ => eyre!(message),
+            });
+        }
+        Ok(())
+    }
+
+    async fn create_table(&self) -> Result<()> {
+        let table = quote(&self.config.table);
+        let create = format!(
+            "CREATE TABLE IF NOT EXISTS {table} (time DateTime64(3, 'UTC'), \
+             device LowCardinality(String)) ENGINE = MergeTree ORDER BY (device, time)"
+        );
+        self.send(self.request(&create)).await?;
+        // Fields mapped since the table was created get a column of their own
+        for column in &self.columns {
+            let alter = format!(
+                "ALTER TABLE {table} ADD COLUMN IF NOT EXISTS {} Nullable(Float64)",
+                quote(column)
+            );
+            self.send(self.request(&alter)).await?;
+        }
+        info!("Writing to ClickHouse table `{}`.", self.config.table);
+        Ok(())
+    }
+
+    /// A row of the measurements as JSON, leaving out the fields without a column.
+    fn row(&self, measurements: &Measurements) -> String {
+        let mut row = Map::new();
+        let time = measurements.time.format("%Y-%m-%d %H:%M:%S%.3f");
+        row.insert("time".to_owned(), time.to_string().into());
+        row.insert(
+            "device".to_owned(),
+            measurements.device.clone().unwrap_or_default().into(),
+        );
+        for column in &self.columns {
+            // JSON has no representation for NaN and infinity
+            if let Some(value) = measurements
+                .fields
+                .get(column)
+                .filter(|value| value.is_finite())
+            {
+                row.insert(column.clone(), (*value).into());
+            }
+        }
+        Value::Object(row).to_string()
+    }
+}
+
+/// Quotes an identifier for ClickHouse, which also reads backslash escapes inside quotes.
+fn quote(name: &str) -> String {
+    quote_identifier(&name.replace('\\', "\\\\"))
+}
+
+#[async_trait]
+impl Sink for ClickhouseSink {
+    fn name(&self) -> &str {
+        "clickhouse"
+    }
+
+    async fn write(&self, points: &[Measurements]) -> Result<()> {
+        let mut created = self.created.lock().await;
+        if !*created {
+            self.create_table().await?;
+            *created = true;
+        }
+        drop(created);
+
+        // Events have other columns, they don't fit into the table
+        let rows: Vec<_> = points
+            .iter()
+            .filter(|m| m.measurement.is_none())
+            .map(|measurements| self.row(measurements))
+            .collect();
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let insert = format!(
+            "INSERT INTO {} FORMAT JSONEachRow",
+            quote(&self.config.table)
+        );
+        let mut request = self.request(&insert).body(rows.join("\n"));
+        if self.config.async_insert {
+            request = request.query(&[("async_insert", "1"), ("wait_for_async_insert", "1")]);
+        }
+        self.send(request).await
+    }
+}
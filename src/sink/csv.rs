@@ -0,0 +1,174 @@
+use std::{
+    borrow::Cow,
+    fmt::Write as _,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use async_trait::async_trait;
+use color_eyre::{eyre::eyre, Result};
+use serde::Deserialize;
+use tokio::task;
+use tracing::info;
+
+use super::Sink;
+use crate::Measurements;
+
+#[derive(Deserialize, Clone)]
+pub struct CsvConfig {
+    /// Base path of the files, the date is inserted before the extension, e.g.
+    /// `vbus.csv` becomes `vbus_2022-08-01.csv`.
+    pub path: PathBuf,
+}
+
+/// Appends every measurement as a row to a CSV file, starting a new file each day (UTC).
+pub struct CsvSink {
+    path: PathBuf,
+    /// Field columns, after `time` and `device`.
+    columns: Vec<String>,
+    file: Mutex<Option<(String, File)>>,
+}
+
+impl CsvSink {
+    pub fn new(config: CsvConfig, columns: Vec<String>) -> Self {
+        CsvSink {
+            path: config.path,
+            columns,
+            file: Mutex::new(None),
+        }
+    }
+
+    /// Path of the file for the given day.
+    fn dated_path(&self, date: &str) -> PathBuf {
+        let stem = self
+            .path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy())
+            .unwrap_or_default();
+        let name = match self.path.extension() {
+            Some(extension) => format!("{stem}_{date}.{}", extension.to_string_lossy()),
+            None => format!("{stem}_{date}"),
+        };
+        self.path.with_file_name(name)
+    }
+
+    fn header(&self) -> String {
+        let mut header = String::from("time,device");
+        for column in &self.columns {
+            header.push(',');
+            header.push_str(&quote(column));
+        }
+        header.push('\n');
+        header
+    }
+
+    fn row(&self, measurements: &Measurements) -> String {
+        let mut row = measurements.time.to_rfc3339();
+        row.push(',');
+        row.push_str(&quote(measurements.device.as_deref().unwrap_or_default()));
+        for column in &self.columns {
+            row.push(',');
+            if let Some(value) = measurements.fields.get(column) {
+                let _ = write!(row, "{value}");
+            }
+        }
+        row.push('\n');
+        row
+    }
+
+    fn append(&self, points: &[Measurements]) -> Result<()> {
+        let mut current = self
+            .file
+            .lock()
+            .map_err(|_| eyre!("CSV file lock poisoned."))?;
+        // Rows by day, each day has a file of its own. Events have other columns, they don't
+        // fit into the file.
+        let mut days: Vec<(String, String)> = Vec::new();
+        for measurements in points.iter().filter(|m| m.measurement.is_none()) {
+            let date = measurements.time.format("%Y-%m-%d").to_string();
+            let row = self.row(measurements);
+            match days.last_mut() {
+                Some((day, rows)) if *day == date => rows.push_str(&row),
+                _ => days.push((date, row)),
+            }
+        }
+        // A failed batch is written again in full, so none of it may stay behind
+        let mut written = Vec::new();
+        let result = self.append_days(&mut current, days, &mut written);
+        if result.is_err() {
+            for (file, len) in written {
+                let _ = file.set_len(len);
+            }
+        }
+        result
+    }
+
+    /// Writes the rows of each day at once, remembering the files and their lengths before.
+    fn append_days(
+        &self,
+        current: &mut Option<(String, File)>,
+        days: Vec<(String, String)>,
+        written: &mut Vec<(File, u64)>,
+    ) -> Result<()> {
+        for (date, rows) in days {
+            if current.as_ref().is_none_or(|(day, _)| *day != date) {
+                let path = self.dated_path(&date);
+                let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+                if file.metadata()?.len() == 0 {
+                    file.write_all(self.header().as_bytes())?;
+                }
+                info!("Writing CSV to `{}`.", path.display());
+                *current = Some((date, file));
+            }
+            if let Some((_, file)) = current.as_mut() {
+                written.push((file.try_clone()?, file.metadata()?.len()));
+                file.write_all(rows.as_bytes())?;
+                file.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Quotes a field containing separators, quotes or line breaks.
+fn quote(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+#[async_trait]
+impl Sink for CsvSink {
+    fn name(&self) -> &str {
+        "csv"
+    }
+
+    async fn write(&self, points: &[Measurements]) -> Result<()> {
+        // Writing may block on slow SD cards
+        task::block_in_place(|| self.append(points))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_the_device() {
+        let sink = CsvSink::new(
+            CsvConfig {
+                path: PathBuf::from("vbus.csv"),
+            },
+            vec!["collector".to_owned()],
+        );
+        let mut measurements = Measurements::empty();
+        measurements.device = Some("roof, \"east\"".to_owned());
+        measurements.fields.insert("collector".to_owned(), 21.5);
+        let row = sink.row(&measurements);
+        assert!(row.ends_with(",\"roof, \"\"east\"\"\",21.5\n"), "{row}");
+    }
+}
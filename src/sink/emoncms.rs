@@ -0,0 +1,92 @@
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use color_eyre::{eyre::eyre, Result};
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::{PermanentError, Sink};
+use crate::Measurements;
+
+#[derive(Deserialize, Clone)]
+pub struct EmoncmsConfig {
+    /// Base URL of the instance, e.g. `http://emonpi.local` or `https://emoncms.org`.
+    pub url: String,
+    /// Read & Write API key from the account page.
+    pub apikey: String,
+    /// Node the inputs appear under, named sources use their device name instead.
+    #[serde(default = "default_node")]
+    pub node: String,
+}
+
+fn default_node() -> String {
+    "vbus".to_owned()
+}
+
+/// Posts every field as an input to EmonCMS's `/input/post`, e.g. on an emonPi.
+pub struct EmoncmsSink {
+    client: Client,
+    config: EmoncmsConfig,
+    url: String,
+}
+
+impl EmoncmsSink {
+    pub fn new(config: EmoncmsConfig) -> Result<Self> {
+        let url = format!("{}/input/post", config.url.trim_end_matches('/'));
+        reqwest::Url::parse(&url)?;
+        Ok(EmoncmsSink {
+            client: Client::new(),
+            config,
+            url,
+        })
+    }
+
+    async fn post(&self, measurements: &Measurements) -> Result<()> {
+        // JSON has no representation for NaN and infinity
+        let fields: BTreeMap<_, _> = measurements
+            .fields
+            .iter()
+            .filter(|(_, value)| value.is_finite())
+            .collect();
+        if fields.is_empty() {
+            return Ok(());
+        }
+        let node = measurements.device.as_ref().unwrap_or(&self.config.node);
+        let form = [
+            ("node", node.clone()),
+            ("time", measurements.time.timestamp().to_string()),
+            ("fulljson", serde_json::to_string(&fields)?),
+            ("apikey", self.config.apikey.clone()),
+        ];
+        let response = self.client.post(&self.url).form(&form).send().await?;
+        let status = response.status();
+        let message = response.text().await.unwrap_or_default();
+        if status.as_u16() == 401 || status.as_u16() == 403 {
+            return Err(PermanentError(format!("EmonCMS answered {status}: {message}")).into());
+        }
+        // Older versions answer `ok`, newer ones JSON, both with 200 even on errors
+        let accepted = message.trim() == "ok"
+            || serde_json::from_str::<Value>(&message)
+                .is_ok_and(|answer| answer["success"] == Value::Bool(true));
+        if !status.is_success() || !accepted {
+            return Err(eyre!("EmonCMS answered {status}: {message}"));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for EmoncmsSink {
+    fn name(&self) -> &str {
+        "emoncms"
+    }
+
+    async fn write(&self, points: &[Measurements]) -> Result<()> {
+        // Events are no samples of an input
+        for measurements in points.iter().filter(|m| m.measurement.is_none()) {
+            self.post(measurements).await?;
+        }
+        Ok(())
+    }
+}
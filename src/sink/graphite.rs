@@ -0,0 +1,107 @@
+use std::fmt::Write as _;
+
+use async_trait::async_trait;
+use color_eyre::Result;
+use serde::Deserialize;
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpStream, UdpSocket},
+};
+
+use super::Sink;
+use crate::Measurements;
+
+#[derive(Deserialize, Clone)]
+pub struct GraphiteConfig {
+    /// Plaintext receiver of carbon, e.g. `graphite.local:2003`.
+    pub address: String,
+    #[serde(default)]
+    pub protocol: GraphiteProtocol,
+    /// Put in front of every metric path, e.g. `home.solar`.
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum GraphiteProtocol {
+    #[default]
+    Tcp,
+    /// One datagram per measurement, lost without notice if carbon doesn't receive it.
+    Udp,
+}
+
+fn default_prefix() -> String {
+    "vbus".to_owned()
+}
+
+/// Sends `<prefix>.<device>.<field> <value> <timestamp>` lines in carbon's plaintext protocol,
+/// leaving out the device of unnamed sources.
+pub struct GraphiteSink {
+    config: GraphiteConfig,
+}
+
+impl GraphiteSink {
+    pub fn new(config: GraphiteConfig) -> Self {
+        GraphiteSink { config }
+    }
+
+    /// The lines of one measurement.
+    fn lines(&self, measurements: &Measurements) -> String {
+        let mut path = self.config.prefix.clone();
+        if let Some(device) = &measurements.device {
+            path.push('.');
+            path.push_str(&sanitize(device));
+        }
+        let timestamp = measurements.time.timestamp();
+        let mut lines = String::new();
+        // Plaintext has no representation for NaN and infinity
+        for (name, value) in measurements.fields.iter().filter(|(_, v)| v.is_finite()) {
+            let _ = writeln!(lines, "{path}.{} {value} {timestamp}", sanitize(name));
+        }
+        lines
+    }
+}
+
+/// Replaces everything but letters, digits, `-` and `_` in a path segment, dots would
+/// start a new one.
+fn sanitize(segment: &str) -> String {
+    segment
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[async_trait]
+impl Sink for GraphiteSink {
+    fn name(&self) -> &str {
+        "graphite"
+    }
+
+    async fn write(&self, points: &[Measurements]) -> Result<()> {
+        // Events are no samples of a metric
+        let measurements = points.iter().filter(|m| m.measurement.is_none());
+        match self.config.protocol {
+            GraphiteProtocol::Tcp => {
+                let lines: String = measurements.map(|m| self.lines(m)).collect();
+                let mut stream = TcpStream::connect(&self.config.address).await?;
+                stream.write_all(lines.as_bytes()).await?;
+                stream.shutdown().await?;
+            }
+            GraphiteProtocol::Udp => {
+                let socket = UdpSocket::bind("0.0.0.0:0").await?;
+                socket.connect(&self.config.address).await?;
+                for measurements in measurements {
+                    socket.send(self.lines(measurements).as_bytes()).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
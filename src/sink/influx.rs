@@ -0,0 +1,84 @@
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use color_eyre::{eyre::eyre, Result};
+use influxdb::Client;
+
+use super::{line_protocol::Precision, PermanentError, Sink};
+use crate::{
+    routes::{self, FieldTags, RouteConfig},
+    Measurements,
+};
+
+pub struct InfluxSink {
+    pub client: Client,
+    /// Clients for the buckets of the routes.
+    pub route_clients: BTreeMap<String, Client>,
+    pub measurement: String,
+    pub routes: Vec<RouteConfig>,
+    pub field_tags: FieldTags,
+    pub precision: Precision,
+}
+
+#[async_trait]
+impl Sink for InfluxSink {
+    fn name(&self) -> &str {
+        "influxdb"
+    }
+
+    async fn write(&self, points: &[Measurements]) -> Result<()> {
+        let mut batches = BTreeMap::<_, Vec<_>>::new();
+        for measurements in points {
+            for (bucket, part) in routes::split(&self.routes, &self.field_tags, measurements) {
+                batches
+                    .entry(bucket)
+                    .or_default()
+                    .push(part.into_query(&self.measurement, self.precision));
+            }
+        }
+        for (bucket, batch) in batches {
+            let client = match bucket {
+                Some(bucket) => self
+                    .route_clients
+                    .get(bucket)
+                    .ok_or_else(|| eyre!("No client for bucket `{bucket}`."))?,
+                None => &self.client,
+            };
+            client.query(&batch).await.map_err(|err| {
+                if is_permanent(&err) {
+                    PermanentError(err.to_string()).into()
+                } else {
+                    eyre!(err)
+                }
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Parts of the errors InfluxDB answers with for the statuses `line_protocol` doesn't retry
+/// (400, 404, 413 and 422), by API version.
+const PERMANENT_ERRORS: [&str; 8] = [
+    // 2.x, the `code` of the answer
+    "\"invalid\"",
+    "\"not found\"",
+    "\"request too large\"",
+    "\"unprocessable entity\"",
+    // 1.x
+    "database not found",
+    "unable to parse",
+    "partial write",
+    "field type conflict",
+];
+
+/// Whether the batch would be rejected again. The client library only passes on the body of
+/// other errors than the authentication ones, not their status.
+fn is_permanent(err: &influxdb::Error) -> bool {
+    match err {
+        influxdb::Error::AuthenticationError | influxdb::Error::AuthorizationError => true,
+        influxdb::Error::DatabaseError { error } => {
+            PERMANENT_ERRORS.iter().any(|part| error.contains(part))
+        }
+        _ => false,
+    }
+}
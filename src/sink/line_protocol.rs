@@ -0,0 +1,262 @@
+use std::{collections::BTreeMap, fmt::Write as _, io::Write};
+
+use async_trait::async_trait;
+use color_eyre::{eyre::eyre, Result};
+use flate2::{write::GzEncoder, Compression};
+use reqwest::{header, Client};
+use resol_vbus::chrono::{DateTime, Utc};
+
+use super::{PermanentError, Sink};
+use crate::{
+    routes::{self, FieldTags, RouteConfig},
+    Measurements,
+};
+
+/// Writes line protocol straight to `/api/v2/write`, which InfluxDB 1.8 (compatibility API),
+/// 2.x and 3.x as well as e.g. VictoriaMetrics understand.
+pub struct LineProtocolSink {
+    pub client: Client,
+    /// `/api/v2/write` of the server.
+    url: String,
+    org: String,
+    bucket: String,
+    token: String,
+    measurement: String,
+    /// Fields written to other measurements or buckets.
+    pub routes: Vec<RouteConfig>,
+    /// Fields written as points of their own with extra tags.
+    pub field_tags: FieldTags,
+    precision: Precision,
+    gzip: bool,
+}
+
+/// Timestamp precision of the written points.
+#[derive(Clone, Copy)]
+pub enum Precision {
+    Seconds,
+    Milliseconds,
+    Microseconds,
+    Nanoseconds,
+}
+
+impl Precision {
+    pub fn parse(precision: &str) -> Result<Self> {
+        match precision {
+            "s" => Ok(Precision::Seconds),
+            "ms" => Ok(Precision::Milliseconds),
+            "us" => Ok(Precision::Microseconds),
+            "ns" => Ok(Precision::Nanoseconds),
+            _ => Err(eyre!(
+                "Invalid precision `{precision}`, use s, ms, us or ns."
+            )),
+        }
+    }
+
+    /// The time in units of the precision since the epoch, truncated.
+    pub fn timestamp(self, time: DateTime<Utc>) -> i64 {
+        match self {
+            Precision::Seconds => time.timestamp(),
+            Precision::Milliseconds => time.timestamp_millis(),
+            Precision::Microseconds => time.timestamp_nanos() / 1_000,
+            Precision::Nanoseconds => time.timestamp_nanos(),
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Precision::Seconds => "s",
+            Precision::Milliseconds => "ms",
+            Precision::Microseconds => "us",
+            Precision::Nanoseconds => "ns",
+        }
+    }
+}
+
+impl LineProtocolSink {
+    pub fn new(
+        url: &str,
+        org: &str,
+        bucket: &str,
+        token: &str,
+        measurement: &str,
+        precision: Precision,
+        gzip: bool,
+    ) -> Result<Self> {
+        let url = reqwest::Url::parse(&format!("{}/api/v2/write", url.trim_end_matches('/')))?;
+        Ok(LineProtocolSink {
+            client: Client::new(),
+            url: url.into(),
+            org: org.to_owned(),
+            bucket: bucket.to_owned(),
+            token: token.to_owned(),
+            measurement: measurement.to_owned(),
+            routes: Vec::new(),
+            field_tags: FieldTags::new(),
+            precision,
+            gzip,
+        })
+    }
+
+    async fn post(&self, bucket: &str, body: String) -> Result<()> {
+        let url = reqwest::Url::parse_with_params(
+            &self.url,
+            [
+                ("org", self.org.as_str()),
+                ("bucket", bucket),
+                ("precision", self.precision.as_str()),
+            ],
+        )?;
+        let mut request = self
+            .client
+            .post(url)
+            .header(header::AUTHORIZATION, format!("Token {}", self.token))
+            .header(header::CONTENT_TYPE, "text/plain; charset=utf-8");
+        request = if self.gzip {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(body.as_bytes())?;
+            request
+                .header(header::CONTENT_ENCODING, "gzip")
+                .body(encoder.finish()?)
+        } else {
+            request.body(body)
+        };
+
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let message = format!(
+                "InfluxDB answered {status}: {}",
+                response.text().await.unwrap_or_default()
+            );
+            // A wrong token, a missing bucket or malformed points stay that way
+            return Err(match status.as_u16() {
+                400 | 401 | 403 | 404 | 413 | 422 => PermanentError(message).into(),
+                _ => eyre!(message),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Formats measurements as a line of line protocol, written to `measurement` unless they
+/// name their own.
+pub fn line(measurements: &Measurements, measurement: &str, precision: Precision) -> String {
+    let measurement = measurements.measurement.as_deref().unwrap_or(measurement);
+    let mut line = escape(measurement, &[',', ' ']);
+    let tags = measurements.device.iter().map(|device| ("device", device));
+    for (tag, value) in tags.chain(measurements.tags.iter().map(|(k, v)| (k.as_str(), v))) {
+        let _ = write!(
+            line,
+            ",{}={}",
+            escape(tag, &[',', '=', ' ']),
+            escape(value, &[',', '=', ' '])
+        );
+    }
+    // Line protocol has no representation for NaN and infinity
+    let mut fields: Vec<_> = measurements
+        .fields
+        .iter()
+        .filter(|(_, value)| value.is_finite())
+        .map(|(name, value)| format!("{}={value}", escape(name, &[',', '=', ' '])))
+        .collect();
+    if let Some(text) = &measurements.text {
+        fields.push(format!("text=\"{}\"", escape(text, &['"'])));
+    }
+    let _ = write!(line, " {}", fields.join(","));
+    let _ = write!(line, " {}", precision.timestamp(measurements.time));
+    line
+}
+
+/// Escapes the given characters with a backslash.
+fn escape(value: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '\\' || special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[async_trait]
+impl Sink for LineProtocolSink {
+    fn name(&self) -> &str {
+        "influxdb"
+    }
+
+    async fn write(&self, points: &[Measurements]) -> Result<()> {
+        let mut bodies = BTreeMap::<_, Vec<_>>::new();
+        for measurements in points {
+            for (bucket, part) in routes::split(&self.routes, &self.field_tags, measurements) {
+                if part.text.is_some() || part.fields.values().any(|v| v.is_finite()) {
+                    let line = line(&part, &self.measurement, self.precision);
+                    bodies.entry(bucket).or_default().push(line);
+                }
+            }
+        }
+        for (bucket, body) in bodies {
+            let bucket = bucket.unwrap_or(&self.bucket);
+            self.post(bucket, body.join("\n")).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measurements() -> Measurements {
+        Measurements {
+            time: DateTime::parse_from_rfc3339("2020-09-13T12:26:40.5Z")
+                .unwrap()
+                .with_timezone(&Utc),
+            device: Some("roof, east".to_owned()),
+            measurement: None,
+            fields: BTreeMap::from([
+                ("temperature_01".to_owned(), 21.5),
+                ("a=b".to_owned(), 1.0),
+                ("broken".to_owned(), f64::NAN),
+            ]),
+            text: None,
+            tags: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn escapes_special_characters() {
+        assert_eq!(escape("a b,c=d", &[',', ' ']), "a\\ b\\,c=d");
+        assert_eq!(escape("C:\\temp", &['"']), "C:\\\\temp");
+    }
+
+    #[test]
+    fn formats_a_line() {
+        assert_eq!(
+            line(&measurements(), "vbus", Precision::Seconds),
+            "vbus,device=roof\\,\\ east a\\=b=1,temperature_01=21.5 1600000000"
+        );
+    }
+
+    #[test]
+    fn formats_events_with_their_measurement_and_text() {
+        let mut event = measurements();
+        event.measurement = Some("alert events".to_owned());
+        event.fields.clear();
+        event.text = Some("Collector \"hot\"".to_owned());
+        event.tags.insert("rule".to_owned(), "t > 80".to_owned());
+        assert_eq!(
+            line(&event, "vbus", Precision::Milliseconds),
+            "alert\\ events,device=roof\\,\\ east,rule=t\\ >\\ 80 text=\"Collector \\\"hot\\\"\" \
+             1600000000500"
+        );
+    }
+
+    #[test]
+    fn parses_precisions() {
+        for precision in ["s", "ms", "us", "ns"] {
+            assert_eq!(Precision::parse(precision).unwrap().as_str(), precision);
+        }
+        assert!(Precision::parse("m").is_err());
+    }
+}
@@ -0,0 +1,166 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use color_eyre::{eyre::eyre, Result};
+use rumqttc::{AsyncClient, ConnectionError, Event, MqttOptions, Outgoing, QoS};
+use serde::Deserialize;
+use tokio::{task::JoinHandle, time};
+use tracing::error;
+
+use super::Sink;
+use crate::Measurements;
+
+#[derive(Deserialize, Clone)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default = "default_client_id")]
+    pub client_id: String,
+    #[serde(default = "default_topic_prefix")]
+    pub topic_prefix: String,
+    #[serde(default)]
+    pub qos: u8,
+    #[serde(default)]
+    pub retain: bool,
+}
+
+/// How long a batch may take to publish before the write fails and is retried later.
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn default_port() -> u16 {
+    1883
+}
+
+fn default_client_id() -> String {
+    "vbus2influx".to_owned()
+}
+
+fn default_topic_prefix() -> String {
+    "vbus".to_owned()
+}
+
+fn options(config: &MqttConfig, client_id: &str) -> MqttOptions {
+    let mut options = MqttOptions::new(client_id, &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        options.set_credentials(username, password);
+    }
+    options
+}
+
+/// Publishes a single message over a connection of its own, e.g. an alert, and disconnects
+/// once it was sent.
+pub async fn publish_once(config: &MqttConfig, topic: &str, payload: String) -> Result<()> {
+    // A client ID of its own keeps the sink's connection up
+    let client_id = format!("{}-once", config.client_id);
+    let (client, mut eventloop) = AsyncClient::new(options(config, &client_id), 4);
+    client
+        .publish(topic, QoS::AtLeastOnce, false, payload)
+        .await?;
+    client.disconnect().await?;
+    let disconnected = async {
+        loop {
+            if let Event::Outgoing(Outgoing::Disconnect) = eventloop.poll().await? {
+                return Ok::<_, ConnectionError>(());
+            }
+        }
+    };
+    time::timeout(Duration::from_secs(10), disconnected)
+        .await
+        .map_err(|_| eyre!("Timed out publishing to MQTT."))??;
+    Ok(())
+}
+
+/// Publishes every measurement, one topic per field plus a combined JSON topic,
+/// below a subtopic per device if the source has a device name and per measurement for events.
+pub struct MqttSink {
+    client: AsyncClient,
+    qos: QoS,
+    config: MqttConfig,
+    /// Polls the event loop, stopped with the sink so a reload doesn't leave it connected.
+    eventloop: JoinHandle<()>,
+}
+
+impl MqttSink {
+    pub fn new(config: MqttConfig) -> Result<Self> {
+        let qos = match config.qos {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            2 => QoS::ExactlyOnce,
+            qos => return Err(eyre!("Invalid MQTT QoS `{qos}`.")),
+        };
+
+        let options = options(&config, &config.client_id);
+        let (client, mut eventloop) = AsyncClient::new(options, 64);
+        // The event loop has to be polled for anything to be sent, it reconnects on its own.
+        let eventloop = tokio::spawn(async move {
+            loop {
+                match eventloop.poll().await {
+                    Ok(_) => {}
+                    // All clients are gone
+                    Err(ConnectionError::RequestsDone) => return,
+                    Err(err) => {
+                        error!("Error in MQTT connection: {err}");
+                        tokio::time::sleep(Duration::from_secs(5)).await;
+                    }
+                }
+            }
+        });
+
+        Ok(MqttSink {
+            client,
+            qos,
+            config,
+            eventloop,
+        })
+    }
+
+    /// Queues every message of the points, waiting for room in the request queue.
+    async fn publish(&self, points: &[Measurements]) -> Result<()> {
+        for measurements in points {
+            let mut prefix = match &measurements.device {
+                Some(device) => format!("{}/{device}", self.config.topic_prefix),
+                None => self.config.topic_prefix.clone(),
+            };
+            if let Some(measurement) = &measurements.measurement {
+                prefix = format!("{prefix}/{measurement}");
+            }
+            for (name, value) in &measurements.fields {
+                let topic = format!("{prefix}/{name}");
+                self.client
+                    .publish(topic, self.qos, self.config.retain, value.to_string())
+                    .await?;
+            }
+            let topic = format!("{prefix}/json");
+            let payload = serde_json::to_vec(&measurements)?;
+            self.client
+                .publish(topic, self.qos, self.config.retain, payload)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MqttSink {
+    fn drop(&mut self) {
+        self.eventloop.abort();
+    }
+}
+
+#[async_trait]
+impl Sink for MqttSink {
+    fn name(&self) -> &str {
+        "mqtt"
+    }
+
+    async fn write(&self, points: &[Measurements]) -> Result<()> {
+        // Publishing waits for room in the request queue, which only drains while the broker
+        // is connected
+        time::timeout(PUBLISH_TIMEOUT, self.publish(points))
+            .await
+            .map_err(|_| eyre!("Timed out publishing to MQTT, is the broker reachable?"))?
+    }
+}
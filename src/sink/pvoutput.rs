@@ -0,0 +1,155 @@
+use std::{path::PathBuf, sync::Mutex};
+
+use async_trait::async_trait;
+use color_eyre::{eyre::eyre, Result};
+use reqwest::Client;
+use resol_vbus::chrono::Local;
+use serde::Deserialize;
+
+use super::{PermanentError, Sink};
+use crate::Measurements;
+
+const URL: &str = "https://pvoutput.org/service/r2/addbatchstatus.jsp";
+
+/// Most statuses PVOutput takes in one request.
+const MAX_BATCH_STATUSES: usize = 30;
+
+#[derive(Deserialize, Clone)]
+pub struct PvoutputConfig {
+    pub apikey: String,
+    pub system_id: String,
+    /// Seconds between statuses, the status interval of the system on PVOutput.
+    #[serde(default = "default_interval")]
+    pub interval: i64,
+    /// Lifetime thermal energy in kWh, uploaded as generation. PVOutput derives the daily
+    /// yield from it.
+    #[serde(default = "default_energy_field")]
+    pub energy_field: String,
+    /// Thermal power in kW, uploaded as generation power.
+    #[serde(default = "default_power_field")]
+    pub power_field: String,
+    /// Uploaded as temperature, e.g. the collector's.
+    pub temperature_field: Option<String>,
+    /// Uploaded as the extended value v7, which needs donation mode.
+    pub irradiation_field: Option<String>,
+    /// File measurements are kept in while PVOutput is unreachable, uploaded as statuses when
+    /// it is back.
+    pub buffer_path: Option<PathBuf>,
+}
+
+fn default_interval() -> i64 {
+    300
+}
+
+fn default_energy_field() -> String {
+    "heat_energy_kwh".to_owned()
+}
+
+fn default_power_field() -> String {
+    "heat_power_kw".to_owned()
+}
+
+/// Uploads the solar thermal yield as status records to PVOutput.org, one per interval.
+pub struct PvoutputSink {
+    client: Client,
+    config: PvoutputConfig,
+    /// Interval of the last uploaded status, in intervals since the epoch.
+    last_slot: Mutex<Option<i64>>,
+}
+
+impl PvoutputSink {
+    pub fn new(config: PvoutputConfig) -> Self {
+        PvoutputSink {
+            client: Client::new(),
+            config,
+            last_slot: Mutex::new(None),
+        }
+    }
+
+    /// The status of measurements as `date,time,v1,v2,v3,v4,v5,v6,v7`, leaving out what
+    /// they don't carry. `None` without energy or power.
+    fn status(&self, measurements: &Measurements) -> Option<String> {
+        let field = |name: Option<&String>| {
+            name.and_then(|name| measurements.fields.get(name))
+                .filter(|value| value.is_finite())
+                .copied()
+        };
+        let energy = field(Some(&self.config.energy_field)).map(|kwh| kwh * 1000.0);
+        let power = field(Some(&self.config.power_field)).map(|kw| kw * 1000.0);
+        if energy.is_none() && power.is_none() {
+            return None;
+        }
+        let temperature = field(self.config.temperature_field.as_ref());
+        let irradiation = field(self.config.irradiation_field.as_ref());
+        let format = |value: Option<f64>, precision: usize| {
+            value.map_or_else(String::new, |value| format!("{value:.precision$}"))
+        };
+        // PVOutput expects the local time of the system
+        let time = measurements.time.with_timezone(&Local);
+        Some(format!(
+            "{},{},{},{},,,{},,{}",
+            time.format("%Y%m%d"),
+            time.format("%H:%M"),
+            format(energy, 0),
+            format(power, 0),
+            format(temperature, 1),
+            format(irradiation, 1)
+        ))
+    }
+
+    async fn post(&self, statuses: &[String]) -> Result<()> {
+        let form = [("data", statuses.join(";")), ("c1", "1".to_owned())];
+        let response = self
+            .client
+            .post(URL)
+            .header("X-Pvoutput-Apikey", &self.config.apikey)
+            .header("X-Pvoutput-SystemId", &self.config.system_id)
+            .form(&form)
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let message = format!(
+                "PVOutput answered {status}: {}",
+                response.text().await.unwrap_or_default()
+            );
+            // A wrong key or system, or statuses too old to be added, stay that way
+            return Err(match status.as_u16() {
+                400 | 401 | 403 => PermanentError(message).into(),
+                _ => eyre!(message),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for PvoutputSink {
+    fn name(&self) -> &str {
+        "pvoutput"
+    }
+
+    async fn write(&self, points: &[Measurements]) -> Result<()> {
+        let interval = self.config.interval.max(60);
+        let mut last_slot = *self.last_slot.lock().unwrap();
+        // The first measurements of each interval become its status
+        let mut statuses = Vec::new();
+        for measurements in points.iter().filter(|m| m.measurement.is_none()) {
+            let slot = measurements.time.timestamp().div_euclid(interval);
+            if last_slot.is_some_and(|last_slot| slot <= last_slot) {
+                continue;
+            }
+            if let Some(status) = self.status(measurements) {
+                statuses.push((slot, status));
+                last_slot = Some(slot);
+            }
+        }
+        for batch in statuses.chunks(MAX_BATCH_STATUSES) {
+            let data: Vec<_> = batch.iter().map(|(_, status)| status.clone()).collect();
+            self.post(&data).await?;
+            // Retried batches start after what got through
+            *self.last_slot.lock().unwrap() = batch.last().map(|(slot, _)| *slot);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+use async_trait::async_trait;
+use color_eyre::{eyre::eyre, Result};
+use redis::{aio::MultiplexedConnection, Client, Pipeline};
+use serde::Deserialize;
+use tokio::sync::Mutex;
+use tracing::info;
+
+use super::Sink;
+use crate::Measurements;
+
+#[derive(Deserialize, Clone)]
+pub struct RedisConfig {
+    /// Connection URL, e.g. `redis://localhost/` or `redis://:secret@redis.local:6379/0`.
+    pub url: String,
+    #[serde(default = "default_key_prefix")]
+    pub key_prefix: String,
+    /// Also adds every field to a RedisTimeSeries key, the module has to be loaded.
+    #[serde(default)]
+    pub timeseries: bool,
+    /// Seconds samples are kept in time series created by the sink, forever if not set.
+    pub retention: Option<u64>,
+}
+
+fn default_key_prefix() -> String {
+    "vbus".to_owned()
+}
+
+/// Keeps the latest fields in a hash `<key_prefix>` (`<key_prefix>:<device>` for named
+/// sources) along with their `time`, and optionally adds them to the time series
+/// `<hash>:<field>`.
+pub struct RedisSink {
+    client: Client,
+    config: RedisConfig,
+    /// Connected on the first write and again after a write failed.
+    connection: Mutex<Option<MultiplexedConnection>>,
+}
+
+impl RedisSink {
+    pub fn new(config: RedisConfig) -> Result<Self> {
+        let client =
+            Client::open(config.url.as_str()).map_err(|err| eyre!("Invalid Redis URL: {err}"))?;
+        Ok(RedisSink {
+            client,
+            config,
+            connection: Mutex::new(None),
+        })
+    }
+
+    fn key(&self, measurements: &Measurements) -> String {
+        match &measurements.device {
+            Some(device) => format!("{}:{device}", self.config.key_prefix),
+            None => self.config.key_prefix.clone(),
+        }
+    }
+
+    fn add_samples(&self, pipe: &mut Pipeline, key: &str, measurements: &Measurements) {
+        let time = measurements.time.timestamp_millis();
+        // RedisTimeSeries rejects NaN and infinity
+        let fields = measurements
+            .fields
+            .iter()
+            .filter(|(_, value)| value.is_finite());
+        for (name, value) in fields {
+            let command = pipe
+                .cmd("TS.ADD")
+                .arg(format!("{key}:{name}"))
+                .arg(time)
+                .arg(value);
+            if let Some(retention) = self.config.retention {
+                command.arg("RETENTION").arg(retention * 1000);
+            }
+            // Batches are sent again after a failure, with samples that may already be there
+            command.arg("ON_DUPLICATE").arg("LAST");
+            command.arg("LABELS").arg("field").arg(name);
+            if let Some(device) = &measurements.device {
+                command.arg("device").arg(device);
+            }
+            command.ignore();
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for RedisSink {
+    fn name(&self) -> &str {
+        "redis"
+    }
+
+    async fn write(&self, points: &[Measurements]) -> Result<()> {
+        let mut pipe = redis::pipe();
+        // Events aren't the state of the system
+        for measurements in points.iter().filter(|m| m.measurement.is_none()) {
+            if measurements.fields.is_empty() {
+                continue;
+            }
+            let key = self.key(measurements);
+            let fields: Vec<_> = measurements
+                .fields
+                .iter()
+                .map(|(name, value)| (name.clone(), value.to_string()))
+                .chain([("time".to_owned(), measurements.time.to_rfc3339())])
+                .collect();
+            pipe.cmd("HSET").arg(&key).arg(fields).ignore();
+            if self.config.timeseries {
+                self.add_samples(&mut pipe, &key, measurements);
+            }
+        }
+
+        let mut connection = self.connection.lock().await;
+        let mut connected = match connection.take() {
+            Some(connected) => connected,
+            None => {
+                let connected = self.client.get_multiplexed_tokio_connection().await?;
+                info!("Connected to Redis.");
+                connected
+            }
+        };
+        // A broken connection is replaced with the next write
+        pipe.query_async::<_, ()>(&mut connected).await?;
+        *connection = Some(connected);
+        Ok(())
+    }
+}
@@ -0,0 +1,125 @@
+use async_trait::async_trait;
+use color_eyre::{eyre::eyre, Result};
+use prost::Message;
+use reqwest::{header, Client};
+use serde::Deserialize;
+
+use super::Sink;
+use crate::{metric_name, Measurements};
+
+#[derive(Deserialize, Clone)]
+pub struct RemoteWriteConfig {
+    /// Remote write endpoint, e.g. `http://mimir:9009/api/v1/push`.
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+/// Pushes samples using the Prometheus remote write protocol, understood by Prometheus
+/// itself, Mimir, Thanos, VictoriaMetrics and others.
+pub struct RemoteWriteSink {
+    client: Client,
+    config: RemoteWriteConfig,
+}
+
+impl RemoteWriteSink {
+    pub fn new(config: RemoteWriteConfig) -> Self {
+        RemoteWriteSink {
+            client: Client::new(),
+            config,
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for RemoteWriteSink {
+    fn name(&self) -> &str {
+        "remote_write"
+    }
+
+    async fn write(&self, points: &[Measurements]) -> Result<()> {
+        let mut request = WriteRequest::default();
+        // Events are no samples of a metric
+        for measurements in points.iter().filter(|m| m.measurement.is_none()) {
+            let timestamp = measurements.time.timestamp_millis();
+            for (name, &value) in &measurements.fields {
+                let (metric, sensor) = metric_name(name);
+                let mut labels = vec![label("__name__", &metric)];
+                if let Some(device) = &measurements.device {
+                    labels.push(label("device", device));
+                }
+                if let Some(sensor) = sensor {
+                    labels.push(label("sensor", sensor));
+                }
+                for (tag, value) in &measurements.tags {
+                    labels.push(label(tag, value));
+                }
+                // Labels have to be sorted by name
+                labels.sort_by(|a, b| a.name.cmp(&b.name));
+                request.timeseries.push(TimeSeries {
+                    labels,
+                    samples: vec![Sample { value, timestamp }],
+                });
+            }
+        }
+        let body = snap::raw::Encoder::new().compress_vec(&request.encode_to_vec())?;
+
+        let mut request = self
+            .client
+            .post(&self.config.url)
+            .header(header::CONTENT_ENCODING, "snappy")
+            .header(header::CONTENT_TYPE, "application/x-protobuf")
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+            .body(body);
+        if let Some(username) = &self.config.username {
+            request = request.basic_auth(username, self.config.password.as_ref());
+        }
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let message = response.text().await.unwrap_or_default();
+            return Err(eyre!("Remote write endpoint answered {status}: {message}"));
+        }
+        Ok(())
+    }
+}
+
+fn label(name: &str, value: &str) -> Label {
+    Label {
+        name: name.to_owned(),
+        value: value.to_owned(),
+    }
+}
+
+// The subset of the remote write protobuf messages (`prompb`) needed for samples
+
+#[derive(Clone, PartialEq, Message)]
+struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Label {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Sample {
+    #[prost(double, tag = "1")]
+    value: f64,
+    /// Unix time in milliseconds.
+    #[prost(int64, tag = "2")]
+    timestamp: i64,
+}
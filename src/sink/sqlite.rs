@@ -0,0 +1,141 @@
+use std::{
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use color_eyre::{eyre::eyre, Result};
+use resol_vbus::chrono::{self, SecondsFormat, Utc};
+use rusqlite::{params_from_iter, types::Value, Connection};
+use serde::Deserialize;
+use tokio::task;
+use tracing::info;
+
+use super::{quote_identifier, Sink};
+use crate::Measurements;
+
+/// How often rows older than the retention are deleted.
+const RETENTION_INTERVAL: Duration = Duration::from_secs(3600);
+
+#[derive(Deserialize, Clone)]
+pub struct SqliteConfig {
+    /// Database file, created if missing.
+    pub path: PathBuf,
+    /// Days rows are kept, forever if not set.
+    pub retention_days: Option<u32>,
+}
+
+/// Inserts every measurement as a row into the `measurements` table of a SQLite database,
+/// with a column per field.
+pub struct SqliteSink {
+    retention_days: Option<u32>,
+    /// Field columns, after `time` and `device`.
+    columns: Vec<String>,
+    connection: Mutex<Connection>,
+    last_cleanup: Mutex<Option<Instant>>,
+}
+
+impl SqliteSink {
+    pub fn new(config: SqliteConfig, columns: Vec<String>) -> Result<Self> {
+        let connection = Connection::open(&config.path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS measurements (time TEXT NOT NULL, device TEXT);
+             CREATE INDEX IF NOT EXISTS measurements_time ON measurements (time);",
+        )?;
+        // Fields mapped since the table was created get a column of their own
+        let existing = connection
+            .prepare("SELECT name FROM pragma_table_info('measurements')")?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        for column in columns.iter().filter(|column| !existing.contains(column)) {
+            connection.execute(
+                &format!(
+                    "ALTER TABLE measurements ADD COLUMN {} REAL",
+                    quote_identifier(column)
+                ),
+                [],
+            )?;
+        }
+        info!("Writing to SQLite database `{}`.", config.path.display());
+        Ok(SqliteSink {
+            retention_days: config.retention_days,
+            columns,
+            connection: Mutex::new(connection),
+            last_cleanup: Mutex::new(None),
+        })
+    }
+
+    fn insert(&self, points: &[Measurements]) -> Result<()> {
+        let mut connection = self
+            .connection
+            .lock()
+            .map_err(|_| eyre!("SQLite connection lock poisoned."))?;
+        let names: Vec<_> = ["time", "device"]
+            .into_iter()
+            .map(String::from)
+            .chain(self.columns.iter().map(|column| quote_identifier(column)))
+            .collect();
+        let placeholders = vec!["?"; names.len()].join(", ");
+        let sql = format!(
+            "INSERT INTO measurements ({}) VALUES ({placeholders})",
+            names.join(", ")
+        );
+
+        let transaction = connection.transaction()?;
+        {
+            let mut statement = transaction.prepare_cached(&sql)?;
+            // Events have other columns, they don't fit into the table
+            for measurements in points.iter().filter(|m| m.measurement.is_none()) {
+                let time = measurements
+                    .time
+                    .to_rfc3339_opts(SecondsFormat::Millis, true);
+                let mut values = vec![Value::from(time), Value::from(measurements.device.clone())];
+                values.extend(
+                    self.columns
+                        .iter()
+                        .map(|column| Value::from(measurements.fields.get(column).copied())),
+                );
+                statement.execute(params_from_iter(values))?;
+            }
+        }
+        transaction.commit()?;
+
+        self.apply_retention(&connection)
+    }
+
+    /// Deletes rows older than the retention, at most once per `RETENTION_INTERVAL`.
+    fn apply_retention(&self, connection: &Connection) -> Result<()> {
+        let Some(days) = self.retention_days else {
+            return Ok(());
+        };
+        let mut last_cleanup = self
+            .last_cleanup
+            .lock()
+            .map_err(|_| eyre!("SQLite cleanup lock poisoned."))?;
+        if last_cleanup.is_some_and(|last_cleanup| last_cleanup.elapsed() < RETENTION_INTERVAL) {
+            return Ok(());
+        }
+        *last_cleanup = Some(Instant::now());
+
+        let cutoff = (Utc::now() - chrono::Duration::days(i64::from(days)))
+            .to_rfc3339_opts(SecondsFormat::Millis, true);
+        let deleted = connection.execute("DELETE FROM measurements WHERE time < ?", [cutoff])?;
+        if deleted > 0 {
+            info!(deleted, "Deleted rows older than {days} days from SQLite.");
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for SqliteSink {
+    fn name(&self) -> &str {
+        "sqlite"
+    }
+
+    async fn write(&self, points: &[Measurements]) -> Result<()> {
+        // Writing may block on slow SD cards
+        task::block_in_place(|| self.insert(points))
+    }
+}
@@ -0,0 +1,62 @@
+use std::io::Write;
+
+use async_trait::async_trait;
+use color_eyre::Result;
+use serde::Deserialize;
+
+use super::{
+    line_protocol::{self, Precision},
+    Sink,
+};
+use crate::{
+    routes::{self, FieldTags, RouteConfig},
+    Measurements,
+};
+
+#[derive(Deserialize, Clone)]
+pub struct StdoutConfig {
+    /// `s`, `ms`, `us` or `ns`, Telegraf expects `ns` unless `influx_timestamp_precision` is set.
+    #[serde(default = "default_precision")]
+    pub precision: String,
+}
+
+fn default_precision() -> String {
+    "ns".to_owned()
+}
+
+/// Prints line protocol to stdout, e.g. for Telegraf's `inputs.execd` to pick up. Logs go to
+/// stderr while this sink is configured.
+pub struct StdoutSink {
+    pub measurement: String,
+    pub routes: Vec<RouteConfig>,
+    pub field_tags: FieldTags,
+    pub precision: Precision,
+}
+
+#[async_trait]
+impl Sink for StdoutSink {
+    fn name(&self) -> &str {
+        "stdout"
+    }
+
+    async fn write(&self, points: &[Measurements]) -> Result<()> {
+        let mut lines = String::new();
+        for measurements in points {
+            // Buckets don't exist here, routes only change the measurement and tags
+            for (_, part) in routes::split(&self.routes, &self.field_tags, measurements) {
+                if part.text.is_some() || part.fields.values().any(|v| v.is_finite()) {
+                    lines.push_str(&line_protocol::line(
+                        &part,
+                        &self.measurement,
+                        self.precision,
+                    ));
+                    lines.push('\n');
+                }
+            }
+        }
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(lines.as_bytes())?;
+        stdout.flush()?;
+        Ok(())
+    }
+}
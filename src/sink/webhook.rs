@@ -0,0 +1,106 @@
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use color_eyre::{eyre::eyre, Result};
+use reqwest::{header, Client};
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::Sink;
+use crate::Measurements;
+
+#[derive(Deserialize, Clone)]
+pub struct WebhookConfig {
+    /// Name used in logs and metrics, has to differ between webhooks.
+    #[serde(default = "default_name")]
+    pub name: String,
+    pub url: String,
+    /// Sent with every request, e.g. `Authorization = "Bearer ..."`.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Body sent for each point instead of the JSON array of all points, with `{{name}}`
+    /// placeholders for the time, device, tags and fields.
+    pub template: Option<String>,
+}
+
+fn default_name() -> String {
+    "webhook".to_owned()
+}
+
+/// POSTs measurements as JSON, e.g. to Node-RED, n8n or a custom endpoint.
+pub struct WebhookSink {
+    client: Client,
+    config: WebhookConfig,
+}
+
+impl WebhookSink {
+    pub fn new(config: WebhookConfig) -> Self {
+        WebhookSink {
+            client: Client::new(),
+            config,
+        }
+    }
+
+    async fn post(&self, body: String) -> Result<()> {
+        let mut request = self
+            .client
+            .post(&self.config.url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body);
+        for (name, value) in &self.config.headers {
+            request = request.header(name, value);
+        }
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let message = response.text().await.unwrap_or_default();
+            return Err(eyre!("Webhook answered {status}: {message}"));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for WebhookSink {
+    fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    async fn write(&self, points: &[Measurements]) -> Result<()> {
+        match &self.config.template {
+            Some(template) => {
+                for measurements in points {
+                    self.post(render(template, measurements)?).await?;
+                }
+                Ok(())
+            }
+            None => self.post(serde_json::to_string(points)?).await,
+        }
+    }
+}
+
+/// Replaces every `{{name}}` in the template by the JSON value of that key of the point
+/// (`time`, `device`, `measurement`, `text`, a tag or a field), `null` if it has none. Strings
+/// are inserted with their quotes, so `{"temp": {{temperature_01}}, "at": {{time}}}` is valid
+/// JSON.
+fn render(template: &str, measurements: &Measurements) -> Result<String> {
+    let Value::Object(mut values) = serde_json::to_value(measurements)? else {
+        return Err(eyre!("Measurements aren't serialized as an object."));
+    };
+    if let Some(Value::Object(tags)) = values.remove("tags") {
+        values.extend(tags);
+    }
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}") else {
+            break;
+        };
+        rendered.push_str(&rest[..start]);
+        let name = rest[start + 2..start + end].trim();
+        rendered.push_str(&values.get(name).unwrap_or(&Value::Null).to_string());
+        rest = &rest[start + end + 2..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
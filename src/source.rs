@@ -0,0 +1,387 @@
+#[cfg(all(not(feature = "rppal"), not(feature = "generic-serial")))]
+compile_error!("Either the `rppal` or the `generic-serial` feature has to be enabled.");
+
+mod dlx;
+mod failover;
+mod frame_check;
+#[cfg(not(feature = "generic-serial"))]
+mod rppal_uart;
+#[cfg(feature = "generic-serial")]
+mod serial_uart;
+mod simulator;
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufReader, ErrorKind, Read, Write},
+    net::TcpStream,
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use color_eyre::{eyre::eyre, Result};
+use resol_vbus::{Data, Datagram, LiveDataReader, LiveDataRecordingReader, TcpClientHandshake};
+use serde::Deserialize;
+
+use self::frame_check::FrameChecker;
+#[cfg(not(feature = "generic-serial"))]
+use self::rppal_uart::{open_uart, power_cycle};
+#[cfg(feature = "generic-serial")]
+use self::serial_uart::{open_uart, power_cycle};
+pub use self::{dlx::DlxSource, failover::FailoverSource, simulator::SimulatorSource};
+use crate::{
+    parameters::encode_datagram,
+    recorder::{Recorder, Tee},
+};
+
+/// Something VBus data can be read from.
+pub trait Source {
+    /// Opens a new reader for the data, reads fail once nothing arrived for `read_timeout`.
+    ///
+    /// Live sources pass the raw bytes they read to the `recorder`, if any.
+    fn open(
+        &self,
+        read_timeout: Duration,
+        recorder: Option<Recorder>,
+    ) -> Result<Box<dyn DataReader + Send>>;
+
+    /// Whether the data carries its own meaningful timestamps (e.g. from a recording).
+    fn has_timestamps(&self) -> bool {
+        false
+    }
+
+    /// Whether the source delivers data as it happens, so reopening it after an error
+    /// makes sense.
+    fn is_live(&self) -> bool {
+        true
+    }
+
+    /// Cuts the power of the hardware behind the source for a moment, if it can, before it
+    /// is reopened after too many invalid frames.
+    fn power_cycle(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Yields decoded VBus data, regardless of where it comes from.
+pub trait DataReader {
+    /// Reads the next piece of data, `None` once the source is exhausted.
+    fn read_data(&mut self) -> Result<Option<Data>>;
+
+    /// Sends a datagram to the bus, e.g. a parameter request.
+    fn send_datagram(&mut self, _datagram: &Datagram) -> Result<()> {
+        Err(eyre!("The source can't send to the bus."))
+    }
+
+    /// Whether reading failed because too many frames in a row were invalid.
+    fn invalid_frames_exceeded(&self) -> bool {
+        false
+    }
+}
+
+/// A live stream that can be written to as well.
+struct Duplex<R: Read> {
+    reader: LiveDataReader<FrameChecker<R>>,
+    writer: Box<dyn Write + Send>,
+    invalid_frames_exceeded: Arc<AtomicBool>,
+}
+
+impl<R: Read> DataReader for Duplex<R> {
+    fn read_data(&mut self) -> Result<Option<Data>> {
+        Ok(self.reader.read_data()?)
+    }
+
+    fn invalid_frames_exceeded(&self) -> bool {
+        self.invalid_frames_exceeded.load(Ordering::Relaxed)
+    }
+
+    fn send_datagram(&mut self, datagram: &Datagram) -> Result<()> {
+        self.writer.write_all(&encode_datagram(datagram))?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+impl<R: Read> DataReader for LiveDataRecordingReader<R> {
+    fn read_data(&mut self) -> Result<Option<Data>> {
+        Ok(LiveDataRecordingReader::read_data(self)?)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SourceConfig {
+    Uart(UartSource),
+    Usb(UsbSource),
+    Tcp(TcpSource),
+    Replay(ReplaySource),
+    /// The KM2 offers the same web API as the DL2 and DL3.
+    #[serde(alias = "km2")]
+    Dlx(DlxSource),
+    Failover(FailoverSource),
+    Simulator(SimulatorSource),
+}
+
+/// A source together with the name of the controller behind it.
+#[derive(Deserialize, Clone)]
+pub struct DeviceSource {
+    /// Tags all points read from this source, needed to tell several controllers apart.
+    pub device: Option<String>,
+    #[serde(flatten)]
+    pub source: SourceConfig,
+}
+
+impl SourceConfig {
+    pub fn source(&self) -> &dyn Source {
+        match self {
+            SourceConfig::Uart(source) => source,
+            SourceConfig::Usb(source) => source,
+            SourceConfig::Tcp(source) => source,
+            SourceConfig::Replay(source) => source,
+            SourceConfig::Dlx(source) => source,
+            SourceConfig::Failover(source) => source,
+            SourceConfig::Simulator(source) => source,
+        }
+    }
+
+    /// Checks what can be checked without reading, e.g. permissions of the UART's device
+    /// node. Network sources aren't contacted.
+    pub fn check(&self) -> Result<()> {
+        match self {
+            SourceConfig::Uart(source) => {
+                check_device(&source.path, "is the UART enabled (`enable_uart=1`)?")
+            }
+            SourceConfig::Usb(source) => check_device(
+                &source.path,
+                "is the adapter plugged in (see `ls /dev/serial/by-id`)?",
+            ),
+            SourceConfig::Replay(source) => File::open(&source.path)
+                .map(|_| ())
+                .map_err(|err| eyre!("Can't open `{}`: {err}", source.path.display())),
+            SourceConfig::Failover(source) => {
+                source.primary.check()?;
+                source.secondary.check()
+            }
+            SourceConfig::Tcp(_) | SourceConfig::Dlx(_) | SourceConfig::Simulator(_) => Ok(()),
+        }
+    }
+}
+
+/// VBus connected to a local UART, e.g. via a level shifter on the Pi's GPIO header or a
+/// USB serial adapter with the `generic-serial` feature.
+#[derive(Deserialize, Clone)]
+pub struct UartSource {
+    pub path: PathBuf,
+    /// Only needed for adapters that don't speak VBus's 9600 baud 8N1.
+    #[serde(default = "default_baud")]
+    pub baud: u32,
+    #[serde(default)]
+    pub parity: UartParity,
+    #[serde(default = "default_stop_bits")]
+    pub stop_bits: u8,
+    /// Frames in a row with a wrong checksum after which the UART is reopened, never if not
+    /// set.
+    pub max_invalid_frames: Option<NonZeroU32>,
+    /// BCM number of a pin switching the adapter's power, turned off for a moment before the
+    /// UART is reopened for invalid frames. Needs the `rppal` feature.
+    pub power_pin: Option<u8>,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum UartParity {
+    #[default]
+    None,
+    Even,
+    Odd,
+}
+
+/// Line settings of a serial port, always with 8 data bits.
+#[derive(Clone, Copy)]
+pub struct UartSettings {
+    pub baud: u32,
+    pub parity: UartParity,
+    /// `1` or `2`.
+    pub stop_bits: u8,
+}
+
+impl UartSettings {
+    /// What VBus uses: 9600 baud 8N1.
+    pub const VBUS: UartSettings = UartSettings {
+        baud: 9600,
+        parity: UartParity::None,
+        stop_bits: 1,
+    };
+}
+
+pub fn default_baud() -> u32 {
+    UartSettings::VBUS.baud
+}
+
+pub fn default_stop_bits() -> u8 {
+    UartSettings::VBUS.stop_bits
+}
+
+impl Source for UartSource {
+    fn open(
+        &self,
+        read_timeout: Duration,
+        recorder: Option<Recorder>,
+    ) -> Result<Box<dyn DataReader + Send>> {
+        let settings = UartSettings {
+            baud: self.baud,
+            parity: self.parity,
+            stop_bits: self.stop_bits,
+        };
+        let (uart, writer) = open_uart(&self.path, settings, read_timeout)?;
+        Ok(live_data_reader(
+            uart,
+            writer,
+            recorder,
+            self.max_invalid_frames,
+        ))
+    }
+
+    fn power_cycle(&self) -> Result<()> {
+        match self.power_pin {
+            Some(pin) => power_cycle(pin),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A RESOL VBus/USB adapter, which shows up as a USB CDC serial device.
+#[derive(Deserialize, Clone)]
+pub struct UsbSource {
+    /// Better a stable name from `/dev/serial/by-id`, as `ttyACM` numbers can change when the
+    /// adapter is plugged in again.
+    #[serde(default = "default_usb_path")]
+    pub path: PathBuf,
+}
+
+fn default_usb_path() -> PathBuf {
+    PathBuf::from("/dev/ttyACM0")
+}
+
+impl Source for UsbSource {
+    fn open(
+        &self,
+        read_timeout: Duration,
+        recorder: Option<Recorder>,
+    ) -> Result<Box<dyn DataReader + Send>> {
+        // The adapter ignores the line settings, it delivers the bus's bytes as they are
+        let (uart, writer) = open_uart(&self.path, UartSettings::VBUS, read_timeout)?;
+        Ok(live_data_reader(uart, writer, recorder, None))
+    }
+}
+
+/// VBus reached over the network, e.g. through a VBus/LAN adapter or a DL2/DL3.
+#[derive(Deserialize, Clone)]
+pub struct TcpSource {
+    pub host: String,
+    #[serde(default = "default_tcp_port")]
+    pub port: u16,
+    #[serde(default = "default_tcp_password")]
+    pub password: String,
+}
+
+fn default_tcp_port() -> u16 {
+    7053
+}
+
+fn default_tcp_password() -> String {
+    "vbus".to_owned()
+}
+
+impl Source for TcpSource {
+    fn open(
+        &self,
+        read_timeout: Duration,
+        recorder: Option<Recorder>,
+    ) -> Result<Box<dyn DataReader + Send>> {
+        let stream = TcpStream::connect((self.host.as_str(), self.port))?;
+        stream.set_read_timeout(Some(read_timeout))?;
+        let mut handshake = TcpClientHandshake::start(stream)?;
+        handshake.send_pass_command(&self.password)?;
+        let stream = handshake.send_data_command()?;
+        let writer = stream.try_clone()?;
+        Ok(live_data_reader(stream, writer, recorder, None))
+    }
+}
+
+/// A previously recorded `.vbus` file, used to backfill historical data.
+#[derive(Deserialize, Clone)]
+pub struct ReplaySource {
+    pub path: PathBuf,
+}
+
+impl Source for ReplaySource {
+    fn open(
+        &self,
+        _read_timeout: Duration,
+        _recorder: Option<Recorder>,
+    ) -> Result<Box<dyn DataReader + Send>> {
+        let file = BufReader::new(File::open(&self.path)?);
+        Ok(Box::new(LiveDataRecordingReader::new(file)))
+    }
+
+    fn has_timestamps(&self) -> bool {
+        true
+    }
+
+    fn is_live(&self) -> bool {
+        false
+    }
+}
+
+/// Checks that a serial device node exists and may be opened, with a `hint` if it doesn't.
+fn check_device(path: &Path, hint: &str) -> Result<()> {
+    if !path.exists() {
+        return Err(eyre!("`{}` doesn't exist, {hint}", path.display()));
+    }
+    match OpenOptions::new().read(true).write(true).open(path) {
+        Ok(_) => Ok(()),
+        Err(err) if err.kind() == ErrorKind::PermissionDenied => Err(eyre!(
+            "No permission to open `{}`, add the user to the `dialout` group.",
+            path.display()
+        )),
+        Err(err) => Err(eyre!("Can't open `{}`: {err}", path.display())),
+    }
+}
+
+/// Decodes a live byte stream, recording it on the way if requested. Reads fail once
+/// `max_invalid_frames` frames in a row were invalid.
+fn live_data_reader<R: Read + Send + 'static>(
+    stream: R,
+    writer: impl Write + Send + 'static,
+    recorder: Option<Recorder>,
+    max_invalid_frames: Option<NonZeroU32>,
+) -> Box<dyn DataReader + Send> {
+    let writer = Box::new(writer);
+    match recorder {
+        Some(recorder) => {
+            let stream = Tee {
+                inner: stream,
+                recorder,
+            };
+            let (checker, invalid_frames_exceeded) = FrameChecker::new(stream, max_invalid_frames);
+            Box::new(Duplex {
+                reader: LiveDataReader::new(0, checker),
+                writer,
+                invalid_frames_exceeded,
+            })
+        }
+        None => {
+            let (checker, invalid_frames_exceeded) = FrameChecker::new(stream, max_invalid_frames);
+            Box::new(Duplex {
+                reader: LiveDataReader::new(0, checker),
+                writer,
+                invalid_frames_exceeded,
+            })
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use std::{
+    io::Cursor,
+    thread,
+    time::{Duration, Instant},
+};
+
+use color_eyre::{eyre::eyre, Result};
+use reqwest::blocking::Client;
+use resol_vbus::{Data, LiveDataRecordingReader};
+use serde::Deserialize;
+use tracing::debug;
+
+use super::{DataReader, Source};
+use crate::recorder::Recorder;
+
+/// A RESOL DL2 or DL3 datalogger on the bus, whose live data is polled over HTTP.
+#[derive(Deserialize, Clone)]
+pub struct DlxSource {
+    /// Base URL of the logger, e.g. `http://192.168.1.60`.
+    pub url: String,
+    #[serde(default = "default_username")]
+    pub username: String,
+    #[serde(default = "default_password")]
+    pub password: String,
+    /// Seconds between polls.
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+}
+
+fn default_username() -> String {
+    "admin".to_owned()
+}
+
+fn default_password() -> String {
+    "admin".to_owned()
+}
+
+fn default_interval() -> u64 {
+    30
+}
+
+impl Source for DlxSource {
+    fn open(
+        &self,
+        read_timeout: Duration,
+        _recorder: Option<Recorder>,
+    ) -> Result<Box<dyn DataReader + Send>> {
+        let client = Client::builder().timeout(read_timeout).build()?;
+        Ok(Box::new(DlxReader {
+            client,
+            source: self.clone(),
+            current: None,
+            next_poll: Instant::now(),
+        }))
+    }
+}
+
+struct DlxReader {
+    client: Client,
+    source: DlxSource,
+    /// Data of the last poll not read yet.
+    current: Option<LiveDataRecordingReader<Cursor<Vec<u8>>>>,
+    next_poll: Instant,
+}
+
+impl DlxReader {
+    /// Downloads the current packets of all controllers in the VBus recording format.
+    fn poll(&mut self) -> Result<()> {
+        let url = format!(
+            "{}/dlx/download/live",
+            self.source.url.trim_end_matches('/')
+        );
+        let response = self
+            .client
+            .get(url)
+            .query(&[
+                ("sessionAuthUsername", self.source.username.as_str()),
+                ("sessionAuthPassword", self.source.password.as_str()),
+                ("source", "current"),
+                ("outputType", "vbus"),
+            ])
+            .send()?;
+        if !response.status().is_success() {
+            return Err(eyre!("Datalogger answered {}", response.status()));
+        }
+        let bytes = response.bytes()?.to_vec();
+        debug!(bytes = bytes.len(), "Polled datalogger");
+        self.current = Some(LiveDataRecordingReader::new(Cursor::new(bytes)));
+        self.next_poll = Instant::now() + Duration::from_secs(self.source.interval);
+        Ok(())
+    }
+}
+
+impl DataReader for DlxReader {
+    fn read_data(&mut self) -> Result<Option<Data>> {
+        loop {
+            if let Some(current) = &mut self.current {
+                if let Some(data) = current.read_data()? {
+                    return Ok(Some(data));
+                }
+                self.current = None;
+            }
+            thread::sleep(self.next_poll.saturating_duration_since(Instant::now()));
+            self.poll()?;
+        }
+    }
+}
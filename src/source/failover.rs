@@ -0,0 +1,178 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use color_eyre::{eyre::eyre, Report, Result};
+use resol_vbus::{Data, Datagram};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use super::{DataReader, Source, SourceConfig};
+use crate::recorder::Recorder;
+
+/// Delay between attempts to reopen the primary source before failing over.
+const REOPEN_DELAY: Duration = Duration::from_secs(1);
+
+/// Reads from `primary`, switching to `secondary` while the primary delivers no valid
+/// packets, e.g. a UART with a VBus/LAN adapter as fallback.
+#[derive(Deserialize, Clone)]
+pub struct FailoverSource {
+    pub primary: Box<SourceConfig>,
+    pub secondary: Box<SourceConfig>,
+    /// Seconds without a valid packet from the primary after which the secondary is used.
+    #[serde(default = "default_failover_after")]
+    pub failover_after: u64,
+    /// Seconds after which the primary is tried again.
+    #[serde(default = "default_retry_primary_after")]
+    pub retry_primary_after: u64,
+}
+
+fn default_failover_after() -> u64 {
+    60
+}
+
+fn default_retry_primary_after() -> u64 {
+    600
+}
+
+impl Source for FailoverSource {
+    fn open(
+        &self,
+        read_timeout: Duration,
+        recorder: Option<Recorder>,
+    ) -> Result<Box<dyn DataReader + Send>> {
+        let primary = self
+            .primary
+            .source()
+            .open(read_timeout, recorder.as_ref().map(Recorder::another));
+        let (reader, on_primary) = match primary {
+            Ok(reader) => (reader, true),
+            Err(err) => {
+                warn!("Error while opening the primary source, using the secondary one: {err}");
+                let reader = self
+                    .secondary
+                    .source()
+                    .open(read_timeout, recorder.as_ref().map(Recorder::another))?;
+                (reader, false)
+            }
+        };
+        Ok(Box::new(FailoverReader {
+            source: self.clone(),
+            read_timeout,
+            recorder,
+            reader,
+            on_primary,
+            last_packet: Instant::now(),
+            opened: Instant::now(),
+        }))
+    }
+
+    fn has_timestamps(&self) -> bool {
+        self.primary.source().has_timestamps()
+    }
+
+    fn is_live(&self) -> bool {
+        self.primary.source().is_live()
+    }
+}
+
+struct FailoverReader {
+    source: FailoverSource,
+    read_timeout: Duration,
+    recorder: Option<Recorder>,
+    reader: Box<dyn DataReader + Send>,
+    on_primary: bool,
+    /// When the active source last delivered a valid packet, or was opened.
+    last_packet: Instant,
+    /// When the active source was opened.
+    opened: Instant,
+}
+
+impl FailoverReader {
+    fn name(primary: bool) -> &'static str {
+        if primary {
+            "primary"
+        } else {
+            "secondary"
+        }
+    }
+
+    /// Opens the primary or secondary source and reads from it from now on.
+    fn switch(&mut self, primary: bool) -> Result<()> {
+        let source = if primary {
+            &self.source.primary
+        } else {
+            &self.source.secondary
+        };
+        let recorder = self.recorder.as_ref().map(Recorder::another);
+        self.reader = source.source().open(self.read_timeout, recorder)?;
+        if primary != self.on_primary {
+            info!(
+                "Switched from the {} to the {} source.",
+                Self::name(self.on_primary),
+                Self::name(primary)
+            );
+        }
+        self.on_primary = primary;
+        self.last_packet = Instant::now();
+        self.opened = Instant::now();
+        Ok(())
+    }
+
+    /// Reacts to the active source failing, `Err` once neither source can be read.
+    fn handle_failure(&mut self, err: &Report) -> Result<()> {
+        let failover_after = Duration::from_secs(self.source.failover_after);
+        if self.on_primary && self.last_packet.elapsed() < failover_after {
+            warn!("Error while reading the primary source, reopening it: {err}");
+            thread::sleep(REOPEN_DELAY);
+            if self.switch(true).is_ok() {
+                return Ok(());
+            }
+        }
+        let fallback = !self.on_primary;
+        warn!(
+            "Error while reading the {} source, switching to the {} source: {err}",
+            Self::name(self.on_primary),
+            Self::name(fallback),
+        );
+        self.switch(fallback)
+            .map_err(|err| eyre!("Neither source can be read: {err}"))
+    }
+}
+
+impl DataReader for FailoverReader {
+    fn read_data(&mut self) -> Result<Option<Data>> {
+        loop {
+            let failover_after = Duration::from_secs(self.source.failover_after);
+            if self.on_primary && self.last_packet.elapsed() >= failover_after {
+                // Data arrives, but no valid packets
+                let err = eyre!("No valid packet within {failover_after:?}.");
+                self.handle_failure(&err)?;
+            }
+            let retry_primary_after = Duration::from_secs(self.source.retry_primary_after);
+            if !self.on_primary && self.opened.elapsed() >= retry_primary_after {
+                info!("Trying the primary source again.");
+                if let Err(err) = self.switch(true) {
+                    warn!("Error while opening the primary source: {err}");
+                    self.opened = Instant::now();
+                }
+            }
+
+            match self.reader.read_data() {
+                Ok(Some(data)) => {
+                    if matches!(data, Data::Packet(_)) {
+                        self.last_packet = Instant::now();
+                    }
+                    return Ok(Some(data));
+                }
+                Ok(None) => return Ok(None),
+                Err(err) => self.handle_failure(&err)?,
+            }
+        }
+    }
+
+    fn send_datagram(&mut self, datagram: &Datagram) -> Result<()> {
+        self.reader.send_datagram(datagram)
+    }
+}
@@ -0,0 +1,201 @@
+use std::{
+    io::{self, Read},
+    num::NonZeroU32,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+const SYNC_BYTE: u8 = 0xAA;
+
+/// Checks the frames of a live byte stream on their way to the decoder, which silently skips
+/// invalid ones. Fails the read once `max_invalid` frames in a row had a wrong checksum or
+/// were cut short, e.g. because a loose wire turned the bus into noise.
+pub struct FrameChecker<R> {
+    inner: R,
+    /// Checks nothing if not set.
+    max_invalid: Option<NonZeroU32>,
+    /// Bytes of the current frame, `None` until the next sync byte once it was checked.
+    frame: Option<Vec<u8>>,
+    consecutive_invalid: u32,
+    exceeded: Arc<AtomicBool>,
+}
+
+impl<R: Read> FrameChecker<R> {
+    /// Returns the checker together with a flag set once reading failed for too many invalid
+    /// frames.
+    pub fn new(inner: R, max_invalid: Option<NonZeroU32>) -> (Self, Arc<AtomicBool>) {
+        let exceeded = Arc::new(AtomicBool::new(false));
+        let checker = FrameChecker {
+            inner,
+            max_invalid,
+            frame: None,
+            consecutive_invalid: 0,
+            exceeded: Arc::clone(&exceeded),
+        };
+        (checker, exceeded)
+    }
+
+    fn push(&mut self, byte: u8) {
+        if byte == SYNC_BYTE {
+            // The previous frame ended before it was complete
+            if self.frame.is_some() {
+                self.finish(false);
+            }
+            self.frame = Some(vec![byte]);
+            return;
+        }
+        let Some(frame) = &mut self.frame else {
+            return;
+        };
+        // Everything but the sync byte is sent with the high bit cleared
+        if byte & 0x80 != 0 {
+            self.frame = None;
+            self.finish(false);
+            return;
+        }
+        frame.push(byte);
+        if let Some(valid) = check(frame) {
+            self.frame = None;
+            self.finish(valid);
+        }
+    }
+
+    fn finish(&mut self, valid: bool) {
+        if valid {
+            self.consecutive_invalid = 0;
+        } else {
+            self.consecutive_invalid += 1;
+        }
+    }
+}
+
+/// Whether a frame has valid checksums, `None` while it is incomplete. Frames of unknown
+/// protocol versions count as valid.
+fn check(frame: &[u8]) -> Option<bool> {
+    let &version = frame.get(5)?;
+    match version {
+        // Packet: 10 header bytes followed by frames of 4 data bytes, septett and checksum
+        0x10 => {
+            let &frame_count = frame.get(8)?;
+            let len = 10 + 6 * usize::from(frame_count);
+            if frame.len() < len {
+                return None;
+            }
+            Some(checksum_ok(&frame[1..10]) && frame[10..len].chunks(6).all(checksum_ok))
+        }
+        // Datagram
+        0x20 => (frame.len() >= 16).then(|| checksum_ok(&frame[1..16])),
+        // Telegram: 8 header bytes followed by frames of 7 data bytes, septett and checksum
+        0x30 | 0x31 => {
+            let &command = frame.get(6)?;
+            let len = 8 + 9 * usize::from((command >> 5) & 0x03);
+            if frame.len() < len {
+                return None;
+            }
+            Some(checksum_ok(&frame[1..8]) && frame[8..len].chunks(9).all(checksum_ok))
+        }
+        _ => Some(true),
+    }
+}
+
+/// Whether the last byte is the VBus checksum of the ones before it.
+fn checksum_ok(bytes: &[u8]) -> bool {
+    let Some((&checksum, data)) = bytes.split_last() else {
+        return false;
+    };
+    let expected = data
+        .iter()
+        .fold(0x7F_u8, |sum, byte| sum.wrapping_sub(*byte) & 0x7F);
+    checksum == expected
+}
+
+impl<R: Read> Read for FrameChecker<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        let Some(max_invalid) = self.max_invalid else {
+            return Ok(len);
+        };
+        for &byte in &buf[..len] {
+            self.push(byte);
+        }
+        if self.consecutive_invalid >= max_invalid.get() {
+            self.exceeded.store(true, Ordering::Relaxed);
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} frames in a row were invalid", self.consecutive_invalid),
+            ));
+        }
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Appends the VBus checksum of `bytes` to them.
+    fn with_checksum(mut bytes: Vec<u8>) -> Vec<u8> {
+        let checksum = bytes
+            .iter()
+            .fold(0x7F_u8, |sum, byte| sum.wrapping_sub(*byte) & 0x7F);
+        bytes.push(checksum);
+        bytes
+    }
+
+    /// A datagram from a DeltaSol controller to the display.
+    fn datagram() -> Vec<u8> {
+        let mut frame = vec![SYNC_BYTE];
+        frame.extend(with_checksum(vec![
+            0x10, 0x00, 0x11, 0x7E, 0x20, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ]));
+        frame
+    }
+
+    #[test]
+    fn checksum_matches_vbus() {
+        assert!(checksum_ok(&with_checksum(vec![0x10, 0x00, 0x11, 0x7E])));
+        assert!(!checksum_ok(&[0x10, 0x00, 0x11, 0x7E, 0x00]));
+        assert!(!checksum_ok(&[]));
+    }
+
+    #[test]
+    fn checks_complete_datagrams() {
+        let frame = datagram();
+        assert_eq!(check(&frame), Some(true));
+        assert_eq!(check(&frame[..10]), None);
+        let mut corrupted = frame;
+        corrupted[7] ^= 0x01;
+        assert_eq!(check(&corrupted), Some(false));
+    }
+
+    #[test]
+    fn unknown_versions_count_as_valid() {
+        assert_eq!(
+            check(&[SYNC_BYTE, 0x10, 0x00, 0x11, 0x7E, 0x40]),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn fails_after_too_many_invalid_frames() {
+        let mut corrupted = datagram();
+        corrupted[7] ^= 0x01;
+        let stream = [corrupted.clone(), corrupted].concat();
+        let (mut checker, exceeded) = FrameChecker::new(&stream[..], NonZeroU32::new(2));
+        let err = checker.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(exceeded.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn valid_frames_reset_the_count() {
+        let mut corrupted = datagram();
+        corrupted[7] ^= 0x01;
+        let stream = [corrupted.clone(), datagram(), corrupted].concat();
+        let (mut checker, exceeded) = FrameChecker::new(&stream[..], NonZeroU32::new(2));
+        checker.read_to_end(&mut Vec::new()).unwrap();
+        assert!(!exceeded.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,109 @@
+use std::{
+    io::{self, Read, Write},
+    path::Path,
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use color_eyre::Result;
+use rppal::{
+    gpio::{self, Gpio},
+    uart::{self, Parity, Uart},
+};
+
+use super::{UartParity, UartSettings};
+
+/// Opens the UART through the Pi's peripheral access. Returns halves for reading and writing.
+pub fn open_uart(
+    path: &Path,
+    settings: UartSettings,
+    read_timeout: Duration,
+) -> Result<(impl Read + Send + 'static, impl Write + Send + 'static)> {
+    let parity = match settings.parity {
+        UartParity::None => Parity::None,
+        UartParity::Even => Parity::Even,
+        UartParity::Odd => Parity::Odd,
+    };
+    let mut uart = Uart::with_path(path, settings.baud, parity, 8, settings.stop_bits)?;
+    // Return whatever arrived within a second, so the timeout can be checked in between
+    uart.set_read_mode(0, Duration::from_secs(1))?;
+    let uart = Arc::new(Mutex::new(uart));
+    let writer = UartWriter {
+        uart: Arc::clone(&uart),
+    };
+    Ok((UartWrapper { uart, read_timeout }, writer))
+}
+
+/// How long the adapter is left without power, so its capacitors drain.
+const POWER_OFF_DURATION: Duration = Duration::from_secs(2);
+
+/// Turns the adapter's power off for a moment through the pin, a high level powering it.
+pub fn power_cycle(pin: u8) -> Result<()> {
+    let mut pin = Gpio::new()?.get(pin)?.into_output();
+    // Otherwise the pin falls back to an input when dropped, leaving the power undefined
+    pin.set_reset_on_drop(false);
+    pin.set_low();
+    thread::sleep(POWER_OFF_DURATION);
+    pin.set_high();
+    Ok(())
+}
+
+struct UartWrapper {
+    uart: Arc<Mutex<Uart>>,
+    read_timeout: Duration,
+}
+
+impl Read for UartWrapper {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let deadline = Instant::now() + self.read_timeout;
+        loop {
+            let len = self
+                .uart
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .read(buf)
+                .map_err(uart_err_to_io)?;
+            if len > 0 || buf.is_empty() {
+                return Ok(len);
+            }
+            if Instant::now() >= deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "no data received from UART",
+                ));
+            }
+        }
+    }
+}
+
+struct UartWriter {
+    uart: Arc<Mutex<Uart>>,
+}
+
+impl Write for UartWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.uart
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .write(buf)
+            .map_err(uart_err_to_io)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.uart
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .drain()
+            .map_err(uart_err_to_io)
+    }
+}
+
+fn uart_err_to_io(err: uart::Error) -> io::Error {
+    match err {
+        uart::Error::Io(err) => err,
+        uart::Error::Gpio(gpio::Error::Io(err)) => err,
+        uart::Error::Gpio(err) => io::Error::new(io::ErrorKind::Other, err),
+        uart::Error::InvalidValue => io::Error::new(io::ErrorKind::InvalidInput, err),
+    }
+}
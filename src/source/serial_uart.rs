@@ -0,0 +1,46 @@
+use std::{
+    io::{Read, Write},
+    path::Path,
+    time::Duration,
+};
+
+use color_eyre::{eyre::eyre, Result};
+use serialport::{DataBits, Parity, StopBits};
+
+use super::{UartParity, UartSettings};
+
+/// Opens any serial port the OS knows, e.g. a USB adapter on x86, macOS or Windows.
+/// Returns halves for reading and writing.
+pub fn open_uart(
+    path: &Path,
+    settings: UartSettings,
+    read_timeout: Duration,
+) -> Result<(impl Read + Send + 'static, impl Write + Send + 'static)> {
+    let parity = match settings.parity {
+        UartParity::None => Parity::None,
+        UartParity::Even => Parity::Even,
+        UartParity::Odd => Parity::Odd,
+    };
+    let stop_bits = match settings.stop_bits {
+        1 => StopBits::One,
+        2 => StopBits::Two,
+        stop_bits => {
+            return Err(eyre!(
+                "Invalid number of stop bits {stop_bits}, use 1 or 2."
+            ))
+        }
+    };
+    let port = serialport::new(path.to_string_lossy(), settings.baud)
+        .data_bits(DataBits::Eight)
+        .parity(parity)
+        .stop_bits(stop_bits)
+        .timeout(read_timeout)
+        .open()?;
+    let writer = port.try_clone()?;
+    Ok((port, writer))
+}
+
+/// Switching the adapter's power needs the Pi's GPIO.
+pub fn power_cycle(_pin: u8) -> Result<()> {
+    Err(eyre!("`power_pin` needs a build with the `rppal` feature."))
+}
@@ -0,0 +1,211 @@
+use std::{
+    collections::BTreeMap,
+    f64::consts::TAU,
+    thread,
+    time::{Duration, Instant},
+};
+
+use color_eyre::{eyre::eyre, Result};
+use resol_vbus::{
+    chrono::Utc, Data, DataSet, Header, Language, Packet, Specification, SpecificationFile,
+};
+use serde::Deserialize;
+
+use super::{DataReader, Source};
+use crate::{decode::EMBEDDED_SPECIFICATION, recorder::Recorder};
+
+const DESTINATION_ADDRESS: u16 = 0x0010;
+const COMMAND: u16 = 0x0100;
+const FRAME_DATA_LEN: usize = 508;
+
+/// Chance per packet that a relay switches.
+const RELAY_TOGGLE_PROBABILITY: f64 = 0.02;
+
+/// Synthesizes packets of a controller for development and demos: temperatures and
+/// irradiation following sine waves, relays switching at random and flow while one is on.
+#[derive(Deserialize, Clone)]
+pub struct SimulatorSource {
+    /// Controller whose packets are simulated, the DeltaSol BX Plus by default.
+    #[serde(default = "default_source_address")]
+    pub source_address: u16,
+    /// Seconds between packets.
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    /// Seconds of a simulated day, i.e. one period of the sine waves.
+    #[serde(default = "default_period")]
+    pub period: u64,
+}
+
+fn default_source_address() -> u16 {
+    0x7E11
+}
+
+fn default_interval() -> u64 {
+    1
+}
+
+fn default_period() -> u64 {
+    600
+}
+
+impl Source for SimulatorSource {
+    fn open(
+        &self,
+        _read_timeout: Duration,
+        _recorder: Option<Recorder>,
+    ) -> Result<Box<dyn DataReader + Send>> {
+        let fields = probe_fields(self.source_address)?;
+        if fields.is_empty() {
+            return Err(eyre!(
+                "The specification has no packet for the address 0x{:04X} to simulate.",
+                self.source_address
+            ));
+        }
+        Ok(Box::new(SimulatorReader {
+            config: self.clone(),
+            fields,
+            relays: BTreeMap::new(),
+            started: Instant::now(),
+            next_packet: None,
+        }))
+    }
+}
+
+/// Where a field is stored in the frame data.
+struct FieldLayout {
+    unit_code: String,
+    /// Bytes holding the little-endian raw value, least significant first.
+    offsets: Vec<usize>,
+    /// Value of a raw `1`.
+    scale: f64,
+}
+
+/// Finds the bytes of every field by setting one byte at a time and decoding the packet. Only
+/// fields whose bytes are all their own are simulated, bit fields are left at zero.
+fn probe_fields(source_address: u16) -> Result<Vec<FieldLayout>> {
+    let spec_file = SpecificationFile::from_bytes(EMBEDDED_SPECIFICATION)?;
+    let spec = Specification::from_file(spec_file, Language::En);
+    let decode = |frame_data: [u8; FRAME_DATA_LEN]| -> BTreeMap<String, (String, f64)> {
+        let mut data_set = DataSet::new();
+        data_set.add_data(Data::Packet(packet(source_address, frame_data)));
+        spec.fields_in_data_set(&data_set)
+            .map(|field| {
+                let field_spec = field.field_spec();
+                let value = field.raw_value_f64().unwrap_or_default();
+                let id = field_spec.packet_field_id.clone();
+                (id, (field_spec.unit_code.clone(), value))
+            })
+            .collect()
+    };
+
+    let zero = decode([0; FRAME_DATA_LEN]);
+    let mut layouts: BTreeMap<String, FieldLayout> = BTreeMap::new();
+    let mut owners: Vec<Vec<String>> = vec![Vec::new(); FRAME_DATA_LEN];
+    for offset in 0..FRAME_DATA_LEN {
+        let mut frame_data = [0; FRAME_DATA_LEN];
+        frame_data[offset] = 1;
+        for (id, (unit_code, value)) in decode(frame_data) {
+            let delta = value - zero.get(&id).map_or(0.0, |(_, value)| *value);
+            if delta == 0.0 {
+                continue;
+            }
+            owners[offset].push(id.clone());
+            let layout = layouts.entry(id).or_insert_with(|| FieldLayout {
+                unit_code,
+                offsets: Vec::new(),
+                scale: delta,
+            });
+            layout.offsets.push(offset);
+        }
+    }
+    Ok(layouts
+        .into_iter()
+        .filter(|(id, layout)| {
+            // A single bit of a byte shared with other fields changes by more than a raw `1`
+            let exclusive = layout
+                .offsets
+                .iter()
+                .all(|&offset| owners[offset].iter().all(|owner| owner == id));
+            exclusive && layout.offsets.len() <= 8
+        })
+        .map(|(_, layout)| layout)
+        .collect())
+}
+
+fn packet(source_address: u16, frame_data: [u8; FRAME_DATA_LEN]) -> Packet {
+    Packet {
+        header: Header {
+            timestamp: Utc::now(),
+            channel: 0,
+            destination_address: DESTINATION_ADDRESS,
+            source_address,
+            protocol_version: 0x10,
+        },
+        command: COMMAND,
+        frame_count: (FRAME_DATA_LEN / 4) as u8,
+        frame_data,
+    }
+}
+
+struct SimulatorReader {
+    config: SimulatorSource,
+    fields: Vec<FieldLayout>,
+    /// Whether the relay stored at an offset is on.
+    relays: BTreeMap<usize, bool>,
+    started: Instant,
+    next_packet: Option<Instant>,
+}
+
+impl SimulatorReader {
+    /// The simulated value of a field, `index` shifting the phase so fields of the same kind
+    /// differ.
+    fn value(&mut self, index: usize, layout: &FieldLayout, any_relay_on: bool) -> f64 {
+        let period = self.config.period.max(1) as f64;
+        let phase = self.started.elapsed().as_secs_f64() / period * TAU;
+        let shift = index as f64 * 0.7;
+        match layout.unit_code.as_str() {
+            "DegreesCelsius" => 40.0 + 25.0 * (phase + shift).sin(),
+            "WattsPerSquareMeter" => (900.0 * phase.sin()).max(0.0),
+            "Bars" => 1.8 + 0.1 * (phase + shift).sin(),
+            "LitersPerHour" if any_relay_on => 300.0,
+            "LitersPerMinute" if any_relay_on => 5.0,
+            "Percent" => {
+                let on = self.relays.entry(layout.offsets[0]).or_default();
+                if rand::random::<f64>() < RELAY_TOGGLE_PROBABILITY {
+                    *on = !*on;
+                }
+                if *on {
+                    100.0
+                } else {
+                    0.0
+                }
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+impl DataReader for SimulatorReader {
+    fn read_data(&mut self) -> Result<Option<Data>> {
+        let interval = Duration::from_secs(self.config.interval);
+        if let Some(next_packet) = self.next_packet {
+            thread::sleep(next_packet.saturating_duration_since(Instant::now()));
+        }
+        self.next_packet = Some(Instant::now() + interval);
+
+        let fields = std::mem::take(&mut self.fields);
+        let any_relay_on = self.relays.values().any(|on| *on);
+        let mut frame_data = [0; FRAME_DATA_LEN];
+        for (index, layout) in fields.iter().enumerate() {
+            let raw = (self.value(index, layout, any_relay_on) / layout.scale).round() as i64;
+            for (byte, &offset) in raw.to_le_bytes().iter().zip(&layout.offsets) {
+                frame_data[offset] = *byte;
+            }
+        }
+        self.fields = fields;
+        Ok(Some(Data::Packet(packet(
+            self.config.source_address,
+            frame_data,
+        ))))
+    }
+}
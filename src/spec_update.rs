@@ -0,0 +1,169 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::PathBuf,
+    sync::{Arc, PoisonError, RwLock},
+    time::Duration,
+};
+
+use color_eyre::Result;
+use reqwest::Client;
+use resol_vbus::SpecificationFile;
+use serde::Deserialize;
+use tokio::{
+    sync::watch,
+    task::JoinHandle,
+    time::{self, MissedTickBehavior},
+};
+use tracing::{debug, info, warn};
+
+use crate::state_file;
+
+/// Downloads the VBus specification every `interval` hours, so controllers with a newer
+/// firmware decode without a new release. Readers switch to a changed one with their next
+/// packet.
+#[derive(Deserialize, Clone, PartialEq)]
+pub struct SpecUpdateConfig {
+    /// Where the `vbus_specification.vsf` is downloaded from, the copy kept up to date in this
+    /// project's repository if not set.
+    #[serde(default = "default_url")]
+    pub url: String,
+    /// File the latest download is kept in, used from startup on until the next download.
+    pub path: PathBuf,
+    /// Hours between downloads.
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+}
+
+fn default_url() -> String {
+    "https://raw.githubusercontent.com/isarrider/vbus2influx/main/src/vbus_specification.vsf"
+        .to_owned()
+}
+
+fn default_interval() -> u64 {
+    24
+}
+
+/// The latest downloaded specification, preferred over `spec_path` and the embedded one.
+static LATEST: RwLock<Option<Arc<[u8]>>> = RwLock::new(None);
+
+/// The latest downloaded specification, `None` before the first one.
+pub fn latest() -> Option<Arc<[u8]>> {
+    LATEST
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+/// Whether `loaded` is still the latest downloaded specification.
+pub fn is_latest(loaded: &Option<Arc<[u8]>>) -> bool {
+    match (loaded, &latest()) {
+        (Some(loaded), Some(latest)) => Arc::ptr_eq(loaded, latest),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+fn set_latest(bytes: Option<Arc<[u8]>>) {
+    *LATEST.write().unwrap_or_else(PoisonError::into_inner) = bytes;
+}
+
+/// Runs the updates of the configured `[spec_update]`, restarted when a reload changes it.
+pub struct SpecUpdater {
+    config: Option<SpecUpdateConfig>,
+    task: Option<JoinHandle<()>>,
+    shutdown: watch::Receiver<bool>,
+}
+
+impl SpecUpdater {
+    pub fn start(
+        config: Option<SpecUpdateConfig>,
+        shutdown: watch::Receiver<bool>,
+    ) -> Result<Self> {
+        let mut updater = SpecUpdater {
+            config: None,
+            task: None,
+            shutdown,
+        };
+        updater.reconfigure(config)?;
+        Ok(updater)
+    }
+
+    /// Switches to the updates of a reloaded config. Without any, readers go back to
+    /// `spec_path` or the embedded specification.
+    pub fn reconfigure(&mut self, config: Option<SpecUpdateConfig>) -> Result<()> {
+        if self.task.is_some() && config == self.config {
+            return Ok(());
+        }
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+        set_latest(None);
+        if let Some(config) = &config {
+            load_cached(config)?;
+            self.task = Some(tokio::spawn(run_updates(
+                config.clone(),
+                self.shutdown.clone(),
+            )));
+        }
+        self.config = config;
+        Ok(())
+    }
+}
+
+/// Uses the specification kept by an earlier download, if there is a valid one.
+fn load_cached(config: &SpecUpdateConfig) -> Result<()> {
+    let bytes = match fs::read(&config.path) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    match SpecificationFile::from_bytes(&bytes) {
+        Ok(_) => {
+            debug!(path = %config.path.display(), "Using the downloaded specification");
+            set_latest(Some(bytes.into()));
+        }
+        Err(err) => warn!(
+            "Ignoring the downloaded specification `{}`: {err}",
+            config.path.display()
+        ),
+    }
+    Ok(())
+}
+
+/// Downloads the specification right away and then every `interval` hours until shutdown.
+async fn run_updates(config: SpecUpdateConfig, mut shutdown: watch::Receiver<bool>) {
+    let client = Client::new();
+    let mut timer = time::interval(Duration::from_secs(config.interval.max(1) * 60 * 60));
+    timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = timer.tick() => {}
+            _ = shutdown.changed() => return,
+        }
+        match update(&client, &config).await {
+            Ok(true) => info!("Downloaded a new VBus specification."),
+            Ok(false) => debug!("The VBus specification is up to date."),
+            Err(err) => warn!("Error while downloading the VBus specification: {err}"),
+        }
+    }
+}
+
+/// Downloads the specification and switches to it if it changed and parses. Returns whether
+/// it did.
+async fn update(client: &Client, config: &SpecUpdateConfig) -> Result<bool> {
+    let bytes = client
+        .get(&config.url)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    if latest().is_some_and(|latest| *latest == *bytes) {
+        return Ok(false);
+    }
+    SpecificationFile::from_bytes(&bytes)?;
+    state_file::replace(&config.path, &bytes)?;
+    set_latest(Some(Arc::from(&bytes[..])));
+    Ok(true)
+}
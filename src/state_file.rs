@@ -0,0 +1,47 @@
+use std::{
+    ffi::OsString,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::Result;
+use resol_vbus::chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Seconds between saves of state kept across restarts, to spare SD cards.
+const SAVE_INTERVAL_SECONDS: i64 = 300;
+
+/// Whether state last saved at `last_save` is due to be saved again at `time`.
+pub fn save_due(last_save: Option<DateTime<Utc>>, time: DateTime<Utc>) -> bool {
+    last_save.is_none_or(|last_save| (time - last_save).num_seconds() >= SAVE_INTERVAL_SECONDS)
+}
+
+/// Reads state saved as JSON, the default if nothing was saved yet.
+pub fn load<T: DeserializeOwned + Default>(path: &Path) -> Result<T> {
+    match fs::read_to_string(path) {
+        Ok(state) => Ok(serde_json::from_str(&state)?),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(T::default()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Saves state as JSON.
+pub fn save<T: Serialize>(path: &Path, state: &T) -> Result<()> {
+    replace(path, &serde_json::to_vec(state)?)
+}
+
+/// Replaces the file through a temporary one, so it is never left half written.
+pub fn replace(path: &Path, contents: &[u8]) -> Result<()> {
+    let tmp_path = tmp_path(path);
+    fs::write(&tmp_path, contents)?;
+    fs::rename(tmp_path, path)?;
+    Ok(())
+}
+
+/// `<path>.tmp`, next to the file and not shared with files differing only in the extension.
+pub fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp_path = OsString::from(path);
+    tmp_path.push(".tmp");
+    tmp_path.into()
+}
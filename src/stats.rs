@@ -0,0 +1,180 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use resol_vbus::chrono::{DateTime, TimeZone, Utc};
+
+use crate::frames::{FrameInfo, PacketInfo};
+
+/// Without a decoded packet for this long the pipeline is considered failing.
+const MAX_PACKET_AGE: Duration = Duration::from_secs(60);
+
+/// Upper bounds in seconds of the decode duration histogram buckets.
+const DECODE_DURATION_BUCKETS: [f64; 7] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1];
+
+/// Counters describing what the collector has done since startup.
+pub struct Stats {
+    pub started: DateTime<Utc>,
+    pub packets_decoded: AtomicU64,
+    /// Failed reads from a source, each followed by a reconnect.
+    pub read_errors: AtomicU64,
+    /// Sources reopened successfully after a read error.
+    pub reconnects: AtomicU64,
+    /// Sources reopened because too many frames in a row were invalid.
+    pub reader_restarts: AtomicU64,
+    /// Measurements of live sources dropped because the writer was lagging behind.
+    pub queue_dropped: AtomicU64,
+    /// Time spent decoding packets into measurements.
+    pub decode_duration: Histogram,
+    /// Values dropped for being outside of their plausible range.
+    pub sensor_faults: AtomicU64,
+    /// Unix time in milliseconds of the last decoded packet, `0` if there was none yet.
+    pub last_decoded: AtomicI64,
+    /// Counters of every running sink by name.
+    pub sinks: Mutex<Vec<(String, Arc<SinkStats>)>>,
+    /// The latest frame read from any source.
+    pub last_frame: Mutex<Option<FrameInfo>>,
+    /// Every packet read from any source by its ID.
+    pub packets: Mutex<BTreeMap<String, PacketInfo>>,
+}
+
+/// Counters of a single sink.
+#[derive(Default)]
+pub struct SinkStats {
+    pub writes: AtomicU64,
+    pub write_errors: AtomicU64,
+    /// Unix time in milliseconds of the last successful write, `0` if there was none yet.
+    pub last_write: AtomicI64,
+    /// Duration of the last successful write in milliseconds.
+    pub last_write_latency: AtomicU64,
+    /// Whether the most recent write failed.
+    pub write_failing: AtomicBool,
+    /// Number of measurements waiting to be written.
+    pub buffered: AtomicUsize,
+    /// Measurements dropped to keep the buffer within its limits.
+    pub dropped_points: AtomicU64,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats {
+            started: Utc::now(),
+            packets_decoded: AtomicU64::default(),
+            read_errors: AtomicU64::default(),
+            reconnects: AtomicU64::default(),
+            reader_restarts: AtomicU64::default(),
+            queue_dropped: AtomicU64::default(),
+            decode_duration: Histogram::new(&DECODE_DURATION_BUCKETS),
+            sensor_faults: AtomicU64::default(),
+            last_decoded: AtomicI64::default(),
+            sinks: Mutex::default(),
+            last_frame: Mutex::default(),
+            packets: Mutex::default(),
+        }
+    }
+}
+
+impl Stats {
+    /// Stores the current time in one of the timestamp fields.
+    pub fn touch(timestamp: &AtomicI64) {
+        timestamp.store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+    }
+
+    /// Reads one of the timestamp fields.
+    pub fn time(timestamp: &AtomicI64) -> Option<DateTime<Utc>> {
+        match timestamp.load(Ordering::Relaxed) {
+            0 => None,
+            millis => Utc.timestamp_millis_opt(millis).single(),
+        }
+    }
+
+    /// Whether a packet was decoded recently.
+    pub fn packets_arriving(&self) -> bool {
+        Stats::time(&self.last_decoded).is_some_and(|time| {
+            Utc::now()
+                .signed_duration_since(time)
+                .to_std()
+                .unwrap_or_default()
+                < MAX_PACKET_AGE
+        })
+    }
+
+    /// Counters for a sink, a sink restarted by a config reload keeps its counters.
+    pub fn register_sink(&self, name: &str) -> Arc<SinkStats> {
+        let mut sinks = self.sinks.lock().unwrap();
+        if let Some((_, sink_stats)) = sinks.iter().find(|(existing, _)| existing == name) {
+            return Arc::clone(sink_stats);
+        }
+        let sink_stats = Arc::new(SinkStats::default());
+        sinks.push((name.to_owned(), Arc::clone(&sink_stats)));
+        sink_stats
+    }
+
+    /// Snapshot of the sink counters, so the lock isn't held while using them.
+    pub fn sinks(&self) -> Vec<(String, Arc<SinkStats>)> {
+        self.sinks.lock().unwrap().clone()
+    }
+}
+
+impl SinkStats {
+    pub fn record_success(&self, latency: Duration) {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.last_write_latency.store(
+            u64::try_from(latency.as_millis()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+        self.write_failing.store(false, Ordering::Relaxed);
+        Stats::touch(&self.last_write);
+    }
+
+    pub fn record_failure(&self) {
+        self.write_errors.fetch_add(1, Ordering::Relaxed);
+        self.write_failing.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_buffered(&self, buffered: usize) {
+        self.buffered.store(buffered, Ordering::Relaxed);
+    }
+}
+
+/// Distribution of durations, in buckets by upper bound like Prometheus histograms.
+pub struct Histogram {
+    pub bounds: Vec<f64>,
+    /// Observations up to each bound, the last entry counts all of them (`+Inf`).
+    pub counts: Vec<AtomicU64>,
+    /// Sum of all observations in microseconds.
+    pub sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &[f64]) -> Self {
+        Histogram {
+            bounds: bounds.to_vec(),
+            counts: (0..=bounds.len()).map(|_| AtomicU64::default()).collect(),
+            sum_micros: AtomicU64::default(),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let buckets = self.bounds.iter().map(|bound| seconds <= *bound);
+        // The `+Inf` bucket always counts
+        for (count, _) in self
+            .counts
+            .iter()
+            .zip(buckets.chain([true]))
+            .filter(|(_, within)| *within)
+        {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_micros.fetch_add(
+            u64::try_from(duration.as_micros()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+}
@@ -0,0 +1,47 @@
+use std::time::{Duration, Instant};
+
+use sd_notify::NotifyState;
+use tracing::{debug, warn};
+
+/// Tells systemd the service is up, a no-op when not started by systemd.
+pub fn notify(state: NotifyState) {
+    if let Err(err) = sd_notify::notify(false, &[state]) {
+        warn!("Error while notifying systemd: {err}");
+    }
+}
+
+/// Keeps the systemd watchdog (`WatchdogSec=`) fed while measurements arrive, so a stalled
+/// decoder gets the unit restarted.
+pub struct Watchdog {
+    interval: Duration,
+    last_ping: Option<Instant>,
+}
+
+impl Watchdog {
+    /// `None` unless systemd expects pings, as told by `WATCHDOG_USEC`.
+    pub fn from_env() -> Option<Self> {
+        let mut usec = 0;
+        if !sd_notify::watchdog_enabled(false, &mut usec) {
+            return None;
+        }
+        // Ping twice per timeout, as systemd recommends
+        let interval = Duration::from_micros(usec) / 2;
+        debug!(?interval, "Systemd watchdog enabled");
+        Some(Watchdog {
+            interval,
+            last_ping: None,
+        })
+    }
+
+    /// Pings the watchdog, at most once per interval.
+    pub fn ping(&mut self) {
+        let now = Instant::now();
+        if self
+            .last_ping
+            .is_none_or(|last_ping| now.duration_since(last_ping) >= self.interval)
+        {
+            notify(NotifyState::Watchdog);
+            self.last_ping = Some(now);
+        }
+    }
+}
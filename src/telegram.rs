@@ -0,0 +1,138 @@
+use std::{collections::BTreeMap, fmt::Write, sync::Arc, time::Duration};
+
+use color_eyre::{eyre::eyre, Result};
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::{
+    sync::{watch, Mutex},
+    time,
+};
+use tracing::warn;
+
+use crate::Measurements;
+
+/// Seconds Telegram holds a `getUpdates` request open while waiting for messages.
+const POLL_TIMEOUT: u64 = 30;
+
+#[derive(Deserialize, Clone)]
+pub struct TelegramConfig {
+    /// Token of the bot, as handed out by `@BotFather`.
+    pub token: String,
+    /// Chat the bot answers in and sends alerts to, messages from other chats are ignored.
+    pub chat_id: i64,
+}
+
+#[derive(Deserialize)]
+struct Updates {
+    result: Vec<Update>,
+}
+
+#[derive(Deserialize)]
+struct Update {
+    update_id: i64,
+    message: Option<Message>,
+}
+
+#[derive(Deserialize)]
+struct Message {
+    chat: Chat,
+    text: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Chat {
+    id: i64,
+}
+
+fn api_url(config: &TelegramConfig, method: &str) -> String {
+    format!("https://api.telegram.org/bot{}/{method}", config.token)
+}
+
+/// Sends a message to the configured chat.
+pub async fn send_message(client: &Client, config: &TelegramConfig, text: &str) -> Result<()> {
+    let response = client
+        .post(api_url(config, "sendMessage"))
+        .json(&json!({ "chat_id": config.chat_id, "text": text }))
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(eyre!("Telegram answered {}", response.status()));
+    }
+    Ok(())
+}
+
+/// Answers `/status` with the latest measurements until shutdown.
+pub async fn run_bot(
+    config: TelegramConfig,
+    measurements: Arc<Mutex<BTreeMap<String, Measurements>>>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let client = Client::new();
+    let mut offset = 0;
+    loop {
+        let updates = tokio::select! {
+            updates = get_updates(&client, &config, offset) => updates,
+            _ = shutdown.changed() => break,
+        };
+        let updates = match updates {
+            Ok(updates) => updates,
+            Err(err) => {
+                warn!("Error while polling Telegram: {err}");
+                time::sleep(Duration::from_secs(POLL_TIMEOUT)).await;
+                continue;
+            }
+        };
+        for update in updates {
+            offset = offset.max(update.update_id + 1);
+            let Some(message) = update.message else {
+                continue;
+            };
+            let is_status = message
+                .text
+                .is_some_and(|text| text.split('@').next() == Some("/status"));
+            if message.chat.id != config.chat_id || !is_status {
+                continue;
+            }
+            let text = status_text(&measurements.lock().await);
+            if let Err(err) = send_message(&client, &config, &text).await {
+                warn!("Error while answering on Telegram: {err}");
+            }
+        }
+    }
+}
+
+async fn get_updates(client: &Client, config: &TelegramConfig, offset: i64) -> Result<Vec<Update>> {
+    let response = client
+        .get(api_url(config, "getUpdates"))
+        .query(&[("offset", offset), ("timeout", POLL_TIMEOUT as i64)])
+        .timeout(Duration::from_secs(POLL_TIMEOUT + 10))
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(eyre!("Telegram answered {}", response.status()));
+    }
+    Ok(response.json::<Updates>().await?.result)
+}
+
+/// The latest measurements of every device, one field per line.
+fn status_text(measurements: &BTreeMap<String, Measurements>) -> String {
+    if measurements.is_empty() {
+        return "No measurements yet.".to_owned();
+    }
+    let mut text = String::new();
+    for (device, measurements) in measurements {
+        if !device.is_empty() {
+            let _ = writeln!(text, "{device}:");
+        }
+        let _ = writeln!(
+            text,
+            "{}",
+            measurements.time.format("%Y-%m-%d %H:%M:%S UTC")
+        );
+        for (name, value) in &measurements.fields {
+            let _ = writeln!(text, "{name}: {value}");
+        }
+    }
+    text
+}
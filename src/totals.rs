@@ -0,0 +1,146 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
+use color_eyre::Result;
+use resol_vbus::chrono::{DateTime, Datelike, Local, NaiveDate, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::{integration_seconds, state_file, Measurements};
+
+/// Daily and weekly totals, written once a (local) day is over.
+#[derive(Deserialize, Clone)]
+pub struct TotalsConfig {
+    #[serde(default = "default_measurement")]
+    pub measurement: String,
+    /// File keeping the running totals across restarts.
+    pub state_path: Option<PathBuf>,
+    pub fields: Vec<TotalField>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct TotalField {
+    /// Written as `<name>_day` and `<name>_week`.
+    pub name: String,
+    /// Field integrated over time in hours, e.g. a power in kW sums up to kWh.
+    pub field: String,
+    /// Applied to the integral, e.g. `0.01` turns a relay's percentage into hours of runtime.
+    #[serde(default = "default_factor")]
+    pub factor: f64,
+}
+
+fn default_measurement() -> String {
+    "totals".to_owned()
+}
+
+fn default_factor() -> f64 {
+    1.0
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct DeviceTotals {
+    /// Local day the totals are summed up for.
+    day: Option<NaiveDate>,
+    last_time: Option<DateTime<Utc>>,
+    daily: BTreeMap<String, f64>,
+    /// Since the start of the ISO week, i.e. Monday.
+    weekly: BTreeMap<String, f64>,
+}
+
+/// Sums up fields per source and day.
+pub struct Totals {
+    config: TotalsConfig,
+    /// By device, unnamed sources use an empty name.
+    devices: BTreeMap<String, DeviceTotals>,
+    last_save: Option<DateTime<Utc>>,
+}
+
+impl Totals {
+    /// Continues with the totals saved by a previous run, if any.
+    pub fn load(config: TotalsConfig) -> Result<Self> {
+        let devices = match &config.state_path {
+            Some(path) => state_file::load(path)?,
+            None => BTreeMap::new(),
+        };
+        Ok(Totals {
+            config,
+            devices,
+            last_save: None,
+        })
+    }
+
+    /// Takes over the running totals of the totals this replaces, e.g. on a reload.
+    pub fn continue_from(&mut self, old: Totals) {
+        self.devices = old.devices;
+        self.last_save = old.last_save;
+    }
+
+    /// Adds the measurements to the running totals. Returns the totals of the day before as
+    /// event, timestamped with its start, when they are the first of a new day.
+    pub fn update(&mut self, measurements: &Measurements) -> Option<Measurements> {
+        let time = measurements.time;
+        let day = time.with_timezone(&Local).date_naive();
+        let device = measurements.device.clone().unwrap_or_default();
+        let totals = self.devices.entry(device).or_default();
+
+        let finished = match totals.day {
+            Some(previous) if previous != day => {
+                let mut fields = BTreeMap::new();
+                for total in &self.config.fields {
+                    let daily = totals.daily.get(&total.name).copied().unwrap_or_default();
+                    let weekly = totals.weekly.get(&total.name).copied().unwrap_or_default();
+                    fields.insert(format!("{}_day", total.name), daily);
+                    fields.insert(format!("{}_week", total.name), weekly);
+                }
+                totals.daily.clear();
+                if previous.iso_week() != day.iso_week() {
+                    totals.weekly.clear();
+                }
+                debug!(device = ?measurements.device, %previous, "Day finished");
+                let start = previous
+                    .and_hms_opt(0, 0, 0)
+                    .and_then(|midnight| Local.from_local_datetime(&midnight).earliest())
+                    .map_or(time, |start| start.with_timezone(&Utc));
+                Some(Measurements {
+                    time: start,
+                    device: measurements.device.clone(),
+                    measurement: Some(self.config.measurement.clone()),
+                    fields,
+                    text: None,
+                    tags: BTreeMap::new(),
+                })
+            }
+            _ => None,
+        };
+        totals.day = Some(day);
+
+        if let Some(last_time) = totals.last_time {
+            if let Some(seconds) = integration_seconds(last_time, time) {
+                for total in &self.config.fields {
+                    let Some(value) = measurements.fields.get(&total.field) else {
+                        continue;
+                    };
+                    let amount = value * total.factor * seconds / 3600.0;
+                    *totals.daily.entry(total.name.clone()).or_default() += amount;
+                    *totals.weekly.entry(total.name.clone()).or_default() += amount;
+                }
+            }
+        }
+        totals.last_time = Some(time);
+
+        if finished.is_some() || state_file::save_due(self.last_save, time) {
+            if let Err(err) = self.save() {
+                warn!("Error while saving the totals: {err}");
+            }
+            self.last_save = Some(time);
+        }
+        finished
+    }
+
+    /// Writes the running totals to `state_path`, if set.
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.config.state_path else {
+            return Ok(());
+        };
+        state_file::save(path, &self.devices)
+    }
+}
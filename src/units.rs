@@ -0,0 +1,124 @@
+use std::fmt;
+
+use serde::Deserialize;
+
+const LITERS_PER_GALLON: f64 = 3.785_411_784;
+const PSI_PER_BAR: f64 = 14.503_773_8;
+
+/// Non-metric unit a decoded field can be written in.
+#[derive(Deserialize, Clone, Copy)]
+pub enum Unit {
+    #[serde(rename = "°F", alias = "F")]
+    Fahrenheit,
+    #[serde(rename = "gal/min")]
+    GallonsPerMinute,
+    #[serde(rename = "gal/h")]
+    GallonsPerHour,
+    #[serde(rename = "gal")]
+    Gallons,
+    #[serde(rename = "psi")]
+    Psi,
+}
+
+impl Unit {
+    /// Converts a value from the unit the specification names by its code (e.g.
+    /// `DegreesCelsius`), `None` if that's no unit of the same kind.
+    pub fn convert(self, value: f64, unit_code: &str) -> Option<f64> {
+        let liters_per_minute = match unit_code {
+            "LitersPerMinute" => Some(value),
+            "LitersPerHour" => Some(value / 60.0),
+            "CubicMetersPerHour" => Some(value * 1000.0 / 60.0),
+            _ => None,
+        };
+        let liters = match unit_code {
+            "Liters" => Some(value),
+            "CubicMeters" => Some(value * 1000.0),
+            _ => None,
+        };
+        match self {
+            Unit::Fahrenheit => (unit_code == "DegreesCelsius").then(|| value * 9.0 / 5.0 + 32.0),
+            Unit::GallonsPerMinute => liters_per_minute.map(|flow| flow / LITERS_PER_GALLON),
+            Unit::GallonsPerHour => liters_per_minute.map(|flow| flow * 60.0 / LITERS_PER_GALLON),
+            Unit::Gallons => liters.map(|volume| volume / LITERS_PER_GALLON),
+            Unit::Psi => (unit_code == "Bars").then(|| value * PSI_PER_BAR),
+        }
+    }
+
+    /// Converts a value in this unit back to metric: °C, l/min, l/h, l or bar.
+    pub fn to_metric(self, value: f64) -> f64 {
+        match self {
+            Unit::Fahrenheit => (value - 32.0) * 5.0 / 9.0,
+            Unit::GallonsPerMinute | Unit::GallonsPerHour | Unit::Gallons => {
+                value * LITERS_PER_GALLON
+            }
+            Unit::Psi => value / PSI_PER_BAR,
+        }
+    }
+}
+
+impl fmt::Display for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let unit = match self {
+            Unit::Fahrenheit => "°F",
+            Unit::GallonsPerMinute => "gal/min",
+            Unit::GallonsPerHour => "gal/h",
+            Unit::Gallons => "gal",
+            Unit::Psi => "psi",
+        };
+        f.write_str(unit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{actual} != {expected}");
+    }
+
+    #[test]
+    fn converts_from_metric() {
+        assert_close(
+            Unit::Fahrenheit.convert(100.0, "DegreesCelsius").unwrap(),
+            212.0,
+        );
+        assert_close(Unit::Psi.convert(1.0, "Bars").unwrap(), PSI_PER_BAR);
+        assert_close(
+            Unit::GallonsPerMinute
+                .convert(60.0, "LitersPerHour")
+                .unwrap(),
+            1.0 / LITERS_PER_GALLON,
+        );
+        assert_close(
+            Unit::GallonsPerHour
+                .convert(1.0, "CubicMetersPerHour")
+                .unwrap(),
+            1000.0 / LITERS_PER_GALLON,
+        );
+        assert_close(
+            Unit::Gallons.convert(1.0, "CubicMeters").unwrap(),
+            1000.0 / LITERS_PER_GALLON,
+        );
+    }
+
+    #[test]
+    fn rejects_other_kinds_of_units() {
+        assert!(Unit::Fahrenheit.convert(1.0, "Bars").is_none());
+        assert!(Unit::Gallons.convert(1.0, "LitersPerMinute").is_none());
+        assert!(Unit::Psi.convert(1.0, "DegreesCelsius").is_none());
+    }
+
+    #[test]
+    fn converts_back_to_metric() {
+        for (unit, unit_code) in [
+            (Unit::Fahrenheit, "DegreesCelsius"),
+            (Unit::GallonsPerMinute, "LitersPerMinute"),
+            (Unit::Gallons, "Liters"),
+            (Unit::Psi, "Bars"),
+        ] {
+            let converted = unit.convert(42.5, unit_code).unwrap();
+            assert_close(unit.to_metric(converted), 42.5);
+        }
+    }
+}
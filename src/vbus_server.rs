@@ -0,0 +1,132 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use color_eyre::{eyre::eyre, Result};
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::{broadcast, watch},
+};
+use tracing::{debug, info, warn};
+
+/// Raw bus bytes as read from the forwarded source.
+pub type RawBytes = broadcast::Sender<Arc<[u8]>>;
+
+/// Chunks of raw bytes a slow client may fall behind before it misses some.
+pub const RAW_QUEUE_SIZE: usize = 256;
+
+/// Bytes of a command line read at most, the longest valid one is a `PASS` with its password.
+const MAX_LINE_LENGTH: u64 = 1024;
+
+#[derive(Deserialize, Clone)]
+pub struct VbusServerConfig {
+    #[serde(default = "default_address")]
+    pub address: SocketAddr,
+    #[serde(default = "default_password")]
+    pub password: String,
+    /// Source whose bus is forwarded, the one without `device` if not set.
+    pub device: Option<String>,
+}
+
+fn default_address() -> SocketAddr {
+    ([0, 0, 0, 0], 7053).into()
+}
+
+fn default_password() -> String {
+    "vbus".to_owned()
+}
+
+/// Forwards the raw bus of a live source to clients speaking the VBus/LAN protocol, e.g. RSC
+/// or ServiceCenter, as if they were connected to a VBus/LAN adapter. The bus stays read-only,
+/// whatever clients send after `DATA` is discarded.
+pub async fn run_server(
+    config: VbusServerConfig,
+    raw: RawBytes,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let listener = TcpListener::bind(config.address).await?;
+    info!("VBus/LAN server listening on {}", config.address);
+    let password = Arc::new(config.password);
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = shutdown.changed() => return Ok(()),
+        };
+        debug!(%peer, "VBus/LAN client connected");
+        let password = Arc::clone(&password);
+        let raw = raw.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            if let Err(err) = serve(stream, &password, &raw, shutdown).await {
+                warn!(%peer, "Error while serving VBus/LAN client: {err}");
+            }
+        });
+    }
+}
+
+/// Answers the handshake commands and streams the bus once the client sent `DATA`.
+async fn serve(
+    stream: TcpStream,
+    password: &str,
+    raw: &RawBytes,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let mut stream = BufReader::new(stream);
+    stream.write_all(b"+HELLO\n").await?;
+    let mut authorized = false;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let read = (&mut stream)
+            .take(MAX_LINE_LENGTH)
+            .read_line(&mut line)
+            .await?;
+        if read == 0 {
+            // Disconnected
+            return Ok(());
+        }
+        if !line.ends_with('\n') && read as u64 == MAX_LINE_LENGTH {
+            stream.write_all(b"-ERROR: Line too long\n").await?;
+            return Err(eyre!("Command longer than {MAX_LINE_LENGTH} bytes"));
+        }
+        let trimmed = line.trim();
+        let (command, argument) = trimmed.split_once(' ').unwrap_or((trimmed, ""));
+        let answer: &[u8] = match command.to_ascii_uppercase().as_str() {
+            "CONNECT" => b"+OK: Connection established\n",
+            "PASS" if argument == password => {
+                authorized = true;
+                b"+OK: Password accepted\n"
+            }
+            "PASS" => b"-ERROR: Password rejected\n",
+            "CHANNEL" if argument == "0" => b"+OK: Channel selected\n",
+            "CHANNEL" => b"-ERROR: Unknown channel\n",
+            "DATA" if authorized => break,
+            "DATA" => b"-ERROR: Not authorized\n",
+            "QUIT" => {
+                stream.write_all(b"+OK: Bye\n").await?;
+                return Ok(());
+            }
+            _ => b"-ERROR: Unknown command\n",
+        };
+        stream.write_all(answer).await?;
+    }
+    stream.write_all(b"+OK: Data incoming...\n").await?;
+
+    let mut receiver = raw.subscribe();
+    let mut discarded = [0; 256];
+    loop {
+        tokio::select! {
+            bytes = receiver.recv() => match bytes {
+                Ok(bytes) => stream.write_all(&bytes).await?,
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    debug!("VBus/LAN client fell behind, {missed} chunks were skipped");
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            read = stream.read(&mut discarded) => if read? == 0 {
+                return Ok(());
+            },
+            _ = shutdown.changed() => return Ok(()),
+        }
+    }
+}
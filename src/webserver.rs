@@ -0,0 +1,592 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    convert::Infallible,
+    fmt::Write,
+    path::PathBuf,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use axum::{
+    extract::Query,
+    http::{header, Request, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, IntoResponse, Response,
+    },
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use chrono_tz::Tz;
+use color_eyre::{eyre::eyre, Result};
+use futures_util::{stream, Stream};
+use resol_vbus::chrono::{self, DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use subtle::ConstantTimeEq;
+use tokio::{
+    sync::{
+        broadcast::{self, error::RecvError},
+        watch, Mutex, Notify,
+    },
+    task,
+};
+use tower_http::trace::TraceLayer;
+use tracing::{debug, info};
+
+use crate::{
+    frames::{FrameInfo, PacketInfo},
+    mapping::{self, FieldMapping},
+    metric_name,
+    sink::SinkControl,
+    stats::Stats,
+    Config, Measurements, SharedConfig,
+};
+
+/// Shared state the request handlers read from.
+#[derive(Clone)]
+pub struct AppState {
+    /// Latest measurements per device, unnamed sources use an empty name.
+    pub measurements: Arc<Mutex<BTreeMap<String, Measurements>>>,
+    /// Recent measurements of all devices, oldest first.
+    pub history: Arc<Mutex<VecDeque<Measurements>>>,
+    pub stats: Arc<Stats>,
+    pub control: Arc<SinkControl>,
+    /// Every new measurement, for `/events`.
+    pub updates: broadcast::Sender<Measurements>,
+    /// Ends open event streams, which would keep the server from shutting down otherwise.
+    pub shutdown: watch::Receiver<bool>,
+    /// Time zone of the times in JSON responses, UTC if not set.
+    pub timezone: Option<Tz>,
+    pub config: SharedConfig,
+    /// The config file `/admin` saves the field mapping to.
+    pub config_path: PathBuf,
+    /// Applies the saved config.
+    pub reload: Arc<Notify>,
+}
+
+pub async fn run_webserver(
+    config: Arc<Config>,
+    state: AppState,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let mut app = Router::new()
+        .route("/", get(dashboard))
+        .route("/api/measurements", get(measurements))
+        .route("/history", get(history))
+        .route("/events", get(events))
+        .route("/metrics", get(metrics))
+        .route("/metrics/self", get(self_metrics))
+        .route("/status", get(status))
+        .route("/debug/last-packet", get(last_packet))
+        .route("/spec", get(spec));
+    if let Some(expected) = expected_authorization(&config) {
+        // Only offered with credentials, anyone could stop the writes otherwise
+        app = app
+            .route("/control/pause", post(pause))
+            .route("/control/resume", post(resume))
+            .route("/control/flush", post(flush))
+            .route("/admin", get(admin))
+            .route("/admin/fields", get(fields).post(save_fields))
+            .route_layer(middleware::from_fn(move |request, next| {
+                authorize(request, next, expected.clone())
+            }));
+    }
+    // Health checks work without credentials, they don't reveal any data
+    let app = app
+        .route("/health", get(health))
+        .layer(Extension(state))
+        .layer(TraceLayer::new_for_http());
+
+    let address = *config.webserver_address.as_ref().unwrap();
+    match (&config.webserver_tls_cert, &config.webserver_tls_key) {
+        (Some(cert), Some(key)) => {
+            let tls_config = RustlsConfig::from_pem_file(cert, key).await?;
+            let handle = Handle::new();
+            let shutdown_handle = handle.clone();
+            tokio::spawn(async move {
+                let _ = shutdown.changed().await;
+                shutdown_handle.graceful_shutdown(Some(Duration::from_secs(10)));
+            });
+            axum_server::bind_rustls(address, tls_config)
+                .handle(handle)
+                .serve(app.into_make_service())
+                .await?;
+        }
+        (None, None) => {
+            axum::Server::bind(&address)
+                .serve(app.into_make_service())
+                .with_graceful_shutdown(async move {
+                    let _ = shutdown.changed().await;
+                })
+                .await?;
+        }
+        _ => {
+            return Err(eyre!(
+                "`webserver_tls_cert` and `webserver_tls_key` have to be configured together."
+            ))
+        }
+    }
+    Ok(())
+}
+
+/// The `Authorization` header requests have to carry, if any credentials are configured.
+fn expected_authorization(config: &Config) -> Option<Arc<str>> {
+    if let Some(token) = &config.webserver_token {
+        return Some(format!("Bearer {token}").into());
+    }
+    let username = config.webserver_username.as_ref()?;
+    let password = config.webserver_password.as_deref().unwrap_or_default();
+    let credentials = base64::encode(format!("{username}:{password}"));
+    Some(format!("Basic {credentials}").into())
+}
+
+async fn authorize<B>(
+    request: Request<B>,
+    next: Next<B>,
+    expected: Arc<str>,
+) -> std::result::Result<Response, Response> {
+    let authorization = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    // Compared in constant time, so the response time doesn't give away matching prefixes
+    if authorization
+        .is_some_and(|authorization| authorization.as_bytes().ct_eq(expected.as_bytes()).into())
+    {
+        return Ok(next.run(request).await);
+    }
+    let challenge = if expected.starts_with("Basic") {
+        "Basic realm=\"vbus2influx\""
+    } else {
+        "Bearer"
+    };
+    Err((
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, challenge)],
+    )
+        .into_response())
+}
+
+/// Page for mapping the fields of the packets received so far.
+async fn admin() -> Html<&'static str> {
+    Html(include_str!("admin.html"))
+}
+
+/// The fields mapped by `packet_field_id`.
+async fn fields(Extension(state): Extension<AppState>) -> Json<Vec<FieldMapping>> {
+    Json(mapping::mapping(&state.config.get()))
+}
+
+/// Saves the mapping to the config file and reloads it.
+async fn save_fields(
+    Extension(state): Extension<AppState>,
+    Json(fields): Json<Vec<FieldMapping>>,
+) -> std::result::Result<StatusCode, (StatusCode, String)> {
+    // Validating loads the specification, which takes a moment
+    task::block_in_place(|| mapping::save_mapping(&state.config_path, &fields))
+        .map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err.to_string()))?;
+    info!("Field mapping saved to the config file, reloading.");
+    state.reload.notify_one();
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Self-contained page showing the latest measurements, refreshing itself.
+async fn dashboard() -> Html<&'static str> {
+    Html(include_str!("dashboard.html"))
+}
+
+/// Latest measurements of a single unnamed source, or of all sources keyed by device name.
+async fn measurements(Extension(state): Extension<AppState>) -> Json<Value> {
+    let latest = state.measurements.lock().await;
+    let value = match latest.get("") {
+        Some(measurements) if latest.len() == 1 => with_data_age(measurements, state.timezone),
+        _ if latest.is_empty() => to_json(&Measurements::empty(), state.timezone),
+        _ => Value::Object(
+            latest
+                .iter()
+                .map(|(device, measurements)| {
+                    (device.clone(), with_data_age(measurements, state.timezone))
+                })
+                .collect(),
+        ),
+    };
+    Json(value)
+}
+
+/// The measurements as JSON, with the seconds since they were taken as `data_age_seconds` so
+/// clients can tell values of a controller that stopped sending.
+fn with_data_age(measurements: &Measurements, timezone: Option<Tz>) -> Value {
+    let mut value = to_json(measurements, timezone);
+    if let Value::Object(object) = &mut value {
+        object.insert(
+            "data_age_seconds".to_owned(),
+            age_seconds(measurements.time).into(),
+        );
+    }
+    value
+}
+
+/// The measurements as JSON, with the time in the time zone if any.
+fn to_json(measurements: &Measurements, timezone: Option<Tz>) -> Value {
+    let mut value = serde_json::to_value(measurements).unwrap_or_default();
+    if let (Value::Object(object), Some(timezone)) = (&mut value, timezone) {
+        let time = measurements.time.with_timezone(&timezone);
+        object.insert("time".to_owned(), time.to_rfc3339().into());
+    }
+    value
+}
+
+/// A time as RFC 3339, in the time zone if any.
+fn display_time(time: DateTime<Utc>, timezone: Option<Tz>) -> String {
+    match timezone {
+        Some(timezone) => time.with_timezone(&timezone).to_rfc3339(),
+        // Like the timestamps serialized by serde
+        None => time.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+    }
+}
+
+fn age_seconds(time: DateTime<Utc>) -> f64 {
+    (Utc::now() - time).num_milliseconds() as f64 / 1000.0
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    /// Only return measurements from the last this many minutes.
+    minutes: Option<i64>,
+}
+
+/// Recent measurements of all devices as an array, oldest first.
+async fn history(
+    Query(query): Query<HistoryQuery>,
+    Extension(state): Extension<AppState>,
+) -> Json<Vec<Value>> {
+    let since = query
+        .minutes
+        .map(|minutes| Utc::now() - chrono::Duration::minutes(minutes));
+    let history = state.history.lock().await;
+    let recent = history
+        .iter()
+        .filter(|measurements| since.is_none_or(|since| measurements.time >= since))
+        .map(|measurements| to_json(measurements, state.timezone))
+        .collect();
+    Json(recent)
+}
+
+/// Streams every new measurement as Server-Sent Events, each carrying the same JSON as an
+/// entry of `/history`. Clients too slow to keep up miss measurements.
+async fn events(
+    Extension(state): Extension<AppState>,
+) -> Sse<impl Stream<Item = std::result::Result<Event, Infallible>>> {
+    let receiver = state.updates.subscribe();
+    let timezone = state.timezone;
+    let stream = stream::unfold(
+        (receiver, state.shutdown),
+        |(mut receiver, mut shutdown)| async move {
+            loop {
+                let received = tokio::select! {
+                    received = receiver.recv() => received,
+                    _ = shutdown.changed() => return None,
+                };
+                match received {
+                    Ok(measurements) => {
+                        let data = to_json(&measurements, timezone).to_string();
+                        let event = Event::default().data(data);
+                        return Some((Ok(event), (receiver, shutdown)));
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        debug!(skipped, "Event stream client is lagging behind");
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        },
+    );
+    // Comments every few seconds keep proxies from closing idle connections
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+/// Renders the latest measurements and counters in the Prometheus text format.
+async fn metrics(Extension(state): Extension<AppState>) -> impl IntoResponse {
+    // Samples are grouped by metric as the format requires
+    let mut gauges: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for measurements in state.measurements.lock().await.values() {
+        for (name, value) in &measurements.fields {
+            let (metric, sensor) = metric_name(name);
+            let mut labels: Vec<_> = sensor
+                .map(|sensor| format!("sensor=\"{sensor}\""))
+                .into_iter()
+                .collect();
+            if let Some(device) = &measurements.device {
+                labels.push(format!("device=\"{device}\""));
+            }
+            let labels = if labels.is_empty() {
+                String::new()
+            } else {
+                format!("{{{}}}", labels.join(","))
+            };
+            gauges
+                .entry(metric)
+                .or_default()
+                .push(format!("{labels} {value}"));
+        }
+    }
+
+    let mut body = String::new();
+    for (metric, samples) in gauges {
+        let _ = writeln!(body, "# TYPE {metric} gauge");
+        for sample in samples {
+            let _ = writeln!(body, "{metric}{sample}");
+        }
+    }
+
+    let counters = [
+        ("vbus_packets_decoded_total", &state.stats.packets_decoded),
+        ("vbus_read_errors_total", &state.stats.read_errors),
+        ("vbus_sensor_faults_total", &state.stats.sensor_faults),
+    ];
+    for (metric, counter) in counters {
+        let _ = writeln!(body, "# TYPE {metric} counter");
+        let _ = writeln!(body, "{metric} {}", counter.load(Ordering::Relaxed));
+    }
+
+    let sinks = state.stats.sinks();
+    let _ = writeln!(body, "# TYPE vbus_sink_write_errors_total counter");
+    for (name, sink_stats) in &sinks {
+        let errors = sink_stats.write_errors.load(Ordering::Relaxed);
+        let _ = writeln!(
+            body,
+            "vbus_sink_write_errors_total{{sink=\"{name}\"}} {errors}"
+        );
+    }
+    let _ = writeln!(body, "# TYPE vbus_sink_buffered gauge");
+    for (name, sink_stats) in &sinks {
+        let buffered = sink_stats.buffered.load(Ordering::Relaxed);
+        let _ = writeln!(body, "vbus_sink_buffered{{sink=\"{name}\"}} {buffered}");
+    }
+
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
+/// Telemetry of the collector itself in the OpenMetrics format, apart from the sensor data.
+async fn self_metrics(Extension(state): Extension<AppState>) -> impl IntoResponse {
+    let stats = &state.stats;
+    let mut body = String::new();
+    let _ = writeln!(body, "# TYPE vbus2influx_uart_reconnects counter");
+    let _ = writeln!(
+        body,
+        "vbus2influx_uart_reconnects_total {}",
+        stats.reconnects.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(body, "# TYPE vbus2influx_queue_dropped counter");
+    let _ = writeln!(
+        body,
+        "vbus2influx_queue_dropped_total {}",
+        stats.queue_dropped.load(Ordering::Relaxed)
+    );
+
+    let sinks = stats.sinks();
+    if let Some((_, influx)) = sinks.iter().find(|(name, _)| name == "influxdb") {
+        let _ = writeln!(body, "# TYPE vbus2influx_influx_write_errors counter");
+        let _ = writeln!(
+            body,
+            "vbus2influx_influx_write_errors_total {}",
+            influx.write_errors.load(Ordering::Relaxed)
+        );
+    }
+    let _ = writeln!(body, "# TYPE vbus2influx_dropped_points counter");
+    for (name, sink_stats) in &sinks {
+        let dropped = sink_stats.dropped_points.load(Ordering::Relaxed);
+        let _ = writeln!(
+            body,
+            "vbus2influx_dropped_points_total{{sink=\"{name}\"}} {dropped}"
+        );
+    }
+    let _ = writeln!(body, "# TYPE vbus2influx_buffer_len gauge");
+    for (name, sink_stats) in &sinks {
+        let buffered = sink_stats.buffered.load(Ordering::Relaxed);
+        let _ = writeln!(body, "vbus2influx_buffer_len{{sink=\"{name}\"}} {buffered}");
+    }
+
+    let histogram = &stats.decode_duration;
+    let _ = writeln!(body, "# TYPE vbus2influx_decode_duration_seconds histogram");
+    let _ = writeln!(body, "# UNIT vbus2influx_decode_duration_seconds seconds");
+    let bounds = histogram.bounds.iter().map(|bound| bound.to_string());
+    for (bound, count) in bounds.chain(["+Inf".to_owned()]).zip(&histogram.counts) {
+        let _ = writeln!(
+            body,
+            "vbus2influx_decode_duration_seconds_bucket{{le=\"{bound}\"}} {}",
+            count.load(Ordering::Relaxed)
+        );
+    }
+    let count = histogram
+        .counts
+        .last()
+        .map_or(0, |count| count.load(Ordering::Relaxed));
+    let sum = histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+    let _ = writeln!(body, "vbus2influx_decode_duration_seconds_count {count}");
+    let _ = writeln!(body, "vbus2influx_decode_duration_seconds_sum {sum}");
+    let _ = writeln!(body, "# EOF");
+
+    (
+        [(
+            header::CONTENT_TYPE,
+            "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        )],
+        body,
+    )
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum HealthStatus {
+    Ok,
+    Degraded,
+    Failing,
+}
+
+#[derive(Serialize)]
+struct Health {
+    status: HealthStatus,
+    last_decoded: Option<String>,
+    /// Seconds since the last decoded packet.
+    data_age_seconds: Option<f64>,
+    sinks: BTreeMap<String, SinkHealth>,
+}
+
+#[derive(Serialize)]
+struct SinkHealth {
+    failing: bool,
+    last_write: Option<String>,
+    buffered: usize,
+}
+
+/// Pipeline status for health checks, answered with `503` while failing.
+async fn health(Extension(state): Extension<AppState>) -> impl IntoResponse {
+    let stats = &state.stats;
+    let last_decoded = Stats::time(&stats.last_decoded);
+    let sinks: BTreeMap<_, _> = stats
+        .sinks()
+        .into_iter()
+        .map(|(name, sink_stats)| {
+            let sink_health = SinkHealth {
+                failing: sink_stats.write_failing.load(Ordering::Relaxed),
+                last_write: Stats::time(&sink_stats.last_write)
+                    .map(|time| display_time(time, state.timezone)),
+                buffered: sink_stats.buffered.load(Ordering::Relaxed),
+            };
+            (name, sink_health)
+        })
+        .collect();
+    let status = if !stats.packets_arriving() {
+        HealthStatus::Failing
+    } else if sinks.values().any(|sink| sink.failing) {
+        HealthStatus::Degraded
+    } else {
+        HealthStatus::Ok
+    };
+    let code = match status {
+        HealthStatus::Failing => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::OK,
+    };
+    let health = Health {
+        status,
+        last_decoded: last_decoded.map(|time| display_time(time, state.timezone)),
+        data_age_seconds: last_decoded.map(age_seconds),
+        sinks,
+    };
+    (code, Json(health))
+}
+
+#[derive(Serialize)]
+struct Status {
+    started: String,
+    uptime_seconds: i64,
+    packets_decoded: u64,
+    read_errors: u64,
+    /// Sources reopened because too many frames in a row were invalid.
+    reader_restarts: u64,
+    sensor_faults: u64,
+    last_decoded: Option<String>,
+    /// Whether writes are paused through `/control/pause`.
+    paused: bool,
+    sinks: BTreeMap<String, SinkStatus>,
+}
+
+#[derive(Serialize)]
+struct SinkStatus {
+    writes: u64,
+    write_errors: u64,
+    failing: bool,
+    last_write: Option<String>,
+    last_write_latency_ms: u64,
+    buffered: usize,
+}
+
+/// Runtime statistics for troubleshooting.
+async fn status(Extension(state): Extension<AppState>) -> Json<Status> {
+    let stats = &state.stats;
+    let sinks = stats
+        .sinks()
+        .into_iter()
+        .map(|(name, sink_stats)| {
+            let sink_status = SinkStatus {
+                writes: sink_stats.writes.load(Ordering::Relaxed),
+                write_errors: sink_stats.write_errors.load(Ordering::Relaxed),
+                failing: sink_stats.write_failing.load(Ordering::Relaxed),
+                last_write: Stats::time(&sink_stats.last_write)
+                    .map(|time| display_time(time, state.timezone)),
+                last_write_latency_ms: sink_stats.last_write_latency.load(Ordering::Relaxed),
+                buffered: sink_stats.buffered.load(Ordering::Relaxed),
+            };
+            (name, sink_status)
+        })
+        .collect();
+    Json(Status {
+        started: display_time(stats.started, state.timezone),
+        uptime_seconds: (Utc::now() - stats.started).num_seconds(),
+        packets_decoded: stats.packets_decoded.load(Ordering::Relaxed),
+        read_errors: stats.read_errors.load(Ordering::Relaxed),
+        reader_restarts: stats.reader_restarts.load(Ordering::Relaxed),
+        sensor_faults: stats.sensor_faults.load(Ordering::Relaxed),
+        last_decoded: Stats::time(&stats.last_decoded)
+            .map(|time| display_time(time, state.timezone)),
+        paused: state.control.is_paused(),
+        sinks,
+    })
+}
+
+/// Stops writing to the sinks, measurements are buffered until writes are resumed.
+async fn pause(Extension(state): Extension<AppState>) -> StatusCode {
+    state.control.set_paused(true);
+    info!("Writes paused.");
+    StatusCode::NO_CONTENT
+}
+
+async fn resume(Extension(state): Extension<AppState>) -> StatusCode {
+    state.control.set_paused(false);
+    info!("Writes resumed.");
+    StatusCode::NO_CONTENT
+}
+
+/// Writes everything buffered right away, without waiting for a full batch or a retry.
+async fn flush(Extension(state): Extension<AppState>) -> StatusCode {
+    state.control.flush();
+    StatusCode::NO_CONTENT
+}
+
+/// The latest frame read from any source, to diagnose wiring and packet filter issues.
+async fn last_packet(
+    Extension(state): Extension<AppState>,
+) -> std::result::Result<Json<FrameInfo>, StatusCode> {
+    let last_frame = state.stats.last_frame.lock().unwrap().clone();
+    last_frame.map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+/// The specification of every packet received so far, to look up the IDs for `[[fields]]`.
+async fn spec(Extension(state): Extension<AppState>) -> Json<Vec<PacketInfo>> {
+    let packets = state.stats.packets.lock().unwrap();
+    Json(packets.values().cloned().collect())
+}